@@ -0,0 +1,80 @@
+//! `--bench` and `--bench-sweep`: read throughput of the real counting path.
+//!
+//! `--bench` runs a normal count (honouring `--limit`, filters and
+//! `--threads`) but writes no output, and prints one result line instead.
+//! `--bench-sweep` re-runs this executable with `--bench` once per thread
+//! count, so each level gets a fresh process and thread pool, then prints a
+//! table of the results.
+
+use std::env;
+use std::process::{self, Command};
+use std::time::Duration;
+
+/// Prints the `--bench` result line that `run_sweep` parses.
+pub fn report(records: usize, elapsed: Duration, threads: Option<usize>) {
+    let secs = elapsed.as_secs_f64();
+    let rate = if secs > 0.0 { records as f64 / secs } else { 0.0 };
+    let threads = threads.map_or("default".to_string(), |n| n.to_string());
+    println!("Bench: {} records in {:.3}s ({:.0} records/s, threads {})", records, secs, rate, threads);
+}
+
+/// Runs `--bench` at each thread count and prints a table, then exits.
+pub fn run_sweep(args: &[String], thread_counts: &[usize]) -> ! {
+    // Forward everything except the sweep itself and any fixed thread count.
+    let mut forwarded: Vec<&String> = Vec::new();
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--bench-sweep" | "--threads" => {
+                iter.next();
+            },
+            "--bench" => (),
+            _ => forwarded.push(arg),
+        }
+    }
+    let exe = match env::current_exe() {
+        Ok(exe) => exe,
+        Err(e) => {
+            error!("cannot locate the read_counter executable for --bench-sweep: {}", e);
+            process::exit(1);
+        },
+    };
+
+    println!("{:>7} {:>10} {:>9} {:>12}", "threads", "records", "seconds", "records/s");
+    for &threads in thread_counts {
+        let output = Command::new(&exe)
+            .args(&forwarded)
+            .args(["--bench", "--threads", &threads.to_string(), "-q"])
+            .output();
+        let result = match &output {
+            Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .find_map(parse_report),
+            _ => None,
+        };
+        match result {
+            Some((records, secs, rate)) => {
+                println!("{:>7} {:>10} {:>9.3} {:>12.0}", threads, records, secs, rate);
+            },
+            None => {
+                let detail = match &output {
+                    Ok(output) => String::from_utf8_lossy(&output.stderr).trim_end().to_string(),
+                    Err(e) => e.to_string(),
+                };
+                error!("--bench run with {} threads failed:\n{}", threads, detail);
+                process::exit(1);
+            },
+        }
+    }
+    process::exit(0);
+}
+
+/// Extracts (records, seconds, records/s) from a [`report`] line.
+fn parse_report(line: &str) -> Option<(usize, f64, f64)> {
+    let rest = line.strip_prefix("Bench: ")?;
+    let mut fields = rest.split_whitespace();
+    let records = fields.next()?.parse().ok()?;
+    let secs = fields.nth(2)?.strip_suffix('s')?.parse().ok()?;
+    let rate = fields.next()?.strip_prefix('(')?.parse().ok()?;
+    Some((records, secs, rate))
+}
@@ -0,0 +1,26 @@
+//! `--format bincode` output (enabled with the `bincode` cargo feature): a
+//! compact binary file for passing counts between Rust tools without
+//! formatting and reparsing text.
+//!
+//! Layout: the 4-byte magic `RCBC`, then bincode (1.x, default options) of
+//! `(format_version: u32, rows: Vec<(String, u64)>)`, rows in output order.
+//! Readers should check the magic and reject versions they don't know;
+//! [`FORMAT_VERSION`] is bumped whenever the payload changes shape.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+pub const MAGIC: &[u8; 4] = b"RCBC";
+pub const FORMAT_VERSION: u32 = 1;
+
+pub fn write_bincode(sorted_barcodes: &[(String, usize)], path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let rows: Vec<(&str, u64)> = sorted_barcodes
+        .iter()
+        .map(|(barcode, count)| (barcode.as_str(), *count as u64))
+        .collect();
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(MAGIC)?;
+    bincode::serialize_into(&mut writer, &(FORMAT_VERSION, rows))?;
+    writer.flush()?;
+    Ok(())
+}
@@ -0,0 +1,175 @@
+//! `--diff A B`: per-barcode deltas between two count files, e.g. before and
+//! after a filtering step.
+//!
+//! Both files are read with [`load_counts`], so any of the text, TSV or CSV
+//! outputs work (gzipped or not, either `--field-order`, with or without
+//! `--with-rank`). The result is a TSV
+//! with columns `barcode`, `count_a`, `count_b`, `delta` (`count_b - count_a`),
+//! one row per barcode in either file (missing counts are 0), sorted by
+//! absolute delta, largest first, then by barcode. Unchanged barcodes are
+//! kept, at the end, so the row count equals the union of both files.
+
+use std::io::{BufRead, BufWriter, Write};
+
+use ahash::AHashMap;
+use read_counter::filter::open_text;
+
+/// Reads a count file written by this tool, gzipped or not; see [`parse_counts`].
+pub fn load_counts(path: &str) -> Result<AHashMap<String, usize>, Box<dyn std::error::Error>> {
+    parse_counts(open_text(path)?)
+}
+
+/// Parses the count layouts this tool writes. A first line with `barcode`
+/// and `count` columns, separated by tabs or commas, marks a TSV or CSV file
+/// and the header gives the column order. Anything else is the text layout:
+/// the count then the barcode, or rank, count and barcode (`--with-rank`),
+/// separated by whitespace. Blank lines are skipped. A barcode seen twice
+/// has its counts added.
+fn parse_counts(reader: impl BufRead) -> Result<AHashMap<String, usize>, Box<dyn std::error::Error>> {
+    let mut counts = AHashMap::new();
+    let mut columns: Option<(char, usize, usize)> = None;
+    let mut first = true;
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        // Only the first non-blank line can be a header.
+        if std::mem::take(&mut first) {
+            let delimiter = if line.contains('\t') { '\t' } else { ',' };
+            let header: Vec<&str> = line.split(delimiter).map(str::trim).collect();
+            let position = |name: &str| header.iter().position(|&field| field == name);
+            if let (Some(barcode), Some(count)) = (position("barcode"), position("count")) {
+                columns = Some((delimiter, barcode, count));
+                continue;
+            }
+        }
+        let (barcode, count) = match columns {
+            Some((delimiter, barcode, count)) => {
+                let fields = split_fields(&line, delimiter);
+                match (fields.get(barcode), fields.get(count)) {
+                    (Some(barcode), Some(count)) => (barcode.clone(), count.clone()),
+                    _ => {
+                        return Err(format!("line {}: missing the barcode or count column in '{}'", i + 1, line).into());
+                    },
+                }
+            },
+            None => match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+                [count, barcode] | [_, count, barcode] => (barcode.to_string(), count.to_string()),
+                _ => return Err(format!("line {}: expected a count and a barcode, got '{}'", i + 1, line).into()),
+            },
+        };
+        let count: usize = count
+            .trim()
+            .parse()
+            .map_err(|_| format!("line {}: invalid count '{}' in '{}'", i + 1, count, line))?;
+        *counts.entry(barcode).or_insert(0) += count;
+    }
+    Ok(counts)
+}
+
+/// Splits a TSV or CSV row; with `,` a field may be quoted, with `""` for a
+/// literal quote, as `--format csv` writes barcodes containing `,` or `"`.
+fn split_fields(line: &str, delimiter: char) -> Vec<String> {
+    if delimiter != ',' {
+        return line.split(delimiter).map(str::to_string).collect();
+    }
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        let field = fields.last_mut().expect("fields is never empty");
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            },
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            c => field.push(c),
+        }
+    }
+    fields
+}
+
+/// Writes the diff of `path_a` and `path_b` to `output`, returning
+/// (barcodes changed, only in A, only in B).
+pub fn write_diff(path_a: &str, path_b: &str, output: &str) -> Result<(usize, usize, usize), Box<dyn std::error::Error>> {
+    let read = |path: &str| load_counts(path).map_err(|e| format!("Error reading count file '{}': {}", path, e));
+    let counts_a = read(path_a)?;
+    let mut counts_b = read(path_b)?;
+
+    let mut rows: Vec<(String, usize, usize)> = Vec::with_capacity(counts_a.len().max(counts_b.len()));
+    let mut only_a = 0;
+    for (barcode, a) in counts_a {
+        let b = counts_b.remove(&barcode).unwrap_or_else(|| {
+            only_a += 1;
+            0
+        });
+        rows.push((barcode, a, b));
+    }
+    let only_b = counts_b.len();
+    rows.extend(counts_b.into_iter().map(|(barcode, b)| (barcode, 0, b)));
+    rows.sort_unstable_by(|x, y| x.2.abs_diff(x.1).cmp(&y.2.abs_diff(y.1)).reverse().then_with(|| x.0.cmp(&y.0)));
+
+    let mut writer = BufWriter::new(std::fs::File::create(output)?);
+    writeln!(writer, "barcode\tcount_a\tcount_b\tdelta")?;
+    for (barcode, a, b) in &rows {
+        writeln!(writer, "{}\t{}\t{}\t{}", barcode, a, b, *b as i64 - *a as i64)?;
+    }
+    writer.flush()?;
+    let changed = rows.iter().filter(|(_, a, b)| a != b).count();
+    Ok((changed, only_a, only_b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> AHashMap<String, usize> {
+        parse_counts(text.as_bytes()).unwrap()
+    }
+
+    fn counts(pairs: &[(&str, usize)]) -> AHashMap<String, usize> {
+        pairs.iter().map(|&(barcode, count)| (barcode.to_string(), count)).collect()
+    }
+
+    #[test]
+    fn text_layout_is_count_then_barcode() {
+        let expected = counts(&[("AAAC-1", 4), ("TTTG-1", 2)]);
+        assert_eq!(parse("      4 AAAC-1\n      2 TTTG-1\n"), expected);
+    }
+
+    #[test]
+    fn numeric_barcodes_are_not_taken_for_counts() {
+        // Cluster IDs as keys: only the layout says which column is the count.
+        assert_eq!(parse("     50 7\n      3 12\n"), counts(&[("7", 50), ("12", 3)]));
+        assert_eq!(parse("barcode\tcount\n7\t50\n12\t3\n"), counts(&[("7", 50), ("12", 3)]));
+        assert_eq!(parse("count,barcode\n50,7\n3,12\n"), counts(&[("7", 50), ("12", 3)]));
+    }
+
+    #[test]
+    fn rank_column_is_skipped() {
+        let text = "      1      50 7\n      2       3 AAAC-1\n";
+        assert_eq!(parse(text), counts(&[("7", 50), ("AAAC-1", 3)]));
+    }
+
+    #[test]
+    fn delimited_layouts_follow_the_header() {
+        let expected = counts(&[("AAAC-1", 4), ("TTTG-1", 2)]);
+        assert_eq!(parse("barcode\tcount\nAAAC-1\t4\nTTTG-1\t2\n"), expected);
+        assert_eq!(parse("count\tbarcode\n4\tAAAC-1\n2\tTTTG-1\n"), expected);
+        assert_eq!(parse("barcode,count\nAAAC-1,4\n\nTTTG-1,2\n"), expected);
+        assert_eq!(parse("barcode,count\n\"A,\"\"B\",4\n"), counts(&[("A,\"B", 4)]));
+        // The header is the first non-blank line, wherever it is.
+        assert_eq!(parse("\n\nbarcode\tcount\nAAAC-1\t4\nTTTG-1\t2\n"), expected);
+        assert_eq!(parse("  \ncount,barcode\n4,AAAC-1\n2,TTTG-1\n"), expected);
+    }
+
+    #[test]
+    fn malformed_rows_are_errors() {
+        assert!(parse_counts("AAAC-1 4\n".as_bytes()).is_err());
+        assert!(parse_counts("4\n".as_bytes()).is_err());
+        assert!(parse_counts("barcode,count\nAAAC-1\n".as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,657 @@
+//! Read filtering and barcode extraction, shared by the `read_counter` binary
+//! and library users who want to accumulate something other than read counts.
+//!
+//! ```no_run
+//! use ahash::AHashMap;
+//! use read_counter::filter::ReadFilter;
+//! use rust_htslib::bam::{self, Read};
+//!
+//! // Aligned bases per barcode instead of reads.
+//! let mut reader = bam::Reader::from_path("sample.bam")?;
+//! let filter = ReadFilter { proper_pair_only: true, ..ReadFilter::default() };
+//! let mut bases: AHashMap<String, usize> = AHashMap::new();
+//! for item in filter.counted(reader.records()) {
+//!     let (barcode, record) = item?;
+//!     *bases.entry(barcode).or_insert(0) += record.seq_len();
+//! }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+use ahash::AHashSet;
+use rust_htslib::bam::{self, record::Aux};
+use rust_htslib::bgzf;
+use rust_htslib::errors::Error as HtslibError;
+use rust_htslib::htslib;
+
+/// The mate's CIGAR string, read by [`mate_span`].
+const MATE_CIGAR_TAG: [u8; 2] = *b"MC";
+
+/// Parses a SAM aux tag name such as `CB` or `UB`. The SAM spec allows
+/// exactly two characters, a letter then a letter or digit; the error says
+/// which rule `value` breaks.
+pub fn parse_tag(value: &str) -> Result<[u8; 2], String> {
+    let chars = value.chars().count();
+    if chars != 2 {
+        return Err(format!(
+            "'{}' is {} character{} long; SAM tags are exactly two characters (e.g. CB)",
+            value,
+            chars,
+            if chars == 1 { "" } else { "s" }
+        ));
+    }
+    match value.as_bytes() {
+        [a, b] if a.is_ascii_alphabetic() && b.is_ascii_alphanumeric() => Ok([*a, *b]),
+        _ => Err(format!(
+            "'{}' is not a valid SAM tag: it must be a letter followed by a letter or digit",
+            value
+        )),
+    }
+}
+
+/// Why a record was not counted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rejection {
+    /// `proper_pair_only` is set and PROPER_PAIR (0x2) is not.
+    NotProperPair,
+    /// Sequence length outside `min_length..=max_length`.
+    Length,
+    /// Absolute template length (TLEN) outside `template_length`.
+    TemplateLength,
+    /// None of `barcode_tags` is present as a string, hex string or byte array.
+    MissingTag,
+    /// Barcode not in `whitelist`.
+    NotWhitelisted,
+}
+
+/// How a barcode tag was stored on the record.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BarcodeEncoding {
+    /// `Z`: used as is.
+    String,
+    /// `H`: hex-encoded bytes, decoded with [`decode_bytes`].
+    Hex,
+    /// `B:C`: a byte array, decoded with [`decode_bytes`].
+    ByteArray,
+}
+
+/// The filters applied before a record's barcode is counted. The default
+/// matches the binary's defaults: barcode from `CB`, nothing filtered.
+#[derive(Clone, Debug)]
+pub struct ReadFilter {
+    /// Tags tried in order; the first present as a string is the barcode.
+    pub barcode_tags: Vec<[u8; 2]>,
+    pub proper_pair_only: bool,
+    pub min_length: Option<usize>,
+    pub max_length: Option<usize>,
+    /// Inclusive range for the absolute TLEN, so both mates of a pair pass
+    /// or fail together. Reads with TLEN 0 (unpaired, or mates on different
+    /// references) fail any range that excludes 0.
+    pub template_length: Option<(usize, usize)>,
+    pub whitelist: Option<AHashSet<String>>,
+}
+
+impl Default for ReadFilter {
+    fn default() -> Self {
+        ReadFilter {
+            barcode_tags: vec![*b"CB"],
+            proper_pair_only: false,
+            min_length: None,
+            max_length: None,
+            template_length: None,
+            whitelist: None,
+        }
+    }
+}
+
+impl ReadFilter {
+    /// Applies the filters that don't depend on the barcode (flags, lengths).
+    pub fn check_record(&self, record: &bam::Record) -> Result<(), Rejection> {
+        if self.proper_pair_only && !record.is_proper_pair() {
+            return Err(Rejection::NotProperPair);
+        }
+        let read_len = record.seq_len();
+        if self.min_length.is_some_and(|min| read_len < min) || self.max_length.is_some_and(|max| read_len > max) {
+            return Err(Rejection::Length);
+        }
+        if let Some((min, max)) = self.template_length
+            && !(min..=max).contains(&(record.insert_size().unsigned_abs() as usize))
+        {
+            return Err(Rejection::TemplateLength);
+        }
+        Ok(())
+    }
+
+    /// Returns the first of `barcode_tags` present on `record` as a string,
+    /// hex string or byte array, with its index in `barcode_tags`. Byte
+    /// values are decoded with [`decode_bytes`].
+    pub fn barcode<'a>(&self, record: &'a bam::Record) -> Option<(usize, Cow<'a, str>)> {
+        self.barcode_encoded(record).map(|(i, value, _)| (i, value))
+    }
+
+    /// Like [`ReadFilter::barcode`], also saying how the tag was stored.
+    pub fn barcode_encoded<'a>(&self, record: &'a bam::Record) -> Option<(usize, Cow<'a, str>, BarcodeEncoding)> {
+        self.barcode_tags
+            .iter()
+            .enumerate()
+            .find_map(|(i, tag)| tag_text(record, tag).map(|(value, encoding)| (i, value, encoding)))
+    }
+
+    /// True if there is no whitelist or `barcode` is on it.
+    pub fn is_whitelisted(&self, barcode: &str) -> bool {
+        self.whitelist.as_ref().is_none_or(|allowed| allowed.contains(barcode))
+    }
+
+    /// Runs every filter on `record`, returning its barcode if it passes.
+    pub fn accept<'a>(&self, record: &'a bam::Record) -> Result<Cow<'a, str>, Rejection> {
+        self.check_record(record)?;
+        let (_, barcode) = self.barcode(record).ok_or(Rejection::MissingTag)?;
+        if !self.is_whitelisted(&barcode) {
+            return Err(Rejection::NotWhitelisted);
+        }
+        Ok(barcode)
+    }
+
+    /// Adapts a record iterator (e.g. `reader.records()`) to yield
+    /// `(barcode, record)` for the records that pass this filter. Read
+    /// errors are passed through.
+    pub fn counted<I>(&self, records: I) -> CountedRecords<'_, I>
+    where
+        I: Iterator<Item = Result<bam::Record, HtslibError>>,
+    {
+        CountedRecords { filter: self, records }
+    }
+}
+
+/// Iterator returned by [`ReadFilter::counted`]. Records are yielded by value
+/// because each one is freshly read from the underlying reader.
+pub struct CountedRecords<'f, I> {
+    filter: &'f ReadFilter,
+    records: I,
+}
+
+impl<I> Iterator for CountedRecords<'_, I>
+where
+    I: Iterator<Item = Result<bam::Record, HtslibError>>,
+{
+    type Item = Result<(String, bam::Record), HtslibError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let record = match self.records.next()? {
+                Ok(record) => record,
+                Err(e) => return Some(Err(e)),
+            };
+            if let Ok(barcode) = self.filter.accept(&record) {
+                let barcode = barcode.into_owned();
+                return Some(Ok((barcode, record)));
+            }
+        }
+    }
+}
+
+/// The reference interval `[start, end)` (0-based) covered by `record`'s
+/// alignment, from its position and CIGAR. Matches, deletions, skips (`N`)
+/// and `=`/`X` consume reference bases; insertions, soft/hard clips and
+/// padding don't. As in htslib's `bam_endpos`, a record whose CIGAR consumes
+/// no reference (e.g. unmapped) covers the single base at its position.
+pub fn reference_span(record: &bam::Record) -> (i64, i64) {
+    let start = record.pos();
+    let consumed: i64 = record
+        .raw_cigar()
+        .iter()
+        .filter(|&&op| matches!(op & htslib::BAM_CIGAR_MASK, 0 | 2 | 3 | 7 | 8))
+        .map(|&op| i64::from(op >> htslib::BAM_CIGAR_SHIFT))
+        .sum();
+    (start, start + consumed.max(1))
+}
+
+/// The reference interval `(tid, start, end)` covered by `record`'s mate,
+/// or `None` if the read is unpaired or its mate is unmapped. The end comes
+/// from the mate's CIGAR in the `MC` tag (added by `samtools fixmate -m` and
+/// most aligners); without it only the mate's first base is known.
+pub fn mate_span(record: &bam::Record) -> Option<(i32, i64, i64)> {
+    if !record.is_paired() || record.is_mate_unmapped() || record.mtid() < 0 {
+        return None;
+    }
+    let start = record.mpos();
+    let consumed = match record.aux(&MATE_CIGAR_TAG) {
+        Ok(Aux::String(cigar)) => cigar_reference_length(cigar),
+        _ => 0,
+    };
+    Some((record.mtid(), start, start + consumed.max(1)))
+}
+
+/// Reference bases consumed by a CIGAR string such as `50M2D48M`, counting
+/// the same operations as [`reference_span`]. Unparseable parts count 0.
+fn cigar_reference_length(cigar: &str) -> i64 {
+    let mut consumed = 0;
+    let mut length: i64 = 0;
+    for c in cigar.chars() {
+        match c.to_digit(10) {
+            Some(digit) => length = length * 10 + i64::from(digit),
+            None => {
+                if matches!(c, 'M' | 'D' | 'N' | '=' | 'X') {
+                    consumed += length;
+                }
+                length = 0;
+            },
+        }
+    }
+    consumed
+}
+
+/// The value of `tag` on `record` as text, if it is a string, hex string or
+/// byte array, and how it was stored. Byte values are decoded with
+/// [`decode_bytes`]; `None` for other types and invalid hex.
+pub fn tag_text<'a>(record: &'a bam::Record, tag: &[u8; 2]) -> Option<(Cow<'a, str>, BarcodeEncoding)> {
+    match record.aux(tag) {
+        // rust-htslib reads `H` values as strings, so check the stored type.
+        Ok(Aux::String(value)) if aux_type(record, tag) != Some(b'H') => {
+            Some((Cow::Borrowed(value), BarcodeEncoding::String))
+        },
+        Ok(Aux::String(hex) | Aux::HexByteArray(hex)) => {
+            let bytes = parse_hex(hex)?;
+            Some((Cow::Owned(decode_bytes(&bytes)), BarcodeEncoding::Hex))
+        },
+        Ok(Aux::ArrayU8(array)) => {
+            let bytes: Vec<u8> = array.iter().collect();
+            Some((Cow::Owned(decode_bytes(&bytes)), BarcodeEncoding::ByteArray))
+        },
+        _ => None,
+    }
+}
+
+/// Canonical text for a barcode stored as bytes (`H` or `B:C` tags), so the
+/// same bytes give the same barcode in either encoding: bytes that are all
+/// `A`/`C`/`G`/`T`/`N` are read as that sequence, anything else becomes
+/// uppercase hex, two digits per byte.
+pub fn decode_bytes(bytes: &[u8]) -> String {
+    if !bytes.is_empty() && bytes.iter().all(|b| b"ACGTN".contains(b)) {
+        return bytes.iter().map(|&b| b as char).collect();
+    }
+    bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+/// The SAM type character of `tag` on `record`, e.g. `b'Z'` or `b'H'`.
+fn aux_type(record: &bam::Record, tag: &[u8; 2]) -> Option<u8> {
+    // SAFETY: `bam_aux_get` reads the two tag bytes and returns a pointer to
+    // the type byte inside the record's data, or null if the tag is absent.
+    let aux = unsafe { htslib::bam_aux_get(record.inner(), tag.as_ptr().cast()) };
+    (!aux.is_null()).then(|| unsafe { *aux })
+}
+
+/// The bytes of an `H` tag value, or `None` if it is not valid hex.
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    // from_str_radix would also take a sign, as in "+1".
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Result of [`correct_barcode`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Correction {
+    /// Exactly one whitelisted barcode is nearest, within the distance limit.
+    Unique(String),
+    /// Several whitelisted barcodes are equally near.
+    Ambiguous,
+    /// No whitelisted barcode is within the distance limit.
+    NoMatch,
+}
+
+/// Finds the whitelisted barcode nearest to `barcode` by Hamming distance,
+/// up to `max_distance` substitutions. Candidates are generated by
+/// substituting A/C/G/T at up to `max_distance` positions and looked up in
+/// `whitelist`, nearest first, so the cost depends on the barcode length and
+/// distance, not on the whitelist size. For long barcodes (e.g. long-read
+/// data), where that would mean more candidates than whitelist entries, the
+/// whitelist is scanned instead. Call it for barcodes not on the whitelist;
+/// distance 0 is not checked.
+pub fn correct_barcode(whitelist: &AHashSet<String>, barcode: &str, max_distance: usize) -> Correction {
+    if candidate_count(barcode.len(), max_distance) > whitelist.len() {
+        return scan_whitelist(whitelist, barcode, max_distance);
+    }
+    fn visit(
+        candidate: &mut [u8],
+        original: &[u8],
+        from: usize,
+        remaining: usize,
+        whitelist: &AHashSet<String>,
+        hits: &mut Vec<String>,
+    ) {
+        if remaining == 0 {
+            if let Ok(value) = std::str::from_utf8(candidate)
+                && whitelist.contains(value)
+            {
+                hits.push(value.to_string());
+            }
+            return;
+        }
+        for pos in from..original.len() {
+            for &base in b"ACGT" {
+                // Two hits already make the result ambiguous.
+                if base != original[pos] && hits.len() < 2 {
+                    candidate[pos] = base;
+                    visit(candidate, original, pos + 1, remaining - 1, whitelist, hits);
+                }
+            }
+            candidate[pos] = original[pos];
+        }
+    }
+
+    let original = barcode.as_bytes();
+    let mut candidate = original.to_vec();
+    let mut hits = Vec::new();
+    for distance in 1..=max_distance.min(original.len()) {
+        visit(&mut candidate, original, 0, distance, whitelist, &mut hits);
+        match hits.len() {
+            0 => continue,
+            1 => return Correction::Unique(hits.pop().unwrap()),
+            _ => return Correction::Ambiguous,
+        }
+    }
+    Correction::NoMatch
+}
+
+/// Number of strings within `max_distance` A/C/G/T substitutions of a
+/// barcode of length `len`, saturating.
+fn candidate_count(len: usize, max_distance: usize) -> usize {
+    let mut total: usize = 0;
+    // C(len, k) * 3^k, built up term by term.
+    let mut term: usize = 1;
+    for k in 1..=max_distance.min(len) {
+        term = term.saturating_mul(len + 1 - k).saturating_mul(3) / k;
+        total = total.saturating_add(term);
+    }
+    total
+}
+
+/// [`correct_barcode`] by comparing against every same-length whitelist entry.
+fn scan_whitelist(whitelist: &AHashSet<String>, barcode: &str, max_distance: usize) -> Correction {
+    let mut best: Option<(usize, &str)> = None;
+    let mut ambiguous = false;
+    for entry in whitelist.iter().filter(|entry| entry.len() == barcode.len()) {
+        let limit = best.map_or(max_distance, |(distance, _)| distance);
+        let mut distance = 0;
+        for (a, b) in entry.bytes().zip(barcode.bytes()) {
+            if a != b {
+                distance += 1;
+                if distance > limit {
+                    break;
+                }
+            }
+        }
+        match best {
+            _ if distance == 0 || distance > limit => (),
+            Some((best_distance, _)) if distance == best_distance => ambiguous = true,
+            _ => {
+                best = Some((distance, entry));
+                ambiguous = false;
+            },
+        }
+    }
+    match best {
+        Some(_) if ambiguous => Correction::Ambiguous,
+        Some((_, entry)) => Correction::Unique(entry.to_string()),
+        None => Correction::NoMatch,
+    }
+}
+
+/// Opens a text input for line reading. Paths ending in `.gz` are
+/// decompressed through htslib's BGZF reader, which also handles plain gzip
+/// (e.g. 10x whitelists).
+pub fn open_text(path: &str) -> Result<Box<dyn BufRead>, Box<dyn std::error::Error>> {
+    Ok(if path.ends_with(".gz") {
+        Box::new(BufReader::new(bgzf::Reader::from_path(path)?))
+    } else {
+        Box::new(BufReader::new(File::open(path)?))
+    })
+}
+
+/// Loads a whitelist: one barcode per line, surrounding whitespace and
+/// blank lines ignored. `.gz` files are accepted (see [`open_text`]).
+pub fn load_whitelist(path: &str) -> Result<AHashSet<String>, Box<dyn std::error::Error>> {
+    let reader = open_text(path)?;
+    let mut set = AHashSet::new();
+    for line in reader.lines() {
+        let line = line?;
+        let barcode = line.trim();
+        if !barcode.is_empty() {
+            set.insert(barcode.to_string());
+        }
+    }
+    Ok(set)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::record::{Cigar, CigarString};
+
+    fn record(pos: i64, cigar: Vec<Cigar>, flags: u16) -> bam::Record {
+        let query_len: usize = cigar
+            .iter()
+            .map(|op| match op {
+                Cigar::Match(n) | Cigar::Ins(n) | Cigar::SoftClip(n) | Cigar::Equal(n) | Cigar::Diff(n) => *n as usize,
+                _ => 0,
+            })
+            .sum();
+        let seq = vec![b'A'; query_len];
+        let qual = vec![30; query_len];
+        let mut record = bam::Record::new();
+        record.set(b"r", Some(&CigarString(cigar)), &seq, &qual);
+        record.set_pos(pos);
+        record.set_flags(flags);
+        record
+    }
+
+    #[test]
+    fn parse_tag_accepts_two_character_tags() {
+        assert_eq!(parse_tag("CB"), Ok(*b"CB"));
+        assert_eq!(parse_tag("X1"), Ok(*b"X1"));
+    }
+
+    #[test]
+    fn parse_tag_rejects_wrong_lengths() {
+        for value in ["", "C", "CBX", "CELLBARCODE", "ÄB "] {
+            let error = parse_tag(value).unwrap_err();
+            assert!(error.contains("exactly two characters"), "{}: {}", value, error);
+        }
+    }
+
+    #[test]
+    fn parse_tag_rejects_invalid_characters() {
+        for value in ["1B", "C-", "ÄB", "  "] {
+            let error = parse_tag(value).unwrap_err();
+            assert!(error.contains("letter followed by a letter or digit"), "{}: {}", value, error);
+        }
+    }
+
+    #[test]
+    fn correction_picks_unique_nearest_barcode() {
+        let whitelist: AHashSet<String> = ["AAAA", "CCCC", "AATT", "AAGG"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(correct_barcode(&whitelist, "CAAA", 1), Correction::Unique("AAAA".to_string()));
+        assert_eq!(correct_barcode(&whitelist, "ACCC", 1), Correction::Unique("CCCC".to_string()));
+        // One substitution from both AATT and AAGG.
+        assert_eq!(correct_barcode(&whitelist, "AATG", 1), Correction::Ambiguous);
+        assert_eq!(correct_barcode(&whitelist, "GGCC", 1), Correction::NoMatch);
+        assert_eq!(correct_barcode(&whitelist, "GGCC", 2), Correction::Unique("CCCC".to_string()));
+    }
+
+    #[test]
+    fn long_read_record_with_long_name_and_barcode() {
+        // Long-read style: maximal QNAME, a 20 kb read with thousands of CIGAR
+        // ops, and a barcode far longer than short-read cell barcodes.
+        let qname = vec![b'm'; 254];
+        let mut cigar = Vec::new();
+        for _ in 0..1000 {
+            cigar.extend([Cigar::Match(15), Cigar::Ins(5), Cigar::Del(3)]);
+        }
+        let seq = vec![b'A'; 20_000];
+        let qual = vec![20; 20_000];
+        let mut long = bam::Record::new();
+        long.set(&qname, Some(&CigarString(cigar)), &seq, &qual);
+        long.set_pos(1_000_000);
+        let barcode: String = "ACGTTGCA".repeat(16);
+        long.push_aux(b"CB", Aux::String(&barcode)).unwrap();
+
+        assert_eq!(long.qname(), &qname[..]);
+        assert_eq!(reference_span(&long), (1_000_000, 1_018_000));
+        let filter = ReadFilter::default();
+        assert_eq!(filter.accept(&long), Ok(Cow::Borrowed(barcode.as_str())));
+
+        // Candidate generation would be hopeless at this length; the whitelist
+        // scan still corrects it.
+        let mut errored = barcode.clone().into_bytes();
+        errored[100] = b'G';
+        errored[102] = b'G';
+        let errored = String::from_utf8(errored).unwrap();
+        let whitelist: AHashSet<String> = [barcode.clone(), "A".repeat(128)].into_iter().collect();
+        assert_eq!(correct_barcode(&whitelist, &errored, 3), Correction::Unique(barcode));
+    }
+
+    #[test]
+    fn span_of_plain_match() {
+        assert_eq!(reference_span(&record(100, vec![Cigar::Match(50)], 0)), (100, 150));
+    }
+
+    #[test]
+    fn clips_and_insertions_do_not_extend_span() {
+        let cigar = vec![
+            Cigar::HardClip(5),
+            Cigar::SoftClip(10),
+            Cigar::Match(20),
+            Cigar::Ins(4),
+            Cigar::Match(6),
+            Cigar::SoftClip(3),
+        ];
+        assert_eq!(reference_span(&record(0, cigar, 0)), (0, 26));
+    }
+
+    #[test]
+    fn deletions_and_skips_extend_span() {
+        let cigar = vec![Cigar::Match(10), Cigar::Del(2), Cigar::Match(5), Cigar::RefSkip(1000), Cigar::Match(5)];
+        assert_eq!(reference_span(&record(200, cigar, 0)), (200, 1222));
+    }
+
+    #[test]
+    fn sequence_match_and_mismatch_ops_count() {
+        let cigar = vec![Cigar::Equal(8), Cigar::Diff(1), Cigar::Equal(8), Cigar::Pad(3)];
+        assert_eq!(reference_span(&record(7, cigar, 0)), (7, 24));
+    }
+
+    #[test]
+    fn no_reference_consumed_covers_one_base() {
+        assert_eq!(reference_span(&record(42, vec![], 0x4)), (42, 43));
+        assert_eq!(reference_span(&record(42, vec![Cigar::SoftClip(10)], 0)), (42, 43));
+    }
+
+    #[test]
+    fn cigar_reference_length_counts_reference_ops() {
+        assert_eq!(cigar_reference_length("50M2D48M"), 100);
+        assert_eq!(cigar_reference_length("10S40M5I"), 40);
+        assert_eq!(cigar_reference_length("5M100N5M"), 110);
+        assert_eq!(cigar_reference_length("8=1X8=3P"), 17);
+        assert_eq!(cigar_reference_length("*"), 0);
+        assert_eq!(cigar_reference_length(""), 0);
+    }
+
+    /// A paired read whose mate starts at `mpos` on reference 0.
+    fn with_mate(mpos: i64, flags: u16, mate_cigar: Option<&str>) -> bam::Record {
+        let mut record = record(100, vec![Cigar::Match(50)], 0x1 | flags);
+        record.set_mtid(0);
+        record.set_mpos(mpos);
+        if let Some(cigar) = mate_cigar {
+            record.push_aux(&MATE_CIGAR_TAG, Aux::String(cigar)).unwrap();
+        }
+        record
+    }
+
+    #[test]
+    fn mate_span_uses_the_mate_cigar() {
+        assert_eq!(mate_span(&with_mate(500, 0, Some("50M2D48M"))), Some((0, 500, 600)));
+        assert_eq!(mate_span(&with_mate(500, 0, Some("10S40M5I"))), Some((0, 500, 540)));
+        assert_eq!(mate_span(&with_mate(500, 0, Some("5M100N5M"))), Some((0, 500, 610)));
+    }
+
+    #[test]
+    fn mate_span_without_mc_covers_one_base() {
+        assert_eq!(mate_span(&with_mate(500, 0, None)), Some((0, 500, 501)));
+    }
+
+    #[test]
+    fn mate_span_needs_a_mapped_mate() {
+        assert_eq!(mate_span(&record(100, vec![Cigar::Match(50)], 0)), None);
+        assert_eq!(mate_span(&with_mate(500, 0x8, Some("50M"))), None);
+        let mut no_reference = with_mate(500, 0, Some("50M"));
+        no_reference.set_mtid(-1);
+        assert_eq!(mate_span(&no_reference), None);
+    }
+
+    #[test]
+    fn decode_bytes_reads_sequences_as_text() {
+        assert_eq!(decode_bytes(b"ACGTN"), "ACGTN");
+        assert_eq!(decode_bytes(b"AAAC"), "AAAC");
+    }
+
+    #[test]
+    fn decode_bytes_writes_other_bytes_as_hex() {
+        assert_eq!(decode_bytes(&[0x00, 0xff, 0x1a]), "00FF1A");
+        // Lowercase bases and a single non-base byte are not a sequence.
+        assert_eq!(decode_bytes(b"acgt"), "61636774");
+        assert_eq!(decode_bytes(b"ACGU"), "41434755");
+        assert_eq!(decode_bytes(b""), "");
+    }
+
+    #[test]
+    fn parse_hex_accepts_pairs_of_hex_digits() {
+        assert_eq!(parse_hex("41434754"), Some(b"ACGT".to_vec()));
+        assert_eq!(parse_hex("00ffA0"), Some(vec![0x00, 0xff, 0xa0]));
+        assert_eq!(parse_hex(""), Some(Vec::new()));
+    }
+
+    #[test]
+    fn parse_hex_rejects_odd_lengths_and_non_hex() {
+        for hex in ["4", "414", "4G", "ZZ", "+1", "41 3", "é1"] {
+            assert_eq!(parse_hex(hex), None, "{:?}", hex);
+        }
+    }
+
+    #[test]
+    fn hex_and_byte_array_tags_give_the_same_barcode() {
+        for bytes in [&b"ACGTAC"[..], &[0x01, 0xab, 0xff][..]] {
+            let hex: String = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            let mut string = record(0, vec![Cigar::Match(4)], 0);
+            string.push_aux(b"CB", Aux::String(&decode_bytes(bytes))).unwrap();
+            let mut hex_tag = record(0, vec![Cigar::Match(4)], 0);
+            hex_tag.push_aux(b"CB", Aux::HexByteArray(&hex)).unwrap();
+            let mut array_tag = record(0, vec![Cigar::Match(4)], 0);
+            array_tag.push_aux(b"CB", Aux::ArrayU8(bytes.into())).unwrap();
+
+            let (from_string, encoding) = tag_text(&string, b"CB").unwrap();
+            assert_eq!(encoding, BarcodeEncoding::String);
+            let (from_hex, encoding) = tag_text(&hex_tag, b"CB").unwrap();
+            assert_eq!(encoding, BarcodeEncoding::Hex);
+            let (from_array, encoding) = tag_text(&array_tag, b"CB").unwrap();
+            assert_eq!(encoding, BarcodeEncoding::ByteArray);
+            assert_eq!(from_hex, from_string);
+            assert_eq!(from_array, from_string);
+        }
+    }
+
+    #[test]
+    fn invalid_hex_tags_have_no_barcode() {
+        let mut odd = record(0, vec![Cigar::Match(4)], 0);
+        odd.push_aux(b"CB", Aux::HexByteArray("ABC")).unwrap();
+        assert_eq!(tag_text(&odd, b"CB"), None);
+        assert_eq!(ReadFilter::default().barcode(&odd), None);
+    }
+}
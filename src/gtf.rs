@@ -0,0 +1,156 @@
+//! `--gtf`: exon intervals from a GTF annotation, for counting only reads
+//! that overlap an annotated feature.
+//!
+//! Exons are grouped per reference (by the input's tid), sorted and merged, so
+//! an overlap query is a binary search over disjoint intervals.
+
+use std::io::BufRead;
+
+use ahash::AHashMap;
+use read_counter::filter::{mate_span, open_text, reference_span};
+use rust_htslib::bam::{self, HeaderView};
+
+/// Merged exon intervals per tid, 0-based half-open.
+pub struct ExonIndex {
+    intervals: Vec<Vec<(i64, i64)>>,
+}
+
+/// What [`ExonIndex::load`] read, for the summary.
+pub struct GtfSummary {
+    pub exons: usize,
+    /// Exons on references not in the input's header; these are ignored.
+    pub unknown_reference: usize,
+}
+
+impl ExonIndex {
+    /// Loads the `exon` lines of a GTF (`.gz` accepted), resolving reference
+    /// names against `header`.
+    pub fn load(path: &str, header: &HeaderView) -> Result<(ExonIndex, GtfSummary), Box<dyn std::error::Error>> {
+        let mut intervals: Vec<Vec<(i64, i64)>> = vec![Vec::new(); header.target_count() as usize];
+        let mut tids: AHashMap<String, Option<usize>> = AHashMap::new();
+        let mut summary = GtfSummary { exons: 0, unknown_reference: 0 };
+        for (line_no, line) in open_text(path)?.lines().enumerate() {
+            let line = line?;
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() < 9 {
+                return Err(format!("line {}: expected 9 tab-separated fields, found {}", line_no + 1, fields.len()).into());
+            }
+            if fields[2] != "exon" {
+                continue;
+            }
+            let parse = |value: &str| {
+                value
+                    .parse::<i64>()
+                    .map_err(|_| format!("line {}: invalid coordinate '{}'", line_no + 1, value))
+            };
+            let (start, end) = (parse(fields[3])?, parse(fields[4])?);
+            if start < 1 || end < start {
+                return Err(format!("line {}: invalid exon range {}-{}", line_no + 1, start, end).into());
+            }
+            let tid = *tids
+                .entry(fields[0].to_string())
+                .or_insert_with(|| header.tid(fields[0].as_bytes()).map(|tid| tid as usize));
+            match tid {
+                // GTF is 1-based inclusive.
+                Some(tid) => intervals[tid].push((start - 1, end)),
+                None => summary.unknown_reference += 1,
+            }
+            summary.exons += 1;
+        }
+        for per_ref in &mut intervals {
+            per_ref.sort_unstable();
+            let mut merged: Vec<(i64, i64)> = Vec::with_capacity(per_ref.len());
+            for &(start, end) in per_ref.iter() {
+                match merged.last_mut() {
+                    Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                    _ => merged.push((start, end)),
+                }
+            }
+            *per_ref = merged;
+        }
+        Ok((ExonIndex { intervals }, summary))
+    }
+
+    /// True if the reference span of a mapped `record` overlaps any exon.
+    pub fn overlaps_record(&self, record: &bam::Record) -> bool {
+        let (start, end) = reference_span(record);
+        self.overlaps(record.tid(), start, end)
+    }
+
+    /// True if the record's mate (see [`mate_span`]) overlaps an exon, for
+    /// `--mate-aware`.
+    pub fn overlaps_mate(&self, record: &bam::Record) -> bool {
+        mate_span(record).is_some_and(|(tid, start, end)| self.overlaps(tid, start, end))
+    }
+
+    /// True if `start..end` on `tid` overlaps any exon.
+    pub fn overlaps(&self, tid: i32, start: i64, end: i64) -> bool {
+        let Some(per_ref) = usize::try_from(tid).ok().and_then(|tid| self.intervals.get(tid)) else {
+            return false;
+        };
+        // First interval ending after `start`; intervals are disjoint and sorted,
+        // so it is the only candidate.
+        let i = per_ref.partition_point(|&(_, exon_end)| exon_end <= start);
+        per_ref.get(i).is_some_and(|&(exon_start, _)| exon_start < end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::header::HeaderRecord;
+
+    /// Loads `exons` (`reference, start, end`, 1-based inclusive) through a
+    /// GTF written to a temporary file, against a header with chr1 and chr2.
+    fn index(name: &str, exons: &[(&str, i64, i64)]) -> (ExonIndex, GtfSummary) {
+        let mut header = bam::Header::new();
+        for reference in ["chr1", "chr2"] {
+            header.push_record(HeaderRecord::new(b"SQ").push_tag(b"SN", reference).push_tag(b"LN", 10_000));
+        }
+        let header = HeaderView::from_header(&header);
+        let mut gtf = String::from("#!genome-build test\nchr1\ttest\tgene\t1\t9000\t.\t+\t.\tgene_id \"g\";\n");
+        for (reference, start, end) in exons {
+            gtf.push_str(&format!("{}\ttest\texon\t{}\t{}\t.\t+\t.\tgene_id \"g\";\n", reference, start, end));
+        }
+        let path = std::env::temp_dir().join(format!("read_counter_gtf_{}_{}.gtf", name, std::process::id()));
+        std::fs::write(&path, gtf).unwrap();
+        let loaded = ExonIndex::load(path.to_str().unwrap(), &header).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        loaded
+    }
+
+    #[test]
+    fn overlaps_is_half_open_at_both_ends() {
+        // Exon 101-200 is 100..200 0-based half-open.
+        let (index, _) = index("bounds", &[("chr1", 101, 200)]);
+        assert!(!index.overlaps(0, 50, 100), "read ending exactly at the exon start");
+        assert!(index.overlaps(0, 50, 101));
+        assert!(index.overlaps(0, 199, 250));
+        assert!(!index.overlaps(0, 200, 250), "read starting exactly at the exon end");
+        assert!(index.overlaps(0, 120, 130));
+        assert!(index.overlaps(0, 50, 250));
+    }
+
+    #[test]
+    fn adjacent_and_nested_exons_merge() {
+        let (index, summary) =
+            index("merge", &[("chr1", 201, 300), ("chr1", 101, 200), ("chr1", 1001, 2000), ("chr1", 1101, 1200)]);
+        assert_eq!(summary.exons, 4);
+        assert_eq!(index.intervals[0], [(100, 300), (1000, 2000)]);
+        // Past the nested exon's end but inside the enclosing one.
+        assert!(index.overlaps(0, 1500, 1510));
+        assert!(!index.overlaps(0, 300, 1000));
+    }
+
+    #[test]
+    fn overlaps_outside_the_index() {
+        let (index, summary) = index("tids", &[("chr1", 101, 200), ("chrUn", 101, 200)]);
+        assert_eq!((summary.exons, summary.unknown_reference), (2, 1));
+        assert!(!index.overlaps(1, 100, 200), "reference without exons");
+        assert!(!index.overlaps(2, 100, 200), "tid not in the header");
+        assert!(!index.overlaps(-1, 100, 200), "unmapped");
+    }
+}
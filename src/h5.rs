@@ -0,0 +1,50 @@
+//! `--h5` output (enabled with the `hdf5` cargo feature): the counts as an
+//! HDF5 file laid out like the core of an AnnData object, one observation
+//! per barcode and a single variable holding its read count.
+//!
+//! Datasets, all at the root and in output order:
+//!
+//! ```text
+//! obs_names  (n,)    variable-length UTF-8 strings: the barcodes
+//! var_names  (1,)    variable-length UTF-8 strings: "reads"
+//! X          (n, 1)  u64: reads per barcode
+//! ```
+//!
+//! This is not a full `.h5ad` (there are no `obs`/`var` dataframe groups),
+//! but builds one in a few lines:
+//!
+//! ```python
+//! import anndata, h5py, pandas as pd
+//! with h5py.File("counts.h5") as f:
+//!     adata = anndata.AnnData(
+//!         X=f["X"][:],
+//!         obs=pd.DataFrame(index=f["obs_names"].asstr()[:]),
+//!         var=pd.DataFrame(index=f["var_names"].asstr()[:]),
+//!     )
+//! ```
+
+use hdf5::types::VarLenUnicode;
+use hdf5::File;
+
+pub fn write_h5(path: &str, sorted_barcodes: &[(String, usize)]) -> Result<(), Box<dyn std::error::Error>> {
+    let file = File::create(path)?;
+    let names = sorted_barcodes
+        .iter()
+        .map(|(barcode, _)| barcode.parse::<VarLenUnicode>())
+        .collect::<Result<Vec<_>, _>>()?;
+    let counts: Vec<u64> = sorted_barcodes.iter().map(|(_, count)| *count as u64).collect();
+
+    file.new_dataset::<VarLenUnicode>()
+        .shape(names.len())
+        .create("obs_names")?
+        .write_raw(&names)?;
+    file.new_dataset::<VarLenUnicode>()
+        .shape(1)
+        .create("var_names")?
+        .write_raw(&["reads".parse::<VarLenUnicode>()?])?;
+    file.new_dataset::<u64>()
+        .shape((counts.len(), 1))
+        .create("X")?
+        .write_raw(&counts)?;
+    Ok(())
+}
@@ -0,0 +1,469 @@
+//! Opening inputs: BAM/CRAM reader setup, CRAM references, `--chrom` and
+//! `--region` index lookups, and `--skip-bad-inputs` handling when several
+//! inputs are given.
+//!
+//! Inputs are opened lazily, one at a time, and each reader is dropped before
+//! the next is opened, so a run holds at most one input's file descriptors
+//! (data file, plus index and reference for `--chrom`/`--region`/CRAM)
+//! however many inputs it is given.
+//!
+//! Inputs may also be URLs (`http(s)://`, `ftp://`, and with the `s3`/`gcs`
+//! features `s3://` and `gs://`), read through htslib's remote file support.
+//! Credentials come from the environment as htslib expects them: for S3,
+//! `AWS_ACCESS_KEY_ID`/`AWS_SECRET_ACCESS_KEY` (plus `AWS_SESSION_TOKEN`,
+//! `AWS_DEFAULT_REGION`) or `~/.aws/credentials`; for GCS, `GCS_OAUTH_TOKEN`;
+//! for HTTP, a pre-signed URL or `HTS_AUTH_LOCATION`. An index for a remote
+//! input is looked for next to it (`<url>.bai` etc.) unless `--index` names
+//! one, and htslib downloads it into the current directory.
+
+use std::cell::Cell;
+use std::path::Path;
+
+use ahash::AHashSet;
+use rust_htslib::bam::{self, HeaderView, Read};
+use rust_htslib::errors::Error as HtslibError;
+use rust_htslib::htslib;
+use url::Url;
+
+/// Settings shared by every input of a run.
+pub struct InputOptions {
+    pub reference: Option<String>,
+    pub require_reference: bool,
+    pub quiet_reference: bool,
+    pub chrom: Option<String>,
+    /// `--threads`: BGZF/CRAM decompression threads per reader.
+    pub threads: Option<usize>,
+    /// `--strict-cram`: CRAM inputs need an explicit, indexed reference.
+    pub strict_cram: bool,
+    /// `--region`: samtools-style regions, read one after another.
+    pub regions: Vec<String>,
+    /// `--index`: index file or URL for `--chrom`/`--region`, instead of the
+    /// one next to the input.
+    pub index: Option<String>,
+}
+
+/// An opened input: a plain sequential reader, an indexed one positioned on
+/// the `--chrom` reference, or an indexed one walking the `--region` list.
+pub enum InputReader {
+    Sequential(bam::Reader),
+    Indexed(bam::IndexedReader),
+    Regions(RegionReader),
+}
+
+/// One resolved `--region`: tid and 0-based half-open interval, and whether
+/// it overlaps another region (so its reads may come up twice).
+struct Region {
+    tid: i32,
+    start: i64,
+    end: i64,
+    overlaps: bool,
+}
+
+/// An indexed reader with its `--region` list, and the number of records
+/// skipped because an earlier overlapping region already returned them.
+pub struct RegionReader {
+    reader: bam::IndexedReader,
+    regions: Vec<Region>,
+    duplicates: usize,
+}
+
+/// Iterates the records of each region in turn. Records from regions that
+/// overlap another are remembered by name, position and mate, and returned
+/// only the first time; reads in regions without overlaps cost nothing extra.
+struct RegionRecords<'a> {
+    reader: &'a mut bam::IndexedReader,
+    regions: &'a [Region],
+    duplicates: &'a mut usize,
+    next_region: usize,
+    /// Whether `reader` is positioned inside `regions[next_region - 1]`.
+    active: bool,
+    seen: AHashSet<(Vec<u8>, i32, i64, u16)>,
+}
+
+impl Iterator for RegionRecords<'_> {
+    type Item = Result<bam::Record, HtslibError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if !self.active {
+                let region = self.regions.get(self.next_region)?;
+                self.next_region += 1;
+                if let Err(e) = self.reader.fetch((region.tid, region.start, region.end)) {
+                    return Some(Err(e));
+                }
+                self.active = true;
+            }
+            let mut record = bam::Record::new();
+            match self.reader.read(&mut record) {
+                None => self.active = false,
+                Some(Err(e)) => return Some(Err(e)),
+                Some(Ok(())) => {
+                    if self.regions[self.next_region - 1].overlaps {
+                        let mate = record.flags() & (htslib::BAM_FREAD1 | htslib::BAM_FREAD2) as u16;
+                        let key = (record.qname().to_vec(), record.tid(), record.pos(), mate);
+                        if !self.seen.insert(key) {
+                            *self.duplicates += 1;
+                            continue;
+                        }
+                    }
+                    return Some(Ok(record));
+                },
+            }
+        }
+    }
+}
+
+impl InputReader {
+    pub fn header(&self) -> &HeaderView {
+        match self {
+            InputReader::Sequential(reader) => reader.header(),
+            InputReader::Indexed(reader) => reader.header(),
+            InputReader::Regions(regions) => regions.reader.header(),
+        }
+    }
+
+    /// Whether the input is BAM, the one format whose reader can `tell`: the
+    /// BGZF state behind it does not exist for SAM or CRAM.
+    fn is_bam(&self) -> bool {
+        let htsfile = match self {
+            InputReader::Sequential(reader) => reader.htsfile(),
+            InputReader::Indexed(reader) => reader.htsfile(),
+            InputReader::Regions(regions) => regions.reader.htsfile(),
+        };
+        // SAFETY: the reader owns a valid htsFile.
+        unsafe { htsfile.as_ref() }.is_some_and(|file| file.format.format == htslib::htsExactFormat_bam)
+    }
+
+    /// Compressed bytes consumed so far, for BGZF (BAM) inputs.
+    pub fn compressed_offset(&self) -> Option<u64> {
+        if !self.is_bam() {
+            return None;
+        }
+        let virtual_offset = match self {
+            InputReader::Sequential(reader) => reader.tell(),
+            InputReader::Indexed(reader) => reader.tell(),
+            InputReader::Regions(regions) => regions.reader.tell(),
+        };
+        u64::try_from(virtual_offset >> 16).ok()
+    }
+
+    /// For `--region` inputs: (regions, regions overlapping another,
+    /// duplicate records skipped so far).
+    pub fn region_summary(&self) -> Option<(usize, usize, usize)> {
+        let InputReader::Regions(regions) = self else {
+            return None;
+        };
+        let overlapping = regions.regions.iter().filter(|region| region.overlaps).count();
+        Some((regions.regions.len(), overlapping, regions.duplicates))
+    }
+
+    /// Like [`records`](Self::records), but setting `offset` to the BGZF
+    /// virtual offset of each record as it is read, so that it can be read
+    /// again later with a seek. `None` unless the input is BAM; `--region`
+    /// inputs are not supported either, as their overlap handling skips
+    /// records out of sight of the caller.
+    pub fn records_with_offsets<'a>(
+        &'a mut self,
+        offset: &'a Cell<i64>,
+    ) -> Option<Box<dyn Iterator<Item = Result<bam::Record, HtslibError>> + 'a>> {
+        if !self.is_bam() {
+            return None;
+        }
+        fn read_next<R: Read>(reader: &mut R) -> Option<Result<bam::Record, HtslibError>> {
+            let mut record = bam::Record::new();
+            reader.read(&mut record).map(|result| result.map(|()| record))
+        }
+        match self {
+            InputReader::Sequential(reader) => Some(Box::new(std::iter::from_fn(move || {
+                offset.set(reader.tell());
+                read_next(reader)
+            }))),
+            InputReader::Indexed(reader) => Some(Box::new(std::iter::from_fn(move || {
+                offset.set(reader.tell());
+                read_next(reader)
+            }))),
+            InputReader::Regions(_) => None,
+        }
+    }
+
+    pub fn records(&mut self) -> Box<dyn Iterator<Item = Result<bam::Record, HtslibError>> + '_> {
+        match self {
+            InputReader::Sequential(reader) => Box::new(reader.records()),
+            InputReader::Indexed(reader) => Box::new(reader.records()),
+            InputReader::Regions(regions) => Box::new(RegionRecords {
+                reader: &mut regions.reader,
+                regions: &regions.regions,
+                duplicates: &mut regions.duplicates,
+                next_region: 0,
+                active: false,
+                seen: AHashSet::new(),
+            }),
+        }
+    }
+}
+
+/// Schemes passed to htslib as remote inputs.
+const REMOTE_SCHEMES: &[&str] = &["http", "https", "ftp", "s3", "s3+http", "s3+https", "gs", "gs+http", "gs+https"];
+
+/// `path` as a URL, if it names a remote input.
+pub fn remote_url(path: &str) -> Option<Url> {
+    let (scheme, _) = path.split_once("://")?;
+    if !REMOTE_SCHEMES.contains(&scheme.to_ascii_lowercase().as_str()) {
+        return None;
+    }
+    Url::parse(path).ok()
+}
+
+/// Resolves a samtools-style region (`chr1`, `chr1:1000`, `chr1:1,000-2,000`;
+/// 1-based, inclusive) against `header` as (tid, 0-based start, end). A name
+/// that is itself a reference wins over splitting at `:`, for contigs such as
+/// `HLA-A*01:01`.
+fn parse_region(region: &str, header: &HeaderView) -> Result<(i32, i64, i64), String> {
+    let target_len = |tid: u32| header.target_len(tid).unwrap_or(0) as i64;
+    if let Some(tid) = header.tid(region.as_bytes()) {
+        return Ok((tid as i32, 0, target_len(tid)));
+    }
+    let Some((name, range)) = region.rsplit_once(':') else {
+        return Err(format!("Reference '{}' given to --region was not found in the header.", region));
+    };
+    let invalid = || format!("Region '{}' is not a reference name or 'name:start-end'.", region);
+    let tid = header
+        .tid(name.as_bytes())
+        .ok_or_else(|| format!("Reference '{}' in --region '{}' was not found in the header.", name, region))?;
+    let coordinate = |value: &str| value.replace(',', "").parse::<i64>().ok().filter(|&n| n >= 1);
+    let (start, end) = match range.split_once('-') {
+        Some((start, end)) => (coordinate(start).ok_or_else(invalid)?, coordinate(end).ok_or_else(invalid)?),
+        None => (coordinate(range).ok_or_else(invalid)?, target_len(tid)),
+    };
+    if start > end {
+        return Err(format!("Region '{}' ends before it starts.", region));
+    }
+    Ok((tid as i32, start - 1, end))
+}
+
+/// Opens an indexed reader for `path`, with the index named by `--index` if given.
+fn open_indexed(
+    path: &str,
+    url: Option<&Url>,
+    options: &InputOptions,
+) -> Result<bam::IndexedReader, Box<dyn std::error::Error>> {
+    let input_path = Path::new(path);
+    let indexed = match (url, &options.index) {
+        (Some(url), None) => bam::IndexedReader::from_url(url),
+        // htslib's `<data>##idx##<index>` form names the index explicitly.
+        (Some(url), Some(index)) => bam::IndexedReader::from_url(&Url::parse(&format!("{}##idx##{}", url, index))?),
+        (None, Some(index)) => bam::IndexedReader::from_path_and_index(input_path, Path::new(index)),
+        (None, None) => bam::IndexedReader::from_path(input_path),
+    };
+    let reader = indexed.map_err(|e| {
+        format!(
+            "Error opening index for '{}': {}. --chrom and --region require an indexed file (.bai/.csi/.crai, or --index).",
+            input_path.display(),
+            e
+        )
+    })?;
+    Ok(reader)
+}
+
+/// Opens one input, setting the CRAM reference and `--chrom` or `--region` regions.
+pub fn open_input(path: &str, options: &InputOptions) -> Result<InputReader, Box<dyn std::error::Error>> {
+    let input_path = Path::new(path);
+    let url = remote_url(path);
+    let opened = match &url {
+        Some(url) => bam::Reader::from_url(url),
+        None => bam::Reader::from_path(input_path),
+    };
+    let mut bam_reader =
+        opened.map_err(|e| format!("Error opening BAM/CRAM file '{}': {}", input_path.display(), e))?;
+
+    debug!(
+        "Opened '{}' with {} reference sequence(s) in the header.",
+        input_path.display(),
+        bam_reader.header().target_count()
+    );
+
+    // For URLs, look at the path only: pre-signed URLs end in a query string.
+    let name = url.as_ref().map_or(path, |url| url.path());
+    let file_is_cram = name.ends_with(".cram") || name.ends_with(".crai");
+
+    if file_is_cram && options.strict_cram {
+        check_strict_cram(path, options.reference.as_deref())?;
+    }
+    if file_is_cram {
+        if let Some(ref_path_str) = &options.reference {
+            let ref_fasta_path = Path::new(&ref_path_str);
+            if let Err(e) = bam_reader.set_reference(ref_fasta_path) {
+                return Err(format!(
+                    "Error setting reference FASTA '{}' for CRAM file '{}': {}. Ensure FASTA is valid and indexed.",
+                    ref_fasta_path.display(),
+                    input_path.display(),
+                    e
+                )
+                .into());
+            }
+        } else if options.require_reference {
+            return Err(format!(
+                "No reference FASTA provided for CRAM file '{}' (--require-reference).",
+                input_path.display()
+            )
+            .into());
+        } else {
+            info!(
+                "Info: No explicit reference FASTA provided for CRAM file '{}'. HTSlib will attempt automatic reference discovery.",
+                input_path.display()
+            );
+        }
+    } else if options.reference.is_some() && !options.quiet_reference {
+        warn!(
+            "Reference FASTA provided, but input file '{}' does not appear to be CRAM. The reference will be ignored.",
+            input_path.display()
+        );
+    }
+
+    // --chrom and --region restrict reading via the index, so they need an indexed reader.
+    if options.chrom.is_none() && options.regions.is_empty() {
+        if let Some(n) = options.threads {
+            bam_reader.set_threads(n)?;
+        }
+        return Ok(InputReader::Sequential(bam_reader));
+    }
+    let mut reader = open_indexed(path, url.as_ref(), options)?;
+    if file_is_cram && let Some(ref_path_str) = &options.reference {
+        reader.set_reference(Path::new(ref_path_str))?;
+    }
+    if let Some(n) = options.threads {
+        reader.set_threads(n)?;
+    }
+    let Some(name) = &options.chrom else {
+        let mut regions = Vec::with_capacity(options.regions.len());
+        for region in &options.regions {
+            let (tid, start, end) = parse_region(region, reader.header())
+                .map_err(|e| format!("{} (input '{}')", e, input_path.display()))?;
+            regions.push(Region { tid, start, end, overlaps: false });
+        }
+        for i in 0..regions.len() {
+            regions[i].overlaps = regions.iter().enumerate().any(|(j, other)| {
+                j != i && other.tid == regions[i].tid && other.start < regions[i].end && regions[i].start < other.end
+            });
+        }
+        debug!("Reading {} regions via the index.", regions.len());
+        return Ok(InputReader::Regions(RegionReader { reader, regions, duplicates: 0 }));
+    };
+    let tid = reader.header().tid(name.as_bytes()).ok_or_else(|| {
+        format!("Reference '{}' given to --chrom was not found in the header of '{}'.", name, input_path.display())
+    })?;
+    reader.fetch(tid)?;
+    debug!("Restricting to reference '{}' (tid {}) via the index.", name, tid);
+    Ok(InputReader::Indexed(reader))
+}
+
+/// `--strict-cram` checks for one CRAM input, reporting everything missing at once.
+fn check_strict_cram(path: &str, reference: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut missing: Vec<String> = Vec::new();
+    match reference {
+        None => missing.push("no reference FASTA was given (automatic reference discovery is not allowed)".to_string()),
+        Some(reference) if !Path::new(reference).is_file() => {
+            missing.push(format!("reference FASTA '{}' does not exist", reference))
+        },
+        Some(reference) => {
+            let fai = format!("{}.fai", reference);
+            if !Path::new(&fai).is_file() {
+                missing.push(format!("reference index '{}' does not exist (create it with 'samtools faidx')", fai));
+            }
+            if reference.ends_with(".gz") && !Path::new(&format!("{}.gzi", reference)).is_file() {
+                missing.push(format!("bgzip index '{}.gzi' for the compressed reference does not exist", reference));
+            }
+        },
+    }
+    if missing.is_empty() {
+        return Ok(());
+    }
+    Err(format!("CRAM file '{}' cannot be read under --strict-cram: {}.", path, missing.join("; ")).into())
+}
+
+/// Opens the next input from `pending` that can be read.
+///
+/// Without `skip_bad` the first failure is returned as the error. With it,
+/// failures are warned about, recorded in `skipped` as (path, reason) and
+/// passed over. When `expected` is given (outputs keyed by reference id), an
+/// input whose reference names differ from it also counts as a failure.
+pub fn open_next_input<'a>(
+    pending: &mut std::slice::Iter<'a, String>,
+    options: &InputOptions,
+    expected: Option<&HeaderView>,
+    skip_bad: bool,
+    skipped: &mut Vec<(String, String)>,
+) -> Result<Option<(&'a str, InputReader)>, Box<dyn std::error::Error>> {
+    for path in pending.by_ref() {
+        let opened = open_input(path, options).and_then(|input| match expected {
+            Some(header) if header.target_names() != input.header().target_names() => Err(format!(
+                "Input '{}' has different reference sequences from the first input.",
+                path
+            )
+            .into()),
+            _ => Ok(input),
+        });
+        match opened {
+            Ok(input) => return Ok(Some((path, input))),
+            Err(e) if skip_bad => {
+                warn!("{}; skipping this input (--skip-bad-inputs).", e);
+                skipped.push((path.clone(), e.to_string()));
+            },
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::header::HeaderRecord;
+
+    fn header() -> HeaderView {
+        let mut header = bam::Header::new();
+        for (name, length) in [("chr1", 1000), ("HLA-A*01:01", 3000)] {
+            header.push_record(HeaderRecord::new(b"SQ").push_tag(b"SN", name).push_tag(b"LN", length));
+        }
+        HeaderView::from_header(&header)
+    }
+
+    #[test]
+    fn parse_region_whole_reference() {
+        assert_eq!(parse_region("chr1", &header()), Ok((0, 0, 1000)));
+    }
+
+    #[test]
+    fn parse_region_ranges_are_one_based_and_inclusive() {
+        let header = header();
+        assert_eq!(parse_region("chr1:100-200", &header), Ok((0, 99, 200)));
+        assert_eq!(parse_region("chr1:1-1", &header), Ok((0, 0, 1)));
+        // A start alone runs to the end of the reference.
+        assert_eq!(parse_region("chr1:500", &header), Ok((0, 499, 1000)));
+    }
+
+    #[test]
+    fn parse_region_strips_thousands_separators() {
+        assert_eq!(parse_region("chr1:1,000", &header()), Ok((0, 999, 1000)));
+        assert_eq!(parse_region("HLA-A*01:01:1,000-2,500", &header()), Ok((1, 999, 2500)));
+    }
+
+    #[test]
+    fn parse_region_prefers_a_reference_name_containing_a_colon() {
+        let header = header();
+        assert_eq!(parse_region("HLA-A*01:01", &header), Ok((1, 0, 3000)));
+        assert_eq!(parse_region("HLA-A*01:01:10-20", &header), Ok((1, 9, 20)));
+    }
+
+    #[test]
+    fn parse_region_rejects_bad_regions() {
+        let header = header();
+        let error = |region: &str| parse_region(region, &header).unwrap_err();
+        assert!(error("chr2").contains("was not found"));
+        assert!(error("chr2:1-10").contains("'chr2' in --region"));
+        assert!(error("chr1:200-100").contains("ends before it starts"));
+        for region in ["chr1:0-10", "chr1:a-10", "chr1:10-", "chr1:-10", "chr1:"] {
+            assert!(error(region).contains("is not a reference name"), "{}", region);
+        }
+    }
+}
@@ -0,0 +1,4 @@
+//! Library side of `read_counter`: the record filtering and barcode
+//! extraction used by the command-line tool, for reuse in other Rust code.
+
+pub mod filter;
@@ -0,0 +1,135 @@
+//! Verbosity-gated console messages, controlled by `-v/--verbose` and `-q/--quiet`.
+//!
+//! | level | flags  | shows                                   |
+//! |-------|--------|-----------------------------------------|
+//! | -2    | `-qq`  | errors only                             |
+//! | -1    | `-q`   | warnings and errors                     |
+//! |  0    |        | progress/summary info (the default)     |
+//! |  1    | `-v`   | plus per-file debug detail              |
+//!
+//! Info goes to stdout as before; warnings, errors and debug output go to
+//! stderr. Fatal errors are printed unconditionally with `error!`. Every
+//! diagnostic goes through these macros. What a command was asked to print
+//! (the usage text, `--preview` rows, `--dump-header`, `--bench` results and
+//! the `--selftest` verdict) is its output, not a diagnostic, and is printed
+//! directly so that `-q` can't hide it.
+//!
+//! With `--log-json FILE`, every warning and error is also appended to FILE
+//! as one JSON object per line, whatever the verbosity:
+//! `{"level": "warning", "message": "...", "input": "a.bam", "record": 1234}`.
+//! `input` and `record` (1-based, counting unreadable records too) say where
+//! the read loop was at the time, and are `null` outside it. Each line is
+//! written with a single unbuffered write, so the file is complete even when
+//! an error exits the process.
+
+use std::fs::File;
+use std::io::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicI8, AtomicU64, Ordering};
+
+static VERBOSITY: AtomicI8 = AtomicI8::new(0);
+static JSON_LOG_ENABLED: AtomicBool = AtomicBool::new(false);
+static JSON_LOG: Mutex<Option<File>> = Mutex::new(None);
+static CURRENT_INPUT: Mutex<Option<String>> = Mutex::new(None);
+/// The record being processed, 1-based; 0 outside the read loop.
+static CURRENT_RECORD: AtomicU64 = AtomicU64::new(0);
+
+pub fn set_verbosity(level: i8) {
+    VERBOSITY.store(level, Ordering::Relaxed);
+}
+
+pub fn verbosity() -> i8 {
+    VERBOSITY.load(Ordering::Relaxed)
+}
+
+/// Starts `--log-json`, truncating `path`.
+pub fn open_json_log(path: &str) -> std::io::Result<()> {
+    let file = File::create(path)?;
+    *JSON_LOG.lock().unwrap_or_else(|e| e.into_inner()) = Some(file);
+    JSON_LOG_ENABLED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+pub fn json_log_enabled() -> bool {
+    JSON_LOG_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Sets the input named in `--log-json` records from now on.
+pub fn set_input(path: &str) {
+    if json_log_enabled() {
+        *CURRENT_INPUT.lock().unwrap_or_else(|e| e.into_inner()) = Some(path.to_string());
+    }
+}
+
+/// Sets the record index named in `--log-json` records from now on.
+pub fn set_record(index: usize) {
+    CURRENT_RECORD.store(index as u64, Ordering::Relaxed);
+}
+
+/// Leaves the read loop: later records carry no input or record index.
+pub fn clear_context() {
+    *CURRENT_INPUT.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    CURRENT_RECORD.store(0, Ordering::Relaxed);
+}
+
+/// Appends a `--log-json` record, if the log is open.
+pub fn log_json(level: &str, message: &str) {
+    if !json_log_enabled() {
+        return;
+    }
+    let input = CURRENT_INPUT
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_deref()
+        .map_or("null".to_string(), crate::json_string);
+    let record = match CURRENT_RECORD.load(Ordering::Relaxed) {
+        0 => "null".to_string(),
+        index => index.to_string(),
+    };
+    let line = format!(
+        "{{\"level\": {}, \"message\": {}, \"input\": {}, \"record\": {}}}\n",
+        crate::json_string(level),
+        crate::json_string(message),
+        input,
+        record
+    );
+    if let Some(file) = JSON_LOG.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        let _ = file.write_all(line.as_bytes());
+    }
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => {{
+        let message = format!($($arg)*);
+        eprintln!("Error: {}", message);
+        $crate::logging::log_json("error", &message);
+    }};
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        if $crate::logging::verbosity() >= -1 || $crate::logging::json_log_enabled() {
+            let message = format!($($arg)*);
+            if $crate::logging::verbosity() >= -1 {
+                eprintln!("Warning: {}", message);
+            }
+            $crate::logging::log_json("warning", &message);
+        }
+    };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::logging::verbosity() >= 0 {
+            println!($($arg)*);
+        }
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if $crate::logging::verbosity() >= 1 {
+            eprintln!("Debug: {}", format_args!($($arg)*));
+        }
+    };
+}
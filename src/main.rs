@@ -1,105 +1,344 @@
-use rust_htslib::bam::{self, record::Aux, Read};
-use rust_htslib::errors::Error as HtslibError; // This import is crucial
-//use rayon::prelude::*;
-use std::collections::HashMap;
+use rust_htslib::bam::{self, record::Aux};
+use rust_htslib::{bgzf, htslib};
+use rayon::prelude::*;
+use std::borrow::Cow;
+use std::cell::Cell;
 use std::env;
 use std::fs::File;
-use std::io::{BufWriter, Write};
+use std::fmt::Write as _;
+use std::io::{BufRead, BufWriter, Write};
 use std::path::Path;
 use std::process;
+use std::time::{Duration, Instant};
 
-use ahash::AHashMap;
+use ahash::{AHashMap, AHashSet};
+use read_counter::filter::{
+    correct_barcode, load_whitelist, open_text, reference_span, tag_text, BarcodeEncoding, Correction, ReadFilter,
+    Rejection,
+};
+
+use input::{open_next_input, InputOptions};
+use options::Options;
+
+#[macro_use]
+mod logging;
+mod bench;
+#[cfg(feature = "bincode")]
+mod compact;
+mod diff;
+mod gtf;
+mod input;
+#[cfg(feature = "msgpack")]
+mod msgpack;
+#[cfg(feature = "hdf5")]
+mod h5;
+mod options;
+mod profile;
+#[cfg(feature = "script")]
+mod script;
+mod selftest;
+mod spill;
+#[cfg(feature = "sqlite")]
+mod sqlite;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let result = run();
+    if let Err(e) = &result {
+        logging::log_json("error", &e.to_string());
+    }
+    result
+}
+
+fn run() -> Result<(), Box<dyn std::error::Error>> {
     let args: Vec<String> = env::args().collect();
     if args.len() < 2 {
         print_usage(&args[0]);
         process::exit(1);
     }
-    
+    // Before any parsing, so no other flag can be silently dropped; given
+    // with other arguments it is rejected below.
+    if args.len() == 2 && args[1] == "--selftest" {
+        selftest::run();
+    }
+
     // --- Argument Parsing ---
-    let mut input_path_str: Option<String> = None;
-    let mut ref_fasta_path_str: Option<String> = None;
-    let mut max_records: Option<usize> = None;
-
-    let mut arg_iter = args.iter().skip(1);
-    while let Some(arg) = arg_iter.next() {
-        match arg.as_str() {
-            "-n" | "--limit" => {
-                if let Some(val_str) = arg_iter.next() {
-                    match val_str.parse::<usize>() {
-                        Ok(n) => max_records = Some(n),
-                        Err(_) => {
-                            eprintln!("Error: --limit value '{}' is not a valid positive integer.", val_str);
-                            process::exit(1);
-                        }
-                    }
-                } else {
-                    eprintln!("Error: --limit flag requires a number.");
-                    process::exit(1);
-                }
-            },
-            _ if arg.starts_with('-') => {
-                eprintln!("Error: Unknown flag '{}'", arg);
-                print_usage(&args[0]);
-                process::exit(1);
-            }
-            _ => { // Positional arguments
-                if input_path_str.is_none() {
-                    input_path_str = Some(arg.clone());
-                } else if ref_fasta_path_str.is_none() {
-                    ref_fasta_path_str = Some(arg.clone());
-                } else {
-                    eprintln!("Error: Too many positional arguments provided.");
-                    print_usage(&args[0]);
-                    process::exit(1);
-                }
-            }
+    let options = Options::parse(&args)?;
+    let options_json = options.json();
+    let Options {
+        inputs,
+        input_globs,
+        ref_fasta_path_str,
+        max_records,
+        bin_size,
+        proper_pair_only,
+        sample_fraction,
+        seed,
+        stats_path,
+        write_meta,
+        output_format,
+        output_path,
+        count_first,
+        tag_split,
+        tag_composite,
+        max_runtime,
+        by_splice,
+        gc_stats,
+        strand_ratio,
+        mapq_tiers,
+        coord_range,
+        verbosity: _,
+        chrom,
+        index_path,
+        ref_cache,
+        mate_aware,
+        regions,
+        dominant_reference,
+        reference_breadth,
+        split_by_reference,
+        whitelist_path,
+        whitelist_ci,
+        include_zero,
+        off_whitelist_path,
+        whitelist_correct,
+        separate_unmapped,
+        secondary_separate,
+        dup_separate,
+        multimap_rate,
+        merge_supplementary,
+        reference_totals_path,
+        window_size,
+        unmapped_output,
+        tag_required,
+        count_missing_as,
+        strip_suffix,
+        keep_suffix: _,
+        compat_10x: _,
+        strict,
+        preview,
+        preview_only,
+        max_error_rate,
+        fail_on_empty,
+        umi_tag,
+        top_umi,
+        min_umi_reads,
+        umi_length_diagnostic,
+        tag_length_diagnostic,
+        quiet_reference,
+        require_reference,
+        strict_cram,
+        barcode_tags,
+        extra_tags,
+        sort_order,
+        split_output,
+        r_output,
+        min_length,
+        max_length,
+        template_length,
+        barcode_length,
+        barcode_regex,
+        barcode_map_path,
+        barcode_map_drop_unlisted,
+        cluster_map_path,
+        gtf_path,
+        weight_tag,
+        missing_weight,
+        per_input,
+        build_barcode_index,
+        skip_bad_inputs,
+        threads,
+        estimate,
+        tag_concat,
+        tag_summary,
+        bench,
+        bench_sweep,
+        dump_header,
+        profile_enabled,
+        log_json_path: _,
+        diff_files,
+        checksum,
+        external_sort,
+        temp_dir,
+        schema,
+        stream_output,
+        normalize_to,
+        with_raw,
+        with_rank,
+        cumulative_fraction,
+        count_range,
+        cap_count,
+        limit_per_barcode,
+        knee_data_path,
+        #[cfg(feature = "sqlite")]
+        sqlite_path,
+        #[cfg(feature = "hdf5")]
+        h5_path,
+        #[cfg(feature = "script")]
+        script_path,
+    } = options;
+
+    let mut profile = profile::Profile::new(profile_enabled);
+
+    // --diff compares two existing count files and reads no BAM/CRAM.
+    if let Some((path_a, path_b)) = &diff_files {
+        let output = output_path.as_deref().unwrap_or("reads_per_barcode_diff");
+        let (changed, only_a, only_b) = diff::write_diff(path_a, path_b, output)?;
+        info!(
+            "{} barcodes changed between '{}' and '{}' ({} only in the first, {} only in the second); \
+             deltas written to '{}'",
+            changed, path_a, path_b, only_a, only_b, output
+        );
+        return Ok(());
+    }
+
+    if let Some(thread_counts) = &bench_sweep {
+        bench::run_sweep(&args, thread_counts);
+    }
+    if strict_cram {
+        // REF_PATH/REF_CACHE let htslib fetch a reference by checksum from a
+        // shared cache, which --strict-cram rules out.
+        // SAFETY: no other threads exist yet; the rayon pool is built below.
+        unsafe {
+            env::remove_var("REF_PATH");
+            env::remove_var("REF_CACHE");
+        }
+    }
+    if let Some(dir) = &ref_cache {
+        if strict_cram {
+            error!("--ref-cache cannot be combined with --strict-cram, which disables the reference cache.");
+            process::exit(1);
+        }
+        if let Err(e) = check_writable_dir(dir) {
+            error!("--ref-cache directory '{}' is not usable: {}.", dir, e);
+            process::exit(1);
+        }
+        // The same two-level layout samtools' seq_cache_populate.pl creates.
+        // SAFETY: no other threads exist yet; the rayon pool is built below.
+        unsafe {
+            env::set_var("REF_CACHE", format!("{}/%2s/%2s/%s", dir.trim_end_matches('/')));
+        }
+    }
+    // Conflicting flags were rejected by Options::parse.
+    let spill_dir = if external_sort {
+        let dir = temp_dir.clone().unwrap_or_else(|| env::temp_dir().to_string_lossy().into_owned());
+        if let Err(e) = check_writable_dir(&dir) {
+            error!("--temp-dir directory '{}' is not usable: {}.", dir, e);
+            process::exit(1);
+        }
+        Some(dir)
+    } else {
+        if temp_dir.is_some() {
+            error!("--temp-dir requires --external-sort.");
+            process::exit(1);
         }
+        None
+    };
+    if let Some(n) = threads {
+        rayon::ThreadPoolBuilder::new().num_threads(n).build_global()?;
     }
 
-    let input_path_str = input_path_str.ok_or_else(|| {
-        eprintln!("Error: Missing required input BAM/CRAM file.");
-        print_usage(&args[0]);
-        "Missing input file".to_string()
-    })?;
-    
+
     // --- BAM/CRAM Reader Setup ---
-    let input_path = Path::new(&input_path_str);
-    let mut bam_reader = bam::Reader::from_path(input_path)
-        .map_err(|e| format!("Error opening BAM/CRAM file '{}': {}", input_path.display(), e))?;
-
-    let file_is_cram = input_path_str.ends_with(".cram") || input_path_str.ends_with(".crai");
-
-    if file_is_cram {
-        if let Some(ref_path_str) = ref_fasta_path_str {
-            let ref_fasta_path = Path::new(&ref_path_str);
-            if let Err(e) = bam_reader.set_reference(ref_fasta_path) {
-                return Err(format!(
-                    "Error setting reference FASTA '{}' for CRAM file '{}': {}. Ensure FASTA is valid and indexed.",
-                    ref_fasta_path.display(),
-                    input_path.display(),
-                    e
-                )
-                .into());
-            }
-        } else {
-            println!(
-                "Info: No explicit reference FASTA provided for CRAM file '{}'. HTSlib will attempt automatic reference discovery.",
-                input_path.display()
-            );
-        }
-    } else if ref_fasta_path_str.is_some() {
-        eprintln!(
-            "Warning: Reference FASTA provided, but input file '{}' does not appear to be CRAM. The reference will be ignored.",
-            input_path.display()
-        );
+    let input_options = InputOptions {
+        reference: ref_fasta_path_str.clone(),
+        require_reference,
+        quiet_reference,
+        chrom: chrom.clone(),
+        threads,
+        strict_cram,
+        regions: regions.clone(),
+        index: index_path,
+    };
+    // Outputs keyed by reference id need every input to share the first one's references.
+    let tid_keyed = bin_size.is_some()
+        || dominant_reference
+        || reference_breadth
+        || split_by_reference.is_some()
+        || gtf_path.is_some()
+        || coord_range
+        || reference_totals_path.is_some();
+    let mut skipped_inputs: Vec<(String, String)> = Vec::new();
+    let mut pending_inputs = inputs.iter();
+    let first_input = open_next_input(&mut pending_inputs, &input_options, None, skip_bad_inputs, &mut skipped_inputs)?
+        .ok_or("None of the inputs could be opened.")?;
+    let header = first_input.1.header().clone();
+    if dump_header {
+        print_header(&header);
+        return Ok(());
     }
-    
+
+    let whitelist = match &whitelist_path {
+        Some(path) => {
+            let mut set = load_whitelist(path).map_err(|e| format!("Error reading whitelist '{}': {}", path, e))?;
+            if whitelist_ci {
+                set = set.into_iter().map(|barcode| barcode.to_ascii_uppercase()).collect();
+            }
+            info!("Loaded {} whitelisted barcodes from '{}'.", set.len(), path);
+            Some(set)
+        },
+        None => None,
+    };
+    let barcode_map = match &barcode_map_path {
+        Some(path) => {
+            let map = load_barcode_map(path, "observed canonical")
+                .map_err(|e| format!("Error reading barcode map '{}': {}", path, e))?;
+            info!("Loaded {} barcode mappings from '{}'.", map.len(), path);
+            Some(map)
+        },
+        None => None,
+    };
+    let cluster_map = match &cluster_map_path {
+        Some(path) => {
+            let map = load_barcode_map(path, "barcode cluster")
+                .map_err(|e| format!("Error reading cluster map '{}': {}", path, e))?;
+            let clusters: AHashSet<&String> = map.values().collect();
+            info!("Loaded {} barcodes in {} clusters from '{}'.", map.len(), clusters.len(), path);
+            Some(map)
+        },
+        None => None,
+    };
+    #[cfg(feature = "script")]
+    let mut script = match &script_path {
+        Some(path) => Some(script::Script::load(path)?),
+        None => None,
+    };
+    let exon_index = match &gtf_path {
+        Some(path) => {
+            let (index, summary) = gtf::ExonIndex::load(path, &header)
+                .map_err(|e| format!("Error reading GTF '{}': {}", path, e))?;
+            info!("Loaded {} exons from '{}'.", summary.exons, path);
+            if summary.unknown_reference > 0 {
+                warn!(
+                    "{} exons in '{}' are on references not in the input header; they are ignored.",
+                    summary.unknown_reference, path
+                );
+            }
+            Some(index)
+        },
+        None => None,
+    };
+    let read_filter = ReadFilter {
+        barcode_tags,
+        proper_pair_only,
+        min_length,
+        max_length,
+        template_length,
+        whitelist,
+    };
+
+    let inputs_desc = match inputs.as_slice() {
+        [single] => format!("'{}'", single),
+        _ => format!("{} inputs", inputs.len()),
+    };
+    let scope = match (&chrom, regions.as_slice()) {
+        (Some(name), _) => format!("{} (reference '{}')", inputs_desc, name),
+        (None, []) => inputs_desc.clone(),
+        (None, [region]) => format!("{} (region '{}')", inputs_desc, region),
+        (None, regions) => format!("{} ({} regions)", inputs_desc, regions.len()),
+    };
     if let Some(limit) = max_records {
-        println!("Processing up to {} records from '{}'...", limit, input_path.display());
+        info!("Processing up to {} records from {}...", limit, scope);
     } else {
-        println!("Processing all records from '{}'...", input_path.display());
+        info!("Processing all records from {}...", scope);
     }
 
     // // --- Core Processing Logic ---
@@ -111,65 +350,3745 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     //     };
 
     // --- Combined Phase: Read records and count barcodes directly ---
-    println!("Reading records and counting barcodes...");
-    let mut barcode_counts: AHashMap<String, usize> = AHashMap::new();
+    info!("Reading records and counting barcodes...");
+    let mut counts = BarcodeCounts::new(CountModes {
+        bin_size,
+        by_splice,
+        dominant_reference,
+        reference_breadth,
+        per_reference: split_by_reference.is_some(),
+        umi: umi_tag.is_some(),
+        umi_lengths: umi_length_diagnostic,
+        umi_reads: top_umi || min_umi_reads.is_some(),
+        gc: gc_stats,
+        strand: strand_ratio,
+        mapq_tiers: mapq_tiers.clone(),
+        coord_range,
+    });
+    let mut dropped_not_proper_pair: usize = 0;
+    let mut dropped_length: usize = 0;
+    let mut dropped_template_length: usize = 0;
+    // --expect-barcode-length: barcodes checked, and those of the wrong length.
+    let mut barcode_length_checked: usize = 0;
+    let mut barcode_length_mismatch: usize = 0;
+    // --count-by-tag-value-length: reads per barcode tag value length.
+    let mut tag_value_lengths: AHashMap<usize, usize> = AHashMap::new();
+    let mut regex_dropped: usize = 0;
+    // --cluster-map: barcoded reads whose barcode is in no cluster.
+    let mut unassigned: usize = 0;
+    // For the filter accounting: reads dropped for lacking a barcode tag,
+    // barcodes taken from the tags (more than reads with --tag-split), and
+    // barcodes set aside by --separate-unmapped, --secondary-separate and
+    // --dup-separate.
+    let mut untagged_dropped: usize = 0;
+    let mut barcode_occurrences: usize = 0;
+    let mut separated_unmapped: usize = 0;
+    let mut separated_secondary: usize = 0;
+    let mut separated_duplicate: usize = 0;
+    // --limit-per-barcode: reads not counted because their barcode was full,
+    // and barcodes that filled up.
+    let mut over_barcode_limit: usize = 0;
+    let mut saturated: usize = 0;
+    let mut all_saturated = false;
+    let mut sampled_out: usize = 0;
+    // --multimap-rate: secondary and supplementary records seen (and skipped).
+    let mut secondary_reads: usize = 0;
+    let mut supplementary_reads: usize = 0;
+    let mut off_whitelist: usize = 0;
+    // --whitelist-correct outcome per distinct off-whitelist barcode.
+    let mut corrections: AHashMap<String, Correction> = AHashMap::new();
+    let mut corrected: usize = 0;
+    let mut correction_ambiguous: usize = 0;
+    // --whitelist-ci: occurrences that matched only once uppercased.
+    let mut case_folded: usize = 0;
+    let mut remapped: usize = 0;
+    let mut unlisted_dropped: usize = 0;
+    let mut tag_missing: usize = 0;
+    let mut umi_missing: usize = 0;
+    let mut weight_missing: usize = 0;
+    #[cfg(feature = "script")]
+    let mut script_skipped: usize = 0;
+    let mut concat_incomplete: usize = 0;
+    // Barcodes read from H and B:C tags rather than Z strings.
+    let mut hex_encoded: usize = 0;
+    let mut byte_array_encoded: usize = 0;
+    let mut tag_summary_values: AHashMap<String, usize> = AHashMap::new();
+    let mut tag_summary_reads: usize = 0;
+    let mut off_feature: usize = 0;
+    // Reads whose barcode came from each entry of `barcode_tags`.
+    let mut tag_hits: Vec<usize> = vec![0; read_filter.barcode_tags.len()];
+    let tag_list = match &tag_concat {
+        Some(concat) => concat.tags.iter().map(|tag| String::from_utf8_lossy(tag)).collect::<Vec<_>>().join("+"),
+        None => tag_names(&read_filter.barcode_tags),
+    };
+    let mut off_whitelist_counts: AHashMap<String, usize> = AHashMap::new();
+    // --separate-unmapped: unmapped barcoded reads, kept out of `counts`.
+    let mut unmapped_counts: AHashMap<String, usize> = AHashMap::new();
+    let mut secondary_counts: AHashMap<String, usize> = AHashMap::new();
+    // --dup-separate: duplicate-flagged reads per barcode, kept out of the main counts.
+    let mut duplicate_counts: AHashMap<String, usize> = AHashMap::new();
+    // --reference-totals: (reads, barcoded reads) per tid, with unmapped reads last.
+    let mut reference_totals: Vec<(usize, usize)> = match reference_totals_path {
+        Some(_) => vec![(0, 0); header.target_count() as usize + 1],
+        None => Vec::new(),
+    };
+    // Counts per value of each additional --tag, index-aligned with `extra_tags`.
+    let mut extra_counts: Vec<AHashMap<String, usize>> = vec![AHashMap::new(); extra_tags.len()];
+    let mut records_read: usize = 0;
+    let mut inputs_read: usize = 0;
+    // Records taken from the readers, including unreadable ones, for --limit.
+    let mut records_scanned: usize = 0;
+    let mut record_errors: usize = 0;
+    // --region: (regions overlapping another, records skipped as already read), from the last input.
+    let mut region_overlaps: usize = 0;
+    let mut region_duplicates: usize = 0;
+    // --estimate: compressed bytes consumed when reading stopped.
+    let mut estimate_offset: Option<u64> = None;
+    let mut rng = SplitMix64::new(seed);
+    let mut windows = window_size.map(BarcodeWindows::new);
+    let mut timed_out = false;
+    let mut decode_time = Duration::ZERO;
+    // --per-input: counts of each input read, in reading order.
+    let mut per_input_counts: Vec<(String, AHashMap<String, usize>)> = Vec::new();
+    // --build-barcode-index: inputs read, and each barcode's records as
+    // (index into those inputs, virtual offset).
+    let mut offset_inputs: Vec<String> = Vec::new();
+    let mut barcode_offsets: AHashMap<String, Vec<(usize, i64)>> = AHashMap::new();
+    let record_offset = Cell::new(0i64);
+    let mut spill = spill_dir.as_deref().map(|dir| spill::Spill::new(Path::new(dir)));
+    // --preview: barcoded records still to print.
+    let mut preview_left = preview.unwrap_or(0);
+    if preview_left > 0 {
+        println!("qname\tflag\treference\tpos\tbarcode");
+    }
+    profile.phase("setup");
+    let start_time = Instant::now();
     
-    let records_iterator = bam_reader.records();
-
-    // Conditionally apply the limit
-    let limited_iterator: Box<dyn Iterator<Item = Result<bam::Record, HtslibError>>> = 
-        if let Some(limit) = max_records {
-            Box::new(records_iterator.take(limit))
+    let mut current_input = Some(first_input);
+    while let Some((input_path, mut input)) = current_input.take() {
+        if inputs.len() > 1 {
+            debug!("Reading '{}'.", input_path);
+        }
+        inputs_read += 1;
+        logging::set_input(input_path);
+        if per_input {
+            per_input_counts.push((input_path.to_string(), AHashMap::new()));
+        }
+        let inner = if build_barcode_index {
+            offset_inputs.push(input_path.to_string());
+            input.records_with_offsets(&record_offset).ok_or_else(|| {
+                format!("--build-barcode-index needs BAM inputs (CRAM and SAM records have no virtual offset): '{}'.", input_path)
+            })?
         } else {
-            Box::new(records_iterator)
+            input.records()
         };
-
-    for record_result in limited_iterator {
-        match record_result {
-            Ok(record) => match record.aux(b"CB") {
-                Ok(Aux::String(bc_str)) => {
-                    *barcode_counts.entry(bc_str.to_string()).or_insert(0) += 1;
+        let records: Box<dyn Iterator<Item = _>> = if profile.enabled() {
+            Box::new(profile::Timed { inner, spent: &mut decode_time })
+        } else {
+            inner
+        };
+        for record_result in records {
+            if max_records.is_some_and(|limit| records_scanned >= limit) {
+                break;
+            }
+            records_scanned += 1;
+            logging::set_record(records_scanned);
+            if let Some(budget) = max_runtime
+                && records_read.is_multiple_of(RUNTIME_CHECK_INTERVAL)
+                && start_time.elapsed() >= budget
+            {
+                timed_out = true;
+                break;
+            }
+            // --external-sort: hold at most one run of barcodes in memory.
+            if let Some(spill) = &mut spill
+                && counts.counts.len() >= spill::RUN_BARCODES
+            {
+                spill.write_run(&mut counts.counts)?;
+            }
+            match record_result {
+                Ok(record) => {
+                    records_read += 1;
+                    if let Some(windows) = &mut windows {
+                        windows.add_record();
+                    }
+                    // One draw per record, in file order, so a given seed always keeps the same reads.
+                    if let Some(fraction) = sample_fraction
+                        && rng.next_f64() >= fraction
+                    {
+                        sampled_out += 1;
+                        continue;
+                    }
+                    // Index into `reference_totals`, if kept.
+                    let totals_index = match reference_totals.len() {
+                        0 => None,
+                        len if record.is_unmapped() || record.tid() < 0 => Some(len - 1),
+                        _ => Some(record.tid() as usize),
+                    };
+                    if let Some(index) = totals_index {
+                        reference_totals[index].0 += 1;
+                    }
+                    if let Some(tag) = tag_summary
+                        && tag_summary_reads < TAG_SUMMARY_READS
+                    {
+                        tag_summary_reads += 1;
+                        *tag_summary_values.entry(aux_display(&record, &tag)).or_insert(0) += 1;
+                    }
+                    // --merge-supplementary: a chimeric read's supplementary pieces belong to
+                    // the template its primary record already counts.
+                    if (multimap_rate && (record.is_secondary() || record.is_supplementary()))
+                        || (merge_supplementary && record.is_supplementary())
+                    {
+                        if record.is_secondary() {
+                            secondary_reads += 1;
+                        } else {
+                            supplementary_reads += 1;
+                        }
+                        continue;
+                    }
+                    match read_filter.check_record(&record) {
+                        Ok(()) => (),
+                        Err(Rejection::NotProperPair) => {
+                            dropped_not_proper_pair += 1;
+                            continue;
+                        },
+                        Err(Rejection::TemplateLength) => {
+                            dropped_template_length += 1;
+                            continue;
+                        },
+                        Err(_) => {
+                            dropped_length += 1;
+                            continue;
+                        },
+                    }
+                    if let Some(index) = &exon_index
+                        && (record.is_unmapped() || !index.overlaps_record(&record))
+                        && !(mate_aware && index.overlaps_mate(&record))
+                    {
+                        off_feature += 1;
+                        continue;
+                    }
+                    for (tag, tag_counts) in extra_tags.iter().zip(extra_counts.iter_mut()) {
+                        if let Some((value, _)) = tag_text(&record, tag) {
+                            *tag_counts.entry(value.into_owned()).or_insert(0) += 1;
+                        }
+                    }
+                    let barcode_value: Option<Cow<str>> = match &tag_concat {
+                        Some(concat) => {
+                            let (value, partial) = concat.barcode(&record);
+                            if partial {
+                                concat_incomplete += 1;
+                            }
+                            value.map(Cow::Owned)
+                        },
+                        None => read_filter.barcode_encoded(&record).map(|(i, bc_str, encoding)| {
+                            tag_hits[i] += 1;
+                            match encoding {
+                                BarcodeEncoding::String => (),
+                                BarcodeEncoding::Hex => hex_encoded += 1,
+                                BarcodeEncoding::ByteArray => byte_array_encoded += 1,
+                            }
+                            bc_str
+                        }),
+                    };
+                    let bc_str = match &barcode_value {
+                        Some(bc_str) if strip_suffix => strip_barcode_suffix(bc_str),
+                        Some(bc_str) => bc_str.as_ref(),
+                        None if tag_required => {
+                            tag_missing += 1;
+                            untagged_dropped += 1;
+                            if strict {
+                                return Err(format!(
+                                    "Read '{}' has no usable {} tag (--tag-required with --strict).",
+                                    String::from_utf8_lossy(record.qname()),
+                                    tag_list
+                                )
+                                .into());
+                            }
+                            continue;
+                        },
+                        None => match &count_missing_as {
+                            Some(label) => {
+                                tag_missing += 1;
+                                label.as_str()
+                            },
+                            None => {
+                                // Tag not found or not a string, do nothing
+                                untagged_dropped += 1;
+                                continue;
+                            },
+                        },
+                    };
+                    if tag_length_diagnostic && barcode_value.is_some() {
+                        *tag_value_lengths.entry(bc_str.len()).or_insert(0) += 1;
+                    }
+                    if let Some((min, max)) = barcode_length
+                        && barcode_value.is_some()
+                    {
+                        barcode_length_checked += 1;
+                        if !(min..=max).contains(&bc_str.len()) {
+                            barcode_length_mismatch += 1;
+                            continue;
+                        }
+                    }
+                    if let Some(re) = &barcode_regex
+                        && barcode_value.is_some()
+                        && !re.is_match(bc_str)
+                    {
+                        regex_dropped += 1;
+                        continue;
+                    }
+                    if preview_left > 0 && barcode_value.is_some() {
+                        print_preview_row(&record, &header, bc_str);
+                        preview_left -= 1;
+                        if preview_left == 0 && preview_only {
+                            return Ok(());
+                        }
+                    }
+                    if let Some(index) = totals_index
+                        && barcode_value.is_some()
+                    {
+                        reference_totals[index].1 += 1;
+                    }
+                    if let Some(windows) = &mut windows
+                        && barcode_value.is_some()
+                    {
+                        windows.add_barcode(bc_str);
+                    }
+                    let umi = match umi_tag {
+                        Some(tag) => match record.aux(&tag) {
+                            Ok(Aux::String(umi)) => Some(umi),
+                            _ => {
+                                umi_missing += 1;
+                                None
+                            },
+                        },
+                        None => None,
+                    };
+                    let weight = match weight_tag {
+                        Some(tag) => match aux_weight(&record, &tag) {
+                            Some(weight) => weight,
+                            None => {
+                                weight_missing += 1;
+                                match missing_weight {
+                                    MissingWeight::CountOne => 1,
+                                    MissingWeight::Skip => continue,
+                                }
+                            },
+                        },
+                        None => 1,
+                    };
+                    #[cfg(not(feature = "script"))]
+                    let direct_key = barcode_value.is_none().then_some(Cow::Borrowed(bc_str));
+                    #[cfg(feature = "script")]
+                    let (direct_key, weight) = match &mut script {
+                        Some(script) => {
+                            let barcode = barcode_value.is_some().then_some(bc_str);
+                            match script.key(&record, &header, barcode, umi, weight) {
+                                Ok(Some((key, weight))) => (Some(Cow::Owned(key)), weight),
+                                Ok(None) => {
+                                    script_skipped += 1;
+                                    continue;
+                                },
+                                Err(e) => {
+                                    return Err(format!(
+                                        "--script failed on read '{}': {}",
+                                        String::from_utf8_lossy(record.qname()),
+                                        e
+                                    )
+                                    .into());
+                                },
+                            }
+                        },
+                        None => (barcode_value.is_none().then_some(Cow::Borrowed(bc_str)), weight),
+                    };
+                    // The --count-missing-as label and --script keys are not barcodes: they are
+                    // not split, remapped or checked against the whitelist, but are separated
+                    // and limited like one.
+                    let is_barcode = direct_key.is_none();
+                    let keys = match direct_key {
+                        Some(key) => vec![key],
+                        None => barcode_keys(bc_str, tag_split.as_deref(), tag_composite),
+                    };
+                    for mut barcode in keys {
+                        barcode_occurrences += 1;
+                        if is_barcode {
+                            if let Some(map) = &barcode_map {
+                                match map.get(barcode.as_ref()) {
+                                    Some(canonical) => {
+                                        remapped += 1;
+                                        barcode = Cow::Owned(canonical.clone());
+                                    },
+                                    None if barcode_map_drop_unlisted => {
+                                        unlisted_dropped += 1;
+                                        continue;
+                                    },
+                                    None => (),
+                                }
+                            }
+                            if whitelist_ci && !read_filter.is_whitelisted(&barcode) {
+                                let folded = barcode.to_ascii_uppercase();
+                                if read_filter.is_whitelisted(&folded) {
+                                    case_folded += 1;
+                                    barcode = Cow::Owned(folded);
+                                }
+                            }
+                            if !read_filter.is_whitelisted(&barcode) {
+                                let correction = match (whitelist_correct, &read_filter.whitelist) {
+                                    (Some(max_distance), Some(allowed)) => {
+                                        if !corrections.contains_key(barcode.as_ref()) {
+                                            let outcome = correct_barcode(allowed, &barcode, max_distance);
+                                            corrections.insert(barcode.to_string(), outcome);
+                                        }
+                                        corrections.get(barcode.as_ref())
+                                    },
+                                    _ => None,
+                                };
+                                match correction {
+                                    Some(Correction::Unique(canonical)) => {
+                                        corrected += 1;
+                                        barcode = Cow::Owned(canonical.clone());
+                                    },
+                                    outcome => {
+                                        if outcome == Some(&Correction::Ambiguous) {
+                                            correction_ambiguous += 1;
+                                        }
+                                        off_whitelist += 1;
+                                        if off_whitelist_path.is_some() {
+                                            *off_whitelist_counts.entry(barcode.into_owned()).or_insert(0) += 1;
+                                        }
+                                        continue;
+                                    },
+                                }
+                            }
+                            // --cluster-map: from here on the key is the barcode's cluster.
+                            if let Some(clusters) = &cluster_map {
+                                let cluster = clusters.get(barcode.as_ref());
+                                if cluster.is_none() {
+                                    unassigned += 1;
+                                }
+                                barcode = Cow::Borrowed(cluster.map_or(UNASSIGNED_CLUSTER, String::as_str));
+                            }
+                        }
+                        match separation(&record, separate_unmapped, secondary_separate, dup_separate) {
+                            Some(Separated::Unmapped) => {
+                                separated_unmapped += 1;
+                                *unmapped_counts.entry(barcode.into_owned()).or_insert(0) += weight;
+                                continue;
+                            },
+                            Some(Separated::Secondary) => {
+                                separated_secondary += 1;
+                                *secondary_counts.entry(barcode.into_owned()).or_insert(0) += weight;
+                                continue;
+                            },
+                            Some(Separated::Duplicate) => {
+                                separated_duplicate += 1;
+                                *duplicate_counts.entry(barcode.into_owned()).or_insert(0) += weight;
+                                continue;
+                            },
+                            None => (),
+                        }
+                        if at_barcode_limit(&counts.counts, &barcode, limit_per_barcode) {
+                            over_barcode_limit += 1;
+                            continue;
+                        }
+                        // A weighted read only adds what fills the barcode, so N is never overshot.
+                        let weight = match limit_per_barcode {
+                            Some(limit) => weight.min(limit - counts.counts.get(barcode.as_ref()).copied().unwrap_or(0)),
+                            None => weight,
+                        };
+                        counts.add(&barcode, &record, umi, weight);
+                        // Only real barcodes, which with a whitelist are members of it, count
+                        // towards every listed barcode being full; a label or script key does not.
+                        if is_barcode && at_barcode_limit(&counts.counts, &barcode, limit_per_barcode) {
+                            saturated += 1;
+                        }
+                        if build_barcode_index {
+                            let offsets = barcode_offsets.entry(barcode.to_string()).or_default();
+                            offsets.push((offset_inputs.len() - 1, record_offset.get()));
+                        }
+                        if let Some((_, input_counts)) = per_input_counts.last_mut() {
+                            *input_counts.entry(barcode.into_owned()).or_insert(0) += weight;
+                        }
+                    }
+                    // With a whitelist, once every listed barcode is full nothing
+                    // more can be counted. Under --cluster-map the keys are clusters.
+                    if let (Some(_), Some(allowed), None) = (limit_per_barcode, &read_filter.whitelist, &cluster_map)
+                        && saturated >= allowed.len()
+                    {
+                        all_saturated = true;
+                        break;
+                    }
                 },
-                Err(HtslibError::BamAuxTagNotFound { .. }) => (), // Tag not found, do nothing
-                _ => (), // Other tag types or errors, do nothing
-            },
-            Err(e) => eprintln!("Error reading BAM/CRAM record: {}. Skipping.", e),
+                Err(e) if strict => return Err(format!("Error reading BAM/CRAM record: {} (--strict).", e).into()),
+                Err(e) => {
+                    warn!("Error reading BAM/CRAM record: {}. Skipping.", e);
+                    record_errors += 1;
+                    // Judge the rate only once there are enough records for it to mean something.
+                    if records_scanned >= ERROR_RATE_MIN_RECORDS
+                        && record_errors as f64 > records_scanned as f64 * max_error_rate
+                    {
+                        return Err(error_rate_exceeded(record_errors, records_scanned, max_error_rate).into());
+                    }
+                },
+            }
+        }
+        if estimate {
+            estimate_offset = input.compressed_offset();
+        }
+        if let Some((_, overlapping, duplicates)) = input.region_summary() {
+            region_overlaps = overlapping;
+            region_duplicates += duplicates;
+        }
+        if timed_out || all_saturated || max_records.is_some_and(|limit| records_scanned >= limit) {
+            break;
+        }
+        // Inputs are read strictly one after another: close this reader before
+        // opening the next so a long input list never holds more than one open.
+        drop(input);
+        let expected = if tid_keyed { Some(&header) } else { None };
+        current_input = open_next_input(&mut pending_inputs, &input_options, expected, skip_bad_inputs, &mut skipped_inputs)?;
+    }
+
+    profile.read_loop(decode_time);
+    logging::clear_context();
+
+    if bench {
+        bench::report(records_scanned, start_time.elapsed(), threads);
+        return Ok(());
+    }
+
+    if records_scanned > 0 && record_errors as f64 > records_scanned as f64 * max_error_rate {
+        return Err(error_rate_exceeded(record_errors, records_scanned, max_error_rate).into());
+    }
+
+    if tag_length_diagnostic {
+        report_tag_lengths(&tag_value_lengths, &tag_list);
+    }
+
+    // In the order the read loop applies them; only filters that are on.
+    let record_stages = [
+        (sample_fraction.is_some(), "--sample-fraction", sampled_out),
+        (
+            multimap_rate || merge_supplementary,
+            "secondary/supplementary",
+            secondary_reads + supplementary_reads,
+        ),
+        (proper_pair_only, "--proper-pair-only", dropped_not_proper_pair),
+        (min_length.is_some() || max_length.is_some(), "--min/max-length", dropped_length),
+        (template_length.is_some(), "--template-length-range", dropped_template_length),
+        (exon_index.is_some(), "--gtf", off_feature),
+        (true, "no barcode tag", untagged_dropped),
+        (barcode_length.is_some(), "--expect-barcode-length", barcode_length_mismatch),
+        (barcode_regex.is_some(), "--barcode-regex", regex_dropped),
+        (
+            weight_tag.is_some() && missing_weight == MissingWeight::Skip,
+            "--missing-weight skip",
+            weight_missing,
+        ),
+        #[cfg(feature = "script")]
+        (script.is_some(), "--script", script_skipped),
+    ];
+    let barcode_stages = [
+        (barcode_map_drop_unlisted, "--barcode-map-drop-unlisted", unlisted_dropped),
+        (read_filter.whitelist.is_some(), "--whitelist", off_whitelist),
+        (separate_unmapped, "--separate-unmapped", separated_unmapped),
+        (secondary_separate, "--secondary-separate", separated_secondary),
+        (dup_separate, "--dup-separate", separated_duplicate),
+        (limit_per_barcode.is_some(), "--limit-per-barcode", over_barcode_limit),
+    ];
+    let occurrences = tag_split.is_some().then_some(barcode_occurrences);
+    let accounting = filter_accounting(records_read, &record_stages, occurrences, &barcode_stages);
+
+    if let Some(spill) = spill {
+        let runs = spill.runs();
+        if fail_on_empty && runs == 0 && counts.counts.is_empty() {
+            error!(
+                "No barcoded reads were counted from {} ({} records read); nothing was written (--fail-on-empty).",
+                inputs_desc, records_read
+            );
+            process::exit(1);
+        }
+        let counts_path = output_path.as_deref().unwrap_or(output_format.default_path());
+        let delimiter = match output_format {
+            OutputFormat::Tsv => Some('\t'),
+            OutputFormat::Csv => Some(','),
+            _ => None,
+        };
+        let mut writer = create_output(counts_path, stream_output)?;
+        if let Some(delimiter) = delimiter {
+            write_delimited_header(&mut writer, delimiter, count_first)?;
+        }
+        let (mut unique_barcodes, mut total_barcoded_reads) = (0, 0);
+        spill.merge(counts.counts, |barcode, count| {
+            unique_barcodes += 1;
+            total_barcoded_reads += count;
+            match delimiter {
+                Some(delimiter) => write_delimited_row(&mut writer, barcode, count, delimiter, count_first),
+                None => writeln!(writer, "{:>7} {}", count, barcode),
+            }
+        })?;
+        writer.flush()?;
+        let partial_marker = write_partial_marker(counts_path, timed_out, records_read, stream_output)?;
+        info!(
+            "Finished processing. Found {} unique barcodes from a total of {} barcoded reads.",
+            unique_barcodes,
+            total_barcoded_reads
+        );
+        report_filter_accounting(records_read, &accounting);
+        if timed_out {
+            let marker = partial_marker.map_or(String::new(), |marker| format!(" (marked in '{}')", marker));
+            warn!("--max-runtime reached after {} records; results are PARTIAL{}.", records_read, marker);
+        }
+        info!(
+            "Results written to '{}', merged from {} run(s) spilled to '{}' (--external-sort).",
+            counts_path,
+            runs,
+            spill_dir.as_deref().unwrap_or_default()
+        );
+        profile.phase("write");
+        if profile.enabled() {
+            profile.report();
         }
+        return Ok(());
     }
 
     // --- Output Results (unchanged) ---
+    let BarcodeCounts {
+        counts: barcode_counts,
+        bins: bin_counts,
+        splice: splice_counts,
+        references: reference_counts,
+        umis: umi_sets,
+        umi_reads,
+        umi_lengths: umi_length_counts,
+        gc: gc_means,
+        strands: strand_counts,
+        mapq: mapq_counts,
+        coord_ranges,
+        ..
+    } = counts;
+    // --include-zero: whitelist barcodes with no reads, added as zero counts
+    // once the summaries (which describe observed barcodes only) are done.
+    let unseen: Vec<String> = match &read_filter.whitelist {
+        Some(allowed) if include_zero => {
+            allowed.iter().filter(|barcode| !barcode_counts.contains_key(*barcode)).cloned().collect()
+        },
+        _ => Vec::new(),
+    };
+    let zero_filled = include_zero.then_some(unseen.len());
     let mut sorted_barcodes: Vec<(String, usize)> = barcode_counts.into_iter().collect();
-    sorted_barcodes.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    // --estimate: scale a partial read up by how much of the file it covered.
+    let estimate_factor = if estimate {
+        let stopped_early = timed_out || max_records.is_some_and(|limit| records_scanned >= limit);
+        let file_size = std::fs::metadata(&inputs[0])?.len();
+        let factor = match estimate_offset {
+            Some(offset) if stopped_early && offset > 0 => file_size as f64 / offset as f64,
+            _ => 1.0,
+        };
+        for (_, count) in &mut sorted_barcodes {
+            *count = (*count as f64 * factor).round() as usize;
+        }
+        Some((factor, estimate_offset.unwrap_or(0), file_size, stopped_early))
+    } else {
+        None
+    };
+    // --cap-count: (barcodes at the cap, reads above it).
+    let capped = cap_count.map(|cap| {
+        let (mut barcodes, mut reads) = (0, 0);
+        for (_, count) in &mut sorted_barcodes {
+            if *count > cap {
+                barcodes += 1;
+                reads += *count - cap;
+                *count = cap;
+            }
+        }
+        (barcodes, reads)
+    });
+    sort_counts(&mut sorted_barcodes, sort_order);
     
-    let output_file = File::create("reads_per_barcode")?;
-    let mut writer = BufWriter::new(output_file);
-    let mut total_barcoded_reads = 0;
-    for (barcode, count) in &sorted_barcodes {
-        writeln!(writer, "{:>7} {}", count, barcode)?;
-        total_barcoded_reads += count;
+    debug!(
+        "Scanned {} records from {} in {:.2}s.",
+        records_read,
+        inputs_desc,
+        start_time.elapsed().as_secs_f64()
+    );
+    let total_barcoded_reads: usize = sorted_barcodes.iter().map(|(_, count)| count).sum();
+    let unique_barcodes = sorted_barcodes.len();
+    if fail_on_empty && unique_barcodes == 0 {
+        error!(
+            "No barcoded reads were counted from {} ({} records read); nothing was written (--fail-on-empty). \
+             Check the barcode tag ({}) and any filters.",
+            inputs_desc, records_read, tag_list
+        );
+        process::exit(1);
     }
-    writer.flush()?;
+    let mut values: Vec<usize> = sorted_barcodes.iter().map(|(_, count)| *count).collect();
+    values.sort_unstable();
+    let inequality = inequality(&values);
+    let distribution = sorted_barcodes
+        .iter()
+        .max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(&a.0)))
+        .map(|(top_barcode, max_count)| {
+            let (gini, top_decile_share) = inequality.unwrap_or_default();
+            format!(
+                "Reads per barcode: median {}, p90 {}, p99 {}, max {} ({}). Gini {:.3}; the top 10% of barcodes \
+                 hold {:.1}% of reads.",
+                percentile(&values, 50.0),
+                percentile(&values, 90.0),
+                percentile(&values, 99.0),
+                max_count,
+                top_barcode,
+                gini,
+                top_decile_share * 100.0
+            )
+        });
+    // Before any output cutoff, so the curve covers every barcode.
+    if let Some(path) = &knee_data_path {
+        let mut values: Vec<usize> = sorted_barcodes.iter().map(|(_, count)| *count).collect();
+        values.sort_unstable_by(|a, b| b.cmp(a));
+        match write_knee_data(&values, path, stream_output)? {
+            Some((rank, count)) => info!("Knee plot data written to '{}' (knee at rank {}, {} reads).", path, rank, count),
+            None => info!("Knee plot data written to '{}' (no knee: too few barcodes, or no drop in the curve).", path),
+        }
+    }
+    if !unseen.is_empty() {
+        sorted_barcodes.extend(unseen.into_iter().map(|barcode| (barcode, 0)));
+        sort_counts(&mut sorted_barcodes, sort_order);
+    }
+    if let Some(fraction) = cumulative_fraction {
+        let kept_reads = truncate_to_cumulative_fraction(&mut sorted_barcodes, fraction, total_barcoded_reads);
+        sort_counts(&mut sorted_barcodes, sort_order);
+        info!(
+            "Keeping the top {} of {} barcodes, covering {} of {} reads (--cumulative-fraction {}).",
+            sorted_barcodes.len(),
+            unique_barcodes,
+            kept_reads,
+            total_barcoded_reads,
+            fraction
+        );
+    }
+    if let Some((min, max)) = count_range {
+        let before = sorted_barcodes.len();
+        sorted_barcodes.retain(|(_, count)| (min..=max).contains(count));
+        info!(
+            "{} of {} barcodes have a count in [{}, {}] (--count-range); only these are written.",
+            sorted_barcodes.len(),
+            before,
+            min,
+            if max == usize::MAX { "inf".to_string() } else { max.to_string() }
+        );
+    }
+    profile.phase("sort");
+    let counts_path = match &output_path {
+        Some(path) => path.as_str(),
+        None if estimate => "reads_per_barcode_estimate",
+        None => output_format.default_path(),
+    };
+    let results_desc = match (output_format, normalize_to) {
+        (OutputFormat::Text, Some(target)) => {
+            write_normalized_counts(
+                &sorted_barcodes,
+                counts_path,
+                target,
+                total_barcoded_reads,
+                with_raw,
+                with_rank,
+                stream_output,
+            )?;
+            format!("'{}'", counts_path)
+        },
+        (OutputFormat::Text, None) if with_rank => {
+            write_ranked_counts(&sorted_barcodes, counts_path, stream_output)?;
+            format!("'{}'", counts_path)
+        },
+        _ => write_counts(&sorted_barcodes, counts_path, output_format, count_first, schema, timed_out, stream_output)?,
+    };
+    let partial_marker = write_partial_marker(counts_path, timed_out, records_read, stream_output)?;
 
-    println!(
+    info!(
         "Finished processing. Found {} unique barcodes from a total of {} barcoded reads.",
-        sorted_barcodes.len(),
+        unique_barcodes,
         total_barcoded_reads
     );
+    if let Some(line) = &distribution {
+        info!("{}", line);
+    }
+    report_filter_accounting(records_read, &accounting);
+    if let (Some(cap), Some((barcodes, reads))) = (cap_count, capped) {
+        info!(
+            "{} barcodes were capped at {} reads (--cap-count); {} reads above the cap are not in the counts.",
+            barcodes, cap, reads
+        );
+    }
+    if tag_concat.is_none() && read_filter.barcode_tags == [*b"BX"] {
+        // Linked reads: each BX barcode is one molecule (or a few, per GEM).
+        info!(
+            "Linked reads: {} molecules (distinct BX barcodes), {:.1} reads per molecule on average.",
+            unique_barcodes,
+            if unique_barcodes > 0 { total_barcoded_reads as f64 / unique_barcodes as f64 } else { 0.0 }
+        );
+    }
+    if !extra_tags.is_empty() {
+        info!(
+            "Tag {}: {} unique values, {} reads.",
+            String::from_utf8_lossy(&read_filter.barcode_tags[0]),
+            unique_barcodes,
+            total_barcoded_reads
+        );
+        for (tag, tag_counts) in extra_tags.iter().zip(&extra_counts) {
+            info!(
+                "Tag {}: {} unique values, {} reads.",
+                String::from_utf8_lossy(tag),
+                tag_counts.len(),
+                tag_counts.values().sum::<usize>()
+            );
+        }
+    }
     if let Some(limit) = max_records {
-        println!("(Scanned a maximum of {} records).", limit);
+        info!("(Scanned a maximum of {} records).", limit);
+    }
+    if let Some(budget) = max_runtime
+        && timed_out
+    {
+        warn!(
+            "--max-runtime of {:.1}s reached after {} records; results are PARTIAL{}.",
+            budget.as_secs_f64(),
+            records_read,
+            partial_marker.map_or(String::new(), |marker| format!(" (marked in '{}')", marker))
+        );
+    }
+    if let Some((factor, offset, file_size, stopped_early)) = estimate_factor {
+        if stopped_early {
+            warn!(
+                "ESTIMATE: counts were extrapolated by a factor of {:.3} from the first {} of {} compressed bytes. \
+                 This assumes the rest of the file looks like its start; in coordinate-sorted files barcodes \
+                 are not evenly spread, so per-barcode estimates can be far off. Side tables are not extrapolated.",
+                factor, offset, file_size
+            );
+        } else {
+            info!("--estimate: the whole input was read, so the counts are exact (factor 1).");
+        }
+    }
+    if !regions.is_empty() {
+        info!(
+            "Read {} regions; {} overlap another region, and {} records already counted from an earlier \
+             region were skipped.",
+            regions.len(),
+            region_overlaps,
+            region_duplicates
+        );
+    }
+    if record_errors > 0 {
+        warn!(
+            "{} of {} records ({:.3}%) could not be read and were skipped (--max-error-rate {}).",
+            record_errors,
+            records_scanned,
+            record_errors as f64 * 100.0 / records_scanned as f64,
+            max_error_rate
+        );
+    }
+    if let Some(fraction) = sample_fraction {
+        info!(
+            "Sampled a fraction {} of reads with seed {} ({} reads not sampled).",
+            fraction, seed, sampled_out
+        );
+    }
+    if let Some((min, max)) = barcode_length {
+        let expected = if min == max {
+            min.to_string()
+        } else {
+            format!("{}..={}", min, if max == usize::MAX { "inf".to_string() } else { max.to_string() })
+        };
+        let message = format!(
+            "Skipped {} of {} barcoded reads whose barcode length was not {} (--expect-barcode-length).",
+            barcode_length_mismatch, barcode_length_checked, expected
+        );
+        if barcode_length_mismatch as f64 > barcode_length_checked as f64 * BARCODE_LENGTH_WARN_FRACTION {
+            warn!("{} Check the tag choice and the barcode format.", message);
+        } else {
+            info!("{}", message);
+        }
+    }
+    if let Some(re) = &barcode_regex {
+        info!("Skipped {} reads whose barcode did not match --barcode-regex '{}'.", regex_dropped, re.as_str());
+    }
+    if tag_required && tag_missing > 0 {
+        warn!("{} reads had no usable {} tag (--tag-required); they were not counted.", tag_missing, tag_list);
+    }
+    if let Some(label) = &count_missing_as {
+        info!("{} reads had no usable {} tag; they were counted as '{}'.", tag_missing, tag_list, label);
+    }
+    if hex_encoded > 0 || byte_array_encoded > 0 {
+        info!(
+            "Barcode tag encodings: {} reads as hex (H), {} as byte arrays (B:C), the rest as strings (Z).",
+            hex_encoded, byte_array_encoded
+        );
+    }
+    if let Some(tag) = tag_summary {
+        report_tag_summary(&tag, tag_summary_values, tag_summary_reads);
+    }
+    if let Some(concat) = &tag_concat
+        && concat_incomplete > 0
+    {
+        let outcome = match &concat.placeholder {
+            Some(placeholder) => format!("counted with '{}' for the missing part", placeholder),
+            None => "not counted".to_string(),
+        };
+        info!("{} reads lacked part of the {} barcode; they were {}.", concat_incomplete, tag_list, outcome);
+    }
+    if tag_concat.is_none() && read_filter.barcode_tags.len() > 1 {
+        let contributions: Vec<String> = read_filter
+            .barcode_tags
+            .iter()
+            .zip(&tag_hits)
+            .map(|(tag, hits)| format!("{} {}", String::from_utf8_lossy(tag), hits))
+            .collect();
+        let fell_back: usize = tag_hits[1..].iter().sum();
+        info!(
+            "Barcode tag contributions: {} ({} reads fell back past {}).",
+            contributions.join(", "),
+            fell_back,
+            String::from_utf8_lossy(&read_filter.barcode_tags[0])
+        );
+    }
+    if let Some(tag) = weight_tag {
+        let policy = match missing_weight {
+            MissingWeight::CountOne => "counted as 1",
+            MissingWeight::Skip => "skipped",
+        };
+        info!(
+            "Counts are weighted by the {} tag (total weighted count {}); {} barcoded reads had no usable weight ({}).",
+            String::from_utf8_lossy(&tag),
+            total_barcoded_reads,
+            weight_missing,
+            policy
+        );
+    }
+    #[cfg(feature = "script")]
+    if let Some(path) = &script_path {
+        info!(
+            "Counting keys decided by script '{}': {} keys, {} reads left out by the script.",
+            path,
+            unique_barcodes,
+            script_skipped
+        );
+    }
+    if barcode_map.is_some() {
+        info!("Remapped {} barcode occurrences through --barcode-map.", remapped);
+        if barcode_map_drop_unlisted {
+            info!("Dropped {} barcode occurrences not listed in the map.", unlisted_dropped);
+        }
+    }
+    if let Some(clusters) = &cluster_map {
+        info!(
+            "Summed reads into {} clusters through --cluster-map; {} reads had a barcode in no cluster ('{}').",
+            unique_barcodes, unassigned, UNASSIGNED_CLUSTER
+        );
+        let mut sizes: AHashMap<&str, usize> = AHashMap::new();
+        for cluster in clusters.values() {
+            *sizes.entry(cluster.as_str()).or_insert(0) += 1;
+        }
+        let mut totals: Vec<&(String, usize)> = sorted_barcodes.iter().collect();
+        totals.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        for (cluster, reads) in totals {
+            let barcodes = sizes.get(cluster.as_str()).copied().unwrap_or(0);
+            info!("  {}: {} reads ({} barcodes in the map)", cluster, reads, barcodes);
+        }
+    }
+    if separate_unmapped {
+        info!(
+            "Unmapped: {} barcoded reads across {} barcodes were unmapped and are not in the main counts.",
+            unmapped_counts.values().sum::<usize>(),
+            unmapped_counts.len()
+        );
+    }
+    // Records after sampling, the denominator of the multimapping rate.
+    let considered = records_read - sampled_out;
+    let multimap_fraction = match considered {
+        0 => 0.0,
+        n => (secondary_reads + supplementary_reads) as f64 / n as f64,
+    };
+    if multimap_rate {
+        info!(
+            "Multimapping: {} secondary and {} supplementary of {} records ({:.2}%); only primary alignments were counted.",
+            secondary_reads,
+            supplementary_reads,
+            considered,
+            multimap_fraction * 100.0
+        );
+    }
+    if merge_supplementary && !multimap_rate {
+        info!(
+            "Skipped {} supplementary alignments; chimeric reads were counted once, by their primary record.",
+            supplementary_reads
+        );
+    }
+    if secondary_separate {
+        info!(
+            "Secondary: {} barcoded reads are in the main counts; {} secondary alignments across {} barcodes \
+             are counted separately.",
+            total_barcoded_reads,
+            secondary_counts.values().sum::<usize>(),
+            secondary_counts.len()
+        );
+    }
+    if dup_separate {
+        let duplicates: usize = duplicate_counts.values().sum();
+        if duplicates == 0 {
+            warn!("--dup-separate: no counted read has the DUPLICATE flag (0x400); was the input duplicate-marked?");
+        } else {
+            info!(
+                "Duplicates: {} barcoded reads are in the main counts; {} duplicate-flagged reads across {} barcodes \
+                 are counted separately.",
+                total_barcoded_reads,
+                duplicates,
+                duplicate_counts.len()
+            );
+        }
+    }
+    if let Some(limit) = limit_per_barcode {
+        info!(
+            "{} barcodes reached --limit-per-barcode {}; {} further reads of theirs were not counted.",
+            saturated, limit, over_barcode_limit
+        );
+        if all_saturated {
+            info!(
+                "Every whitelisted barcode was full, so reading stopped after {} records.",
+                records_scanned
+            );
+        }
+    }
+    if let Some(added) = zero_filled {
+        info!(
+            "Added {} whitelist barcodes with no reads as zero counts (--include-zero); {} barcodes were observed.",
+            added, unique_barcodes
+        );
+    }
+    if read_filter.whitelist.is_some() {
+        info!(
+            "Whitelist: {} reads on-whitelist, {} reads off-whitelist (not counted).",
+            total_barcoded_reads, off_whitelist
+        );
+    }
+    if whitelist_ci {
+        if case_folded > 0 {
+            info!(
+                "Whitelist: {} barcode occurrences matched only case-insensitively (--whitelist-ci) and were \
+                 counted in upper case.",
+                case_folded
+            );
+        } else {
+            info!("Whitelist: no matches relied on case folding (--whitelist-ci).");
+        }
+    }
+    if let Some(max_distance) = whitelist_correct {
+        info!(
+            "Whitelist correction (Hamming distance <= {}): {} reads corrected; {} ambiguous and {} without a \
+             whitelisted barcode in range were not counted.",
+            max_distance,
+            corrected,
+            correction_ambiguous,
+            off_whitelist - correction_ambiguous
+        );
+    }
+    if exon_index.is_some() {
+        let on_feature = records_read
+            - sampled_out
+            - secondary_reads
+            - supplementary_reads
+            - dropped_not_proper_pair
+            - dropped_length
+            - dropped_template_length
+            - off_feature;
+        let considered = on_feature + off_feature;
+        info!(
+            "Feature overlap: {} of {} reads ({:.2}%) overlapped a GTF exon; the rest were not counted.",
+            on_feature,
+            considered,
+            if considered > 0 { on_feature as f64 * 100.0 / considered as f64 } else { 0.0 }
+        );
+    }
+    if min_length.is_some() || max_length.is_some() {
+        info!("Skipped {} reads outside the --min-length/--max-length range.", dropped_length);
+    }
+    if let Some((min, max)) = template_length {
+        info!(
+            "Skipped {} reads with |TLEN| outside [{}, {}] (--template-length-range).",
+            dropped_template_length,
+            min,
+            if max == usize::MAX { "inf".to_string() } else { max.to_string() }
+        );
+    }
+    if proper_pair_only {
+        info!("Skipped {} reads not in a proper pair (--proper-pair-only).", dropped_not_proper_pair);
+    }
+    if !skipped_inputs.is_empty() {
+        warn!(
+            "Skipped {} of {} inputs that could not be read (--skip-bad-inputs):",
+            skipped_inputs.len(),
+            inputs.len()
+        );
+        for (path, reason) in &skipped_inputs {
+            warn!("  {}: {}", path, reason);
+        }
+    }
+    if !input_globs.is_empty() {
+        info!(
+            "Read {} of {} inputs ({} skipped).",
+            inputs_read,
+            inputs.len(),
+            skipped_inputs.len()
+        );
+    }
+    info!("Results written to {}", results_desc);
+    let counts_checksum = checksum.then(|| counts_checksum(&sorted_barcodes));
+    if let Some(digest) = &counts_checksum {
+        info!("Counts checksum: {}", digest);
+    }
+    profile.phase("write");
+    if profile.enabled() {
+        profile.report();
     }
-    println!("Results written to 'reads_per_barcode'");
 
-    Ok(())
-}
+    if let Some(path) = &stats_path {
+        let sampling = match sample_fraction {
+            Some(fraction) => format!("{{\"fraction\": {}, \"seed\": {}}}", fraction, seed),
+            None => "null".to_string(),
+        };
+        let fields = [
+            ("input", json_string(&inputs[0])),
+            ("inputs", json_array(&inputs)),
+            ("skipped_inputs", json_array(skipped_inputs.iter().map(|(path, _)| path))),
+            ("records_read", records_read.to_string()),
+            ("partial", timed_out.to_string()),
+            ("record_errors", record_errors.to_string()),
+            ("filter_accounting", filter_accounting_json(records_read, &accounting)),
+            ("tag_missing", if tag_required || count_missing_as.is_some() { tag_missing.to_string() } else { "null".to_string() }),
+            ("unique_barcodes", unique_barcodes.to_string()),
+            ("zero_filled_barcodes", zero_filled.map_or("null".to_string(), |added| added.to_string())),
+            ("total_barcoded_reads", total_barcoded_reads.to_string()),
+            ("sampling", sampling),
+            ("multimap_rate", if multimap_rate { multimap_fraction.to_string() } else { "null".to_string() }),
+            ("profile", profile.json()),
+            ("gini", inequality.map_or("null".to_string(), |(gini, _)| format!("{:.6}", gini))),
+            ("top_decile_share", inequality.map_or("null".to_string(), |(_, share)| format!("{:.6}", share))),
+            ("checksum", counts_checksum.as_deref().map_or("null".to_string(), json_string)),
+            ("estimate_factor", estimate_factor.map_or("null".to_string(), |(factor, ..)| factor.to_string())),
+        ];
+        write_stats(path, schema, &fields)?;
+        info!("Run statistics written to '{}'", path);
+    }
 
-fn print_usage(program_name: &str) {
-    eprintln!("A parallel BAM/CRAM barcode counter.");
-    eprintln!("\nUsage:");
-    eprintln!("  {} <input.bam_or_cram> [reference.fasta_if_cram] [--limit N | -n N]", program_name);
-    eprintln!("\nArguments:");
-    eprintln!("  <input.bam_or_cram>    Path to the input file.");
-    eprintln!("  [reference.fasta_if_cram]  Optional path to the reference FASTA (required for CRAM).");
-    eprintln!("\nOptions:");
-    eprintln!("  -n, --limit <N>        Process only the first N records from the file.");
-}
\ No newline at end of file
+    if write_meta {
+        let meta_path = format!("{}.meta.json", counts_path);
+        let input_sizes: Vec<String> = inputs
+            .iter()
+            .map(|path| std::fs::metadata(path).map_or("null".to_string(), |meta| meta.len().to_string()))
+            .collect();
+        let fields = [
+            ("command_line", json_array(&args)),
+            ("version", json_string(env!("CARGO_PKG_VERSION"))),
+            ("htslib_version", json_string(&htslib_version())),
+            ("inputs", json_array(&inputs)),
+            ("input_sizes", format!("[{}]", input_sizes.join(", "))),
+            ("output", json_string(counts_path)),
+            ("options", options_json.clone()),
+        ];
+        write_stats(&meta_path, schema, &fields)?;
+        info!("Run metadata written to '{}'", meta_path);
+    }
+
+    #[cfg(feature = "sqlite")]
+    if let Some(path) = &sqlite_path {
+        let opt = |v: Option<String>| v.unwrap_or_default();
+        let metadata = [
+            ("version", env!("CARGO_PKG_VERSION").to_string()),
+            ("input", inputs.join(",")),
+            ("reference", opt(ref_fasta_path_str.clone())),
+            ("limit", opt(max_records.map(|n| n.to_string()))),
+            ("proper_pair_only", proper_pair_only.to_string()),
+            ("sample_fraction", opt(sample_fraction.map(|f| f.to_string()))),
+            ("seed", seed.to_string()),
+            ("tag_split", opt(tag_split.clone())),
+            ("tag_composite", tag_composite.to_string()),
+            ("partial", timed_out.to_string()),
+            ("records_read", records_read.to_string()),
+            ("options", options_json.clone()),
+        ];
+        sqlite::write_sqlite(path, &sorted_barcodes, &metadata)?;
+        info!("Counts written to SQLite database '{}'", path);
+    }
+
+    #[cfg(feature = "hdf5")]
+    if let Some(path) = &h5_path {
+        h5::write_h5(path, &sorted_barcodes)?;
+        info!("Counts written to HDF5 file '{}' (obs_names, var_names, X)", path);
+    }
+
+    for (tag, tag_counts) in extra_tags.iter().zip(extra_counts) {
+        let tag = String::from_utf8_lossy(tag);
+        let path = tag_output_path(counts_path, &tag);
+        let mut sorted: Vec<(String, usize)> = tag_counts.into_iter().collect();
+        sort_counts(&mut sorted, sort_order);
+        let desc = write_counts(&sorted, &path, output_format, count_first, schema, timed_out, stream_output)?;
+        info!("Counts for tag {} written to {}", tag, desc);
+    }
+
+    if strand_ratio {
+        let rows = write_strand_ratio(strand_counts, "reads_per_barcode_strand")?;
+        info!("Strand ratios for {} barcodes written to 'reads_per_barcode_strand'", rows);
+    }
+
+    if let Some(boundaries) = &mapq_tiers {
+        let (rows, totals, low_dominated) = write_mapq_tiers(mapq_counts, boundaries, "reads_per_barcode_mapq")?;
+        let tiers: Vec<String> = mapq_tier_names(boundaries)
+            .iter()
+            .zip(&totals)
+            .map(|(name, reads)| format!("{}: {}", name, reads))
+            .collect();
+        info!(
+            "Reads per MAPQ tier for {} barcodes written to 'reads_per_barcode_mapq' (mapped reads at MAPQ {}); {} barcodes \
+             have most of their reads in the lowest tier.",
+            rows,
+            tiers.join(", "),
+            low_dominated
+        );
+    }
+
+    if gc_stats {
+        let rows = write_gc_stats(gc_means, "reads_per_barcode_gc")?;
+        info!("Mean GC fraction for {} barcodes written to 'reads_per_barcode_gc'", rows);
+    }
+
+    if coord_range {
+        let rows = write_coord_ranges(&coord_ranges, &header, "reads_per_barcode_coord_range")?;
+        info!("Coordinate ranges ({} barcode/reference rows) written to 'reads_per_barcode_coord_range'", rows);
+    }
+
+    if by_splice {
+        let (spliced, unspliced) = write_splice_counts(splice_counts, "reads_per_barcode_splice")?;
+        info!(
+            "Splice counts written to 'reads_per_barcode_splice' ({} spliced, {} unspliced mapped barcoded reads)",
+            spliced, unspliced
+        );
+    }
+
+    if let Some(prefix) = &split_output {
+        let barcodes_path = format!("{}.barcodes.txt", prefix);
+        let counts_path = format!("{}.counts.txt", prefix);
+        write_split_counts(&sorted_barcodes, &barcodes_path, &counts_path, false)?;
+        info!("Index-aligned barcodes and counts written to '{}' and '{}'", barcodes_path, counts_path);
+    }
+
+    if let Some(prefix) = &r_output {
+        let barcodes_path = format!("{}_barcodes.tsv", prefix);
+        let counts_path = format!("{}_counts.tsv", prefix);
+        write_split_counts(&sorted_barcodes, &barcodes_path, &counts_path, true)?;
+        info!("Barcode and count vectors for R written to '{}' and '{}'", barcodes_path, counts_path);
+    }
+
+    if per_input {
+        let rows = write_per_input_counts(per_input_counts, sort_order, "reads_per_barcode_per_input")?;
+        info!("Per-input counts ({} input/barcode rows) written to 'reads_per_barcode_per_input'", rows);
+    }
+
+    if build_barcode_index {
+        let rows = write_barcode_offsets(barcode_offsets, &offset_inputs, "reads_per_barcode_offsets")?;
+        info!("Read offsets for {} barcode/input rows written to 'reads_per_barcode_offsets'", rows);
+    }
+
+    if let Some(path) = &unmapped_output {
+        let mut sorted_unmapped: Vec<(String, usize)> = unmapped_counts.into_iter().collect();
+        sort_counts(&mut sorted_unmapped, sort_order);
+        write_text_counts(&sorted_unmapped, path, stream_output)?;
+        info!("Unmapped counts for {} barcodes written to '{}'", sorted_unmapped.len(), path);
+    }
+
+    if secondary_separate {
+        let mut sorted_secondary: Vec<(String, usize)> = secondary_counts.into_iter().collect();
+        sort_counts(&mut sorted_secondary, sort_order);
+        write_text_counts(&sorted_secondary, "reads_per_barcode_secondary", stream_output)?;
+        info!(
+            "Secondary alignment counts for {} barcodes written to 'reads_per_barcode_secondary'",
+            sorted_secondary.len()
+        );
+    }
+
+    if dup_separate {
+        let mut sorted_duplicates: Vec<(String, usize)> = duplicate_counts.into_iter().collect();
+        sort_counts(&mut sorted_duplicates, sort_order);
+        write_text_counts(&sorted_duplicates, "reads_per_barcode_duplicate", stream_output)?;
+        info!(
+            "Duplicate read counts for {} barcodes written to 'reads_per_barcode_duplicate'",
+            sorted_duplicates.len()
+        );
+    }
+
+    if let Some(path) = &off_whitelist_path {
+        let mut sorted_off: Vec<(String, usize)> = off_whitelist_counts.into_iter().collect();
+        sort_counts(&mut sorted_off, sort_order);
+        write_text_counts(&sorted_off, path, stream_output)?;
+        info!("Counts for {} off-whitelist barcodes written to '{}'", sorted_off.len(), path);
+    }
+
+    if let Some(tag) = umi_tag {
+        let (mut umi_sets, mut umi_reads) = (umi_sets, umi_reads);
+        // --min-umi-reads: a UMI seen in fewer reads is most likely a sequencing error.
+        if let Some(min) = min_umi_reads {
+            let mut dropped = 0;
+            for per_umi in umi_reads.values_mut() {
+                let before = per_umi.len();
+                per_umi.retain(|_, reads| *reads >= min);
+                dropped += before - per_umi.len();
+            }
+            info!("Dropped {} barcode/UMI pairs seen in fewer than {} reads (--min-umi-reads).", dropped, min);
+            if !top_umi {
+                umi_sets = umi_reads.drain().map(|(barcode, per_umi)| (barcode, per_umi.into_keys().collect())).collect();
+            }
+        }
+        let umi_reads = top_umi.then_some(&umi_reads);
+        let total_umis = write_umi_counts(&sorted_barcodes, &umi_sets, umi_reads, "reads_per_barcode_umi")?;
+        info!(
+            "Unique {} UMIs per barcode written to 'reads_per_barcode_umi' ({} barcode/UMI pairs; {} barcoded reads lacked the tag).",
+            String::from_utf8_lossy(&tag),
+            total_umis,
+            umi_missing
+        );
+        if umi_length_diagnostic {
+            report_umi_lengths(&umi_length_counts);
+        }
+    }
+
+    if let Some(windows) = windows {
+        let rows = windows.write("reads_per_barcode_windows")?;
+        info!("Distinct barcodes for {} record windows written to 'reads_per_barcode_windows'", rows);
+    }
+
+    if let Some(path) = &reference_totals_path {
+        write_reference_totals(&reference_totals, &header, path)?;
+        info!("Reads per reference written to '{}'", path);
+    }
+
+    if let Some(dir) = &split_by_reference {
+        let files = write_reference_split(&reference_counts, &header, dir, sort_order, stream_output)?;
+        info!(
+            "Per-reference counts for {} references written to '{}' (listed in '{}/manifest.tsv')",
+            files, dir, dir
+        );
+    }
+
+    if dominant_reference {
+        let rows = write_dominant_reference(&reference_counts, &header, "reads_per_barcode_dominant_ref")?;
+        info!("Dominant reference for {} barcodes written to 'reads_per_barcode_dominant_ref'", rows);
+    }
+
+    if reference_breadth {
+        let rows = write_reference_breadth(&reference_counts, "reads_per_barcode_reference_breadth")?;
+        info!("Reference breadth for {} barcodes written to 'reads_per_barcode_reference_breadth'", rows);
+    }
+
+    if let Some(size) = bin_size {
+        let bin_rows = write_bin_counts(bin_counts, &header, size, "reads_per_barcode_bin")?;
+        info!("Binned counts ({} rows, {} bp bins) written to 'reads_per_barcode_bin'", bin_rows, size);
+    }
+
+    Ok(())
+}
+
+/// Prints one `--preview` row: read name, flag, reference, 1-based position
+/// (`*` and 0 when unmapped) and the barcode as read from the tag, before any
+/// splitting, remapping or whitelist correction.
+fn print_preview_row(record: &bam::Record, header: &bam::HeaderView, barcode: &str) {
+    let reference = match record.tid() {
+        tid if tid >= 0 => String::from_utf8_lossy(header.tid2name(tid as u32)).into_owned(),
+        _ => "*".to_string(),
+    };
+    println!(
+        "{}\t{}\t{}\t{}\t{}",
+        String::from_utf8_lossy(record.qname()),
+        record.flags(),
+        reference,
+        record.pos() + 1,
+        barcode
+    );
+}
+
+/// `--dump-header`: prints each reference as `name<TAB>length`, then the
+/// header's `@RG` and `@PG` lines verbatim.
+fn print_header(header: &bam::HeaderView) {
+    for tid in 0..header.target_count() {
+        println!(
+            "{}\t{}",
+            String::from_utf8_lossy(header.tid2name(tid)),
+            header.target_len(tid).unwrap_or(0)
+        );
+    }
+    let text = String::from_utf8_lossy(header.as_bytes());
+    for line in text.lines().filter(|line| line.starts_with("@RG") || line.starts_with("@PG")) {
+        println!("{}", line);
+    }
+}
+
+/// Optional per-barcode breakdowns collected alongside the main counts.
+#[derive(Clone, Default)]
+struct CountModes {
+    /// `--bin-size`
+    bin_size: Option<i64>,
+    /// `--by-splice`
+    by_splice: bool,
+    /// `--dominant-reference`
+    dominant_reference: bool,
+    /// `--reference-breadth`
+    reference_breadth: bool,
+    /// `--split-by-reference`
+    per_reference: bool,
+    /// `--umi-tag`
+    umi: bool,
+    /// `--umi-length-diagnostic`
+    umi_lengths: bool,
+    /// `--top-umi`, `--min-umi-reads`: reads per UMI instead of a UMI set.
+    umi_reads: bool,
+    /// `--gc-stats`
+    gc: bool,
+    /// `--strand-ratio`
+    strand: bool,
+    /// `--mapq-tiers`: lower MAPQ bound of every tier but the first.
+    mapq_tiers: Option<Vec<u8>>,
+    /// `--coord-range`
+    coord_range: bool,
+}
+
+/// Parses a SAM aux tag name given to `flag`, exiting with the reason if it is not one.
+fn parse_tag(value: &str, flag: &str) -> [u8; 2] {
+    read_counter::filter::parse_tag(value).unwrap_or_else(|e| {
+        error!("{} value {}.", flag, e);
+        process::exit(1);
+    })
+}
+
+/// Renders tag names for messages, e.g. `CB` or `CB,CR`.
+fn tag_names(tags: &[[u8; 2]]) -> String {
+    tags.iter()
+        .map(|tag| String::from_utf8_lossy(tag).into_owned())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// `--tag-concat`: a barcode assembled from the values of several tags, each
+/// read like a barcode tag (strings as is, `H` and `B:C` bytes decoded).
+struct TagConcat {
+    tags: Vec<[u8; 2]>,
+    separator: String,
+    placeholder: Option<String>,
+}
+
+impl TagConcat {
+    /// Joins the tag values in order, with the placeholder (if any) standing
+    /// in for missing ones. Returns the barcode, or `None` if no tag is
+    /// present or one is missing without a placeholder, and whether only
+    /// some of the tags were present.
+    fn barcode(&self, record: &bam::Record) -> (Option<String>, bool) {
+        let values: Vec<Option<Cow<str>>> =
+            self.tags.iter().map(|tag| tag_text(record, tag).map(|(value, _)| value)).collect();
+        let present = values.iter().filter(|value| value.is_some()).count();
+        let partial = present > 0 && present < values.len();
+        if present == 0 || (partial && self.placeholder.is_none()) {
+            return (None, partial);
+        }
+        let parts: Vec<&str> = values
+            .iter()
+            .map(|value| value.as_deref().or(self.placeholder.as_deref()).unwrap_or_default())
+            .collect();
+        (Some(parts.join(&self.separator)), partial)
+    }
+}
+
+/// Drops a trailing `-<digits>` GEM group suffix (`AAACGT-1` becomes
+/// `AAACGT`), for `--strip-barcode-suffix`. Other values are unchanged.
+fn strip_barcode_suffix(barcode: &str) -> &str {
+    match barcode.rsplit_once('-') {
+        Some((base, suffix))
+            if !base.is_empty() && !suffix.is_empty() && suffix.bytes().all(|b| b.is_ascii_digit()) =>
+        {
+            base
+        },
+        _ => barcode,
+    }
+}
+
+/// Splits a raw tag value into the barcode key(s) to count, per `--tag-split`
+/// and `--tag-composite`. Without a delimiter this is just the tag value.
+fn barcode_keys<'a>(tag_value: &'a str, split: Option<&str>, composite: bool) -> Vec<Cow<'a, str>> {
+    match split {
+        None => vec![Cow::Borrowed(tag_value)],
+        Some(delim) => {
+            let parts = tag_value.split(delim).filter(|p| !p.is_empty());
+            if composite {
+                let parts: Vec<&str> = parts.collect();
+                if parts.is_empty() {
+                    Vec::new()
+                } else {
+                    vec![Cow::Owned(parts.join(delim))]
+                }
+            } else {
+                parts.map(Cow::Borrowed).collect()
+            }
+        },
+    }
+}
+
+/// Parses a `--count-range` value `MIN:MAX`, where an empty MIN means 0 and
+/// an empty MAX means no upper bound.
+fn parse_count_range(value: &str) -> Option<(usize, usize)> {
+    let (min, max) = value.split_once(':')?;
+    let min = if min.is_empty() { 0 } else { min.parse().ok()? };
+    let max = if max.is_empty() { usize::MAX } else { max.parse().ok()? };
+    (min <= max).then_some((min, max))
+}
+
+/// Loads a `--barcode-map` (`observed canonical`) or `--cluster-map`
+/// (`barcode cluster`): whitespace-separated pairs, one per line. Blank lines
+/// and lines starting with `#` are ignored; `columns` names the pair in errors.
+fn load_barcode_map(path: &str, columns: &str) -> Result<AHashMap<String, String>, Box<dyn std::error::Error>> {
+    let mut map = AHashMap::new();
+    for (i, line) in open_text(path)?.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        match (fields.next(), fields.next(), fields.next()) {
+            (Some(observed), Some(canonical), None) => {
+                map.insert(observed.to_string(), canonical.to_string());
+            },
+            _ => return Err(format!("line {}: expected two columns '{}'", i + 1, columns).into()),
+        }
+    }
+    Ok(map)
+}
+
+/// Per-barcode accumulators filled while scanning records.
+struct BarcodeCounts {
+    modes: CountModes,
+    counts: AHashMap<String, usize>,
+    /// Keyed on (barcode, tid, bin index).
+    bins: AHashMap<(String, i32, i64), usize>,
+    /// Mapped reads per barcode as (spliced, unspliced).
+    splice: AHashMap<String, (usize, usize)>,
+    /// Mapped reads per barcode per tid. A barcode usually touches few
+    /// references, so a small vec is leaner than a nested map.
+    references: AHashMap<String, Vec<(i32, usize)>>,
+    /// Distinct UMIs per barcode.
+    umis: AHashMap<String, AHashSet<String>>,
+    /// Reads per UMI length per barcode; a clean run has one length everywhere.
+    umi_lengths: AHashMap<String, Vec<(usize, usize)>>,
+    /// Reads per UMI per barcode, kept instead of `umis` under `--top-umi`
+    /// and `--min-umi-reads`.
+    umi_reads: AHashMap<String, AHashMap<String, usize>>,
+    /// Running mean GC fraction per barcode, with the number of reads in it.
+    gc: AHashMap<String, (f64, usize)>,
+    /// Mapped reads per barcode as (forward, reverse).
+    strands: AHashMap<String, (usize, usize)>,
+    /// Mapped reads per barcode per `--mapq-tiers` tier, lowest tier first.
+    mapq: AHashMap<String, Vec<usize>>,
+    /// Reference interval covered by each barcode's mapped reads, per tid, as
+    /// (tid, min start, max end).
+    coord_ranges: AHashMap<String, Vec<(i32, i64, i64)>>,
+}
+
+impl BarcodeCounts {
+    fn new(modes: CountModes) -> Self {
+        BarcodeCounts {
+            modes,
+            counts: AHashMap::new(),
+            bins: AHashMap::new(),
+            splice: AHashMap::new(),
+            references: AHashMap::new(),
+            umis: AHashMap::new(),
+            umi_lengths: AHashMap::new(),
+            umi_reads: AHashMap::new(),
+            gc: AHashMap::new(),
+            strands: AHashMap::new(),
+            mapq: AHashMap::new(),
+            coord_ranges: AHashMap::new(),
+        }
+    }
+
+    /// Counts one occurrence of `barcode` for `record`, with its UMI in UMI
+    /// mode. `weight` is 1 except under `--weight-tag`; it scales the read
+    /// tallies but not the UMI tables.
+    fn add(&mut self, barcode: &str, record: &bam::Record, umi: Option<&str>, weight: usize) {
+        *self.counts.entry(barcode.to_string()).or_insert(0) += weight;
+        let mapped = !record.is_unmapped() && record.tid() >= 0;
+        if let Some(size) = self.modes.bin_size
+            && mapped
+        {
+            let key = (barcode.to_string(), record.tid(), record.pos() / size);
+            *self.bins.entry(key).or_insert(0) += weight;
+        }
+        if self.modes.gc
+            && let Some(fraction) = gc_fraction(record)
+        {
+            let (mean, reads) = self.gc.entry(barcode.to_string()).or_insert((0.0, 0));
+            *reads += 1;
+            *mean += (fraction - *mean) / *reads as f64;
+        }
+        if self.modes.by_splice && !record.is_unmapped() {
+            let entry = self.splice.entry(barcode.to_string()).or_insert((0, 0));
+            if is_spliced(record) {
+                entry.0 += weight;
+            } else {
+                entry.1 += weight;
+            }
+        }
+        if self.modes.strand && mapped {
+            let entry = self.strands.entry(barcode.to_string()).or_insert((0, 0));
+            if record.is_reverse() {
+                entry.1 += weight;
+            } else {
+                entry.0 += weight;
+            }
+        }
+        if let Some(boundaries) = &self.modes.mapq_tiers
+            && mapped
+        {
+            let tier = boundaries.iter().filter(|&&bound| record.mapq() >= bound).count();
+            let per_tier = self.mapq.entry(barcode.to_string()).or_insert_with(|| vec![0; boundaries.len() + 1]);
+            per_tier[tier] += weight;
+        }
+        if (self.modes.dominant_reference || self.modes.reference_breadth || self.modes.per_reference) && mapped {
+            let per_ref = self.references.entry(barcode.to_string()).or_default();
+            match per_ref.iter_mut().find(|(tid, _)| *tid == record.tid()) {
+                Some((_, n)) => *n += weight,
+                None => per_ref.push((record.tid(), weight)),
+            }
+        }
+        if self.modes.coord_range && mapped {
+            let (start, end) = reference_span(record);
+            let per_ref = self.coord_ranges.entry(barcode.to_string()).or_default();
+            match per_ref.iter_mut().find(|(tid, ..)| *tid == record.tid()) {
+                Some((_, min_start, max_end)) => {
+                    *min_start = (*min_start).min(start);
+                    *max_end = (*max_end).max(end);
+                },
+                None => per_ref.push((record.tid(), start, end)),
+            }
+        }
+        if self.modes.umi
+            && let Some(umi) = umi
+        {
+            if self.modes.umi_reads {
+                let per_umi = self.umi_reads.entry(barcode.to_string()).or_default();
+                match per_umi.get_mut(umi) {
+                    Some(n) => *n += 1,
+                    None => {
+                        per_umi.insert(umi.to_string(), 1);
+                    },
+                }
+            } else {
+                let set = self.umis.entry(barcode.to_string()).or_default();
+                if !set.contains(umi) {
+                    set.insert(umi.to_string());
+                }
+            }
+            if self.modes.umi_lengths {
+                let per_len = self.umi_lengths.entry(barcode.to_string()).or_default();
+                match per_len.iter_mut().find(|(len, _)| *len == umi.len()) {
+                    Some((_, n)) => *n += 1,
+                    None => per_len.push((umi.len(), 1)),
+                }
+            }
+        }
+    }
+}
+
+/// What `--weight-tag` does with reads that lack a usable weight.
+#[derive(Clone, Copy, PartialEq)]
+enum MissingWeight {
+    CountOne,
+    Skip,
+}
+
+/// A non-negative integer aux value usable as a `--weight-tag` weight.
+fn aux_weight(record: &bam::Record, tag: &[u8; 2]) -> Option<usize> {
+    let value: i64 = match record.aux(tag).ok()? {
+        Aux::I8(v) => v.into(),
+        Aux::U8(v) => v.into(),
+        Aux::I16(v) => v.into(),
+        Aux::U16(v) => v.into(),
+        Aux::I32(v) => v.into(),
+        Aux::U32(v) => v.into(),
+        _ => return None,
+    };
+    usize::try_from(value).ok()
+}
+
+/// Fraction of G/C among a read's called bases (A, C, G, T and the S/W
+/// ambiguity codes), or `None` if it has none. Works on the 4-bit encoded
+/// sequence so no decoded copy is allocated, but still touches every base,
+/// which is why `--gc-stats` is opt-in.
+fn gc_fraction(record: &bam::Record) -> Option<f64> {
+    let seq = record.seq();
+    let (mut gc, mut called) = (0usize, 0usize);
+    for i in 0..seq.len() {
+        match seq.encoded_base(i) {
+            // C, G, S
+            2 | 4 | 6 => {
+                gc += 1;
+                called += 1;
+            },
+            // A, T, W
+            1 | 8 | 9 => called += 1,
+            _ => (),
+        }
+    }
+    (called > 0).then(|| gc as f64 / called as f64)
+}
+
+/// True if the alignment skips reference bases (a CIGAR `N`), i.e. spans an intron.
+fn is_spliced(record: &bam::Record) -> bool {
+    // Check the raw ops rather than `record.cigar()` to avoid allocating a CigarString per read.
+    record
+        .raw_cigar()
+        .iter()
+        .any(|op| op & htslib::BAM_CIGAR_MASK == htslib::BAM_CREF_SKIP)
+}
+
+/// Reads (after `--sample-fraction`) whose tag values `--tag-summary` tallies.
+const TAG_SUMMARY_READS: usize = 100_000;
+
+/// How many of the most common values `--tag-summary` lists.
+const TAG_SUMMARY_TOP: usize = 10;
+
+/// A tag's value as text for `--tag-summary`; `<missing>` if absent.
+fn aux_display(record: &bam::Record, tag: &[u8; 2]) -> String {
+    match record.aux(tag) {
+        Ok(Aux::Char(c)) => (c as char).to_string(),
+        Ok(Aux::I8(v)) => v.to_string(),
+        Ok(Aux::U8(v)) => v.to_string(),
+        Ok(Aux::I16(v)) => v.to_string(),
+        Ok(Aux::U16(v)) => v.to_string(),
+        Ok(Aux::I32(v)) => v.to_string(),
+        Ok(Aux::U32(v)) => v.to_string(),
+        Ok(Aux::Float(v)) => v.to_string(),
+        Ok(Aux::Double(v)) => v.to_string(),
+        Ok(Aux::String(v)) => v.to_string(),
+        Ok(Aux::HexByteArray(v)) => v.to_string(),
+        Ok(_) => "<array>".to_string(),
+        Err(_) => "<missing>".to_string(),
+    }
+}
+
+/// Prints the `--tag-summary` table: the most common values with their share
+/// of the tallied reads.
+fn report_tag_summary(tag: &[u8; 2], values: AHashMap<String, usize>, reads: usize) {
+    let mut rows: Vec<(String, usize)> = values.into_iter().collect();
+    sort_counts(&mut rows, SortOrder::CountDesc);
+    info!(
+        "Tag {} summary over the first {} reads ({} distinct values, top {} shown):",
+        String::from_utf8_lossy(tag),
+        reads,
+        rows.len(),
+        TAG_SUMMARY_TOP.min(rows.len())
+    );
+    for (value, count) in rows.iter().take(TAG_SUMMARY_TOP) {
+        info!("  {:>9} {:>6.2}%  {}", count, *count as f64 * 100.0 / reads as f64, value);
+    }
+}
+
+/// How many records to read between wall-clock checks for `--max-runtime`.
+const RUNTIME_CHECK_INTERVAL: usize = 10_000;
+
+/// Seed used for `--sample-fraction` when `--seed` is not given.
+const DEFAULT_SEED: u64 = 42;
+
+/// `--window-diagnostics`: distinct barcodes in each window of `size`
+/// consecutive records. Only the current window's barcodes are held, so
+/// memory is bounded by the window size.
+struct BarcodeWindows {
+    size: usize,
+    records: usize,
+    barcoded: usize,
+    seen: AHashSet<String>,
+    /// Finished windows as (records, barcoded reads, distinct barcodes).
+    rows: Vec<(usize, usize, usize)>,
+}
+
+impl BarcodeWindows {
+    fn new(size: usize) -> Self {
+        BarcodeWindows { size, records: 0, barcoded: 0, seen: AHashSet::new(), rows: Vec::new() }
+    }
+
+    /// Counts a record read, first closing the window if it is full.
+    fn add_record(&mut self) {
+        if self.records == self.size {
+            self.close_window();
+        }
+        self.records += 1;
+    }
+
+    /// Notes the barcode of the record last passed to `add_record`.
+    fn add_barcode(&mut self, barcode: &str) {
+        self.barcoded += 1;
+        if !self.seen.contains(barcode) {
+            self.seen.insert(barcode.to_string());
+        }
+    }
+
+    fn close_window(&mut self) {
+        self.rows.push((self.records, self.barcoded, self.seen.len()));
+        self.records = 0;
+        self.barcoded = 0;
+        self.seen.clear();
+    }
+
+    /// Writes one TSV row per window (the last may be partial) with columns
+    /// `first_record` (1-based), `records`, `barcoded_reads` and
+    /// `distinct_barcodes`. Returns the number of windows.
+    fn write(mut self, path: &str) -> Result<usize, Box<dyn std::error::Error>> {
+        if self.records > 0 {
+            self.close_window();
+        }
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "first_record\trecords\tbarcoded_reads\tdistinct_barcodes")?;
+        for (i, (records, barcoded, distinct)) in self.rows.iter().enumerate() {
+            writeln!(writer, "{}\t{}\t{}\t{}", i * self.size + 1, records, barcoded, distinct)?;
+        }
+        writer.flush()?;
+        Ok(self.rows.len())
+    }
+}
+
+/// Small deterministic PRNG (SplitMix64) backing `--sample-fraction`. Kept
+/// in-tree so sampled output stays byte-identical for a given seed regardless
+/// of external crate versions.
+struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    fn new(seed: u64) -> Self {
+        SplitMix64 { state: seed }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `[0, 1)`.
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Default `--max-error-rate`: lenient enough for the odd bad record, but
+/// stops on a file where most records fail to decode.
+const DEFAULT_MAX_ERROR_RATE: f64 = 0.5;
+
+/// Records scanned before `--max-error-rate` is checked during the run; the
+/// final check at the end applies to any number.
+const ERROR_RATE_MIN_RECORDS: usize = 1_000;
+
+fn error_rate_exceeded(errors: usize, scanned: usize, max_rate: f64) -> String {
+    format!(
+        "{} of {} records could not be read, more than --max-error-rate {}; the input looks truncated or corrupt.",
+        errors, scanned, max_rate
+    )
+}
+
+/// Where `--compat-10x` writes the counts unless `--output` or `--format` say otherwise.
+const COMPAT_10X_OUTPUT: &str = "reads_per_barcode.tsv.gz";
+
+/// The `--cluster-map` bucket for barcodes the map does not list.
+const UNASSIGNED_CLUSTER: &str = "unassigned";
+
+/// Upper bound for `--whitelist-correct`; candidates grow combinatorially
+/// with the distance, and beyond this most corrections would be ambiguous.
+const MAX_CORRECTION_DISTANCE: usize = 3;
+
+/// Share of barcoded reads failing `--expect-barcode-length` above which the
+/// summary is a warning: a few odd tags are noise, more is the wrong tag.
+const BARCODE_LENGTH_WARN_FRACTION: f64 = 0.05;
+
+/// Rows formatted per rayon task when writing text output.
+const FORMAT_CHUNK_ROWS: usize = 64 * 1024;
+
+/// Write buffer for streamed outputs, small so a downstream reader sees data
+/// soon after it is formatted.
+const STREAM_BUFFER_BYTES: usize = 4 * 1024;
+
+/// True if `path` should be written as a stream: it is a FIFO (named pipe,
+/// or a pipe from process substitution) or `stream_output` (`--stream-output`)
+/// is set.
+/// Streamed outputs get a small write buffer and are flushed after each
+/// batch of rows instead of only at the end.
+///
+/// Outputs are always written in place; nothing is staged in a temporary
+/// file and renamed, which a pipe could not support anyway.
+fn is_stream(path: &str, stream_output: bool) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        if std::fs::metadata(path).is_ok_and(|meta| meta.file_type().is_fifo()) {
+            return true;
+        }
+    }
+    stream_output
+}
+
+/// Writes counts in `format` (plain text for [`OutputFormat::Text`]) and
+/// describes where they went, for the summary. `partial` marks a JSON wrapper.
+fn write_counts(
+    sorted_barcodes: &[(String, usize)],
+    path: &str,
+    format: OutputFormat,
+    count_first: bool,
+    schema: SchemaVersion,
+    partial: bool,
+    stream_output: bool,
+) -> Result<String, Box<dyn std::error::Error>> {
+    match format {
+        OutputFormat::Text => write_text_counts(sorted_barcodes, path, stream_output)?,
+        OutputFormat::Tsv => write_delimited_counts(sorted_barcodes, path, '\t', count_first, stream_output)?,
+        OutputFormat::Csv => write_delimited_counts(sorted_barcodes, path, ',', count_first, stream_output)?,
+        OutputFormat::Json => write_json_counts(sorted_barcodes, path, schema, partial, stream_output)?,
+        OutputFormat::Bincode => {
+            #[cfg(feature = "bincode")]
+            compact::write_bincode(sorted_barcodes, path)?;
+        },
+        OutputFormat::Msgpack => {
+            #[cfg(feature = "msgpack")]
+            msgpack::write_msgpack(sorted_barcodes, path, stream_output)?;
+        },
+        OutputFormat::Npy => {
+            let index_path = format!("{}.barcodes.txt", path.strip_suffix(".npy").unwrap_or(path));
+            write_npy_counts(sorted_barcodes, path, &index_path)?;
+            return Ok(format!("'{}' (barcode index in '{}')", path, index_path));
+        },
+    }
+    Ok(format!("'{}'", path))
+}
+
+/// Where the counts of a further `--tag` go: next to `counts_path`, with the
+/// tag before its format extension (and `.gz`), e.g. `out/counts.UB.tsv.gz`,
+/// or appended when there is none (`reads_per_barcode.UB`).
+fn tag_output_path(counts_path: &str, tag: &str) -> String {
+    let (base, gz) = match counts_path.strip_suffix(".gz") {
+        Some(base) => (base, ".gz"),
+        None => (counts_path, ""),
+    };
+    let name_start = base.rfind('/').map_or(0, |slash| slash + 1);
+    match base[name_start..].rfind('.').filter(|&dot| dot > 0) {
+        Some(dot) => {
+            let (stem, extension) = base.split_at(name_start + dot);
+            format!("{}.{}{}{}", stem, tag, extension, gz)
+        },
+        None => format!("{}.{}{}", base, tag, gz),
+    }
+}
+
+/// Writes counts in the default text layout: right-aligned count, a space, the barcode.
+fn write_text_counts(
+    sorted_barcodes: &[(String, usize)],
+    path: &str,
+    stream_output: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    write_rows(sorted_barcodes, path, stream_output, |buf, _, (barcode, count)| {
+        let _ = writeln!(buf, "{:>7} {}", count, barcode);
+    })
+}
+
+/// Like [`write_text_counts`] with a 1-based rank column in front (`--with-rank`).
+fn write_ranked_counts(
+    sorted_barcodes: &[(String, usize)],
+    path: &str,
+    stream_output: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    write_rows(sorted_barcodes, path, stream_output, |buf, index, (barcode, count)| {
+        let _ = writeln!(buf, "{:>7} {:>7} {}", index + 1, count, barcode);
+    })
+}
+
+/// Writes `--normalize-to` output: each count scaled to `target` reads in
+/// total, rounded to two decimals, optionally followed by the raw count
+/// (`--with-raw`), then the barcode. `with_rank` adds a leading rank column.
+fn write_normalized_counts(
+    sorted_barcodes: &[(String, usize)],
+    path: &str,
+    target: f64,
+    total: usize,
+    with_raw: bool,
+    with_rank: bool,
+    stream_output: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let scale = if total > 0 { target / total as f64 } else { 0.0 };
+    write_rows(sorted_barcodes, path, stream_output, |buf, index, (barcode, count)| {
+        if with_rank {
+            let _ = write!(buf, "{:>7} ", index + 1);
+        }
+        let scaled = *count as f64 * scale;
+        let _ = if with_raw {
+            writeln!(buf, "{:>12.2} {:>7} {}", scaled, count, barcode)
+        } else {
+            writeln!(buf, "{:>12.2} {}", scaled, barcode)
+        };
+    })
+}
+
+/// Writes one formatted line (or more) per row; `format_row` also gets the
+/// row's index in `rows`. `stream_output` is `--stream-output` (see
+/// [`is_stream`]).
+///
+/// Formatting dominates the tail of large runs, so rows are formatted into
+/// per-chunk buffers in parallel and then written in order. Work proceeds in
+/// waves of a few chunks per thread to bound the memory held in buffers.
+fn write_rows<T, F>(
+    rows: &[T],
+    path: &str,
+    stream_output: bool,
+    format_row: F,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    T: Sync,
+    F: Fn(&mut String, usize, &T) + Sync,
+{
+    let stream = is_stream(path, stream_output);
+    let mut writer = create_output(path, stream_output)?;
+    // A stream hands each chunk on as soon as it is formatted.
+    let wave_rows = if stream { FORMAT_CHUNK_ROWS } else { FORMAT_CHUNK_ROWS * rayon::current_num_threads() * 2 };
+    for (wave_index, wave) in rows.chunks(wave_rows).enumerate() {
+        let buffers: Vec<String> = wave
+            .par_chunks(FORMAT_CHUNK_ROWS)
+            .enumerate()
+            .map(|(chunk_index, chunk)| {
+                let first = wave_index * wave_rows + chunk_index * FORMAT_CHUNK_ROWS;
+                let mut buf = String::with_capacity(chunk.len() * 32);
+                for (offset, row) in chunk.iter().enumerate() {
+                    format_row(&mut buf, first + offset, row);
+                }
+                buf
+            })
+            .collect();
+        for buf in &buffers {
+            writer.write_all(buf.as_bytes())?;
+        }
+        if stream {
+            writer.flush()?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Nearest-rank percentile `p` (0-100, where 0 gives the minimum) of
+/// ascending `sorted` values, which must not be empty.
+fn percentile(sorted: &[usize], p: f64) -> usize {
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// `--knee-data` rows per decade of rank once past the first 100 ranks.
+const KNEE_POINTS_PER_DECADE: f64 = 100.0;
+
+/// The knee of a descending count curve, as a 1-based (rank, count): the
+/// last rank before the drop, so that `count >= knee count` keeps the
+/// barcodes above it. The foot of the drop is the point furthest below the
+/// straight line joining the first and last points on log-log axes; the
+/// knee is the last rank with a higher count than the foot. `None` with
+/// fewer than three barcodes, or when no point is below that line (e.g.
+/// flat counts).
+fn knee_point(counts: &[usize]) -> Option<(usize, usize)> {
+    if counts.len() < 3 {
+        return None;
+    }
+    let point = |i: usize| ((i as f64 + 1.0).log10(), (counts[i].max(1) as f64).log10());
+    let (x0, y0) = point(0);
+    let (x1, y1) = point(counts.len() - 1);
+    let slope = (y1 - y0) / (x1 - x0);
+    let (foot, _) = (1..counts.len() - 1)
+        .map(|i| {
+            let (x, y) = point(i);
+            (i, y0 + slope * (x - x0) - y)
+        })
+        .filter(|&(_, below)| below > 0.0)
+        .max_by(|a, b| a.1.total_cmp(&b.1))?;
+    let knee = (0..foot).rev().find(|&i| counts[i] > counts[foot])?;
+    Some((knee + 1, counts[knee]))
+}
+
+/// Writes `--knee-data`: `rank<TAB>count` rows for a knee plot, from counts
+/// in descending order. The first 100 ranks are all written; past that,
+/// ranks are thinned to [`KNEE_POINTS_PER_DECADE`] log-spaced points per
+/// decade, always keeping the last rank and the knee. The knee (see
+/// [`knee_point`]) heads the file as `# knee_rank` and `# knee_count`
+/// comment lines, and is returned.
+fn write_knee_data(
+    counts: &[usize],
+    path: &str,
+    stream_output: bool,
+) -> Result<Option<(usize, usize)>, Box<dyn std::error::Error>> {
+    let knee = knee_point(counts);
+    let mut writer = create_output(path, stream_output)?;
+    if let Some((rank, count)) = knee {
+        writeln!(writer, "# knee_rank\t{}", rank)?;
+        writeln!(writer, "# knee_count\t{}", count)?;
+    }
+    writeln!(writer, "rank\tcount")?;
+    let mut last_bucket = -1.0;
+    for (i, count) in counts.iter().enumerate() {
+        let rank = i + 1;
+        let bucket = ((rank as f64).log10() * KNEE_POINTS_PER_DECADE).floor();
+        let keep = rank <= 100
+            || rank == counts.len()
+            || knee.is_some_and(|(knee_rank, _)| knee_rank == rank)
+            || bucket > last_bucket;
+        if keep {
+            last_bucket = bucket;
+            writeln!(writer, "{}\t{}", rank, count)?;
+        }
+    }
+    writer.flush()?;
+    Ok(knee)
+}
+
+/// `--cumulative-fraction`: keeps the highest-count barcodes (ties by
+/// barcode) until they cover `fraction` of `total` reads, leaving `rows` in
+/// descending count order. Returns the reads covered by the kept barcodes.
+fn truncate_to_cumulative_fraction(rows: &mut Vec<(String, usize)>, fraction: f64, total: usize) -> usize {
+    sort_counts(rows, SortOrder::CountDesc);
+    let target = fraction * total as f64;
+    let mut covered = 0;
+    let mut kept = 0;
+    while kept < rows.len() && (covered as f64) < target {
+        covered += rows[kept].1;
+        kept += 1;
+    }
+    rows.truncate(kept);
+    covered
+}
+
+/// The linked htslib's version string.
+fn htslib_version() -> String {
+    // SAFETY: hts_version returns a pointer to a static NUL-terminated string.
+    unsafe { std::ffi::CStr::from_ptr(htslib::hts_version()) }.to_string_lossy().into_owned()
+}
+
+/// Layout of the JSON outputs (`--format json`, `--stats`, `--write-meta`),
+/// chosen with `--schema-version`. A new variant is added whenever one of
+/// them changes shape, and the old ones stay available for existing parsers.
+/// Bincode output versions itself separately (see `compact::FORMAT_VERSION`).
+#[derive(Clone, Copy, PartialEq)]
+enum SchemaVersion {
+    /// The original, unversioned layouts: the counts JSON is a flat
+    /// barcode-to-count object, and no object carries a version field.
+    V1,
+    /// Every JSON object starts with `"schema_version": 2`, and the counts
+    /// move under a `counts` key next to it, after a `partial` flag.
+    V2,
+}
+
+impl SchemaVersion {
+    const CURRENT: SchemaVersion = SchemaVersion::V2;
+
+    fn from_number(number: u32) -> Option<Self> {
+        match number {
+            1 => Some(SchemaVersion::V1),
+            2 => Some(SchemaVersion::V2),
+            _ => None,
+        }
+    }
+
+    /// The `schema_version` value written, or `None` for the unversioned V1.
+    fn number(self) -> Option<u32> {
+        match self {
+            SchemaVersion::V1 => None,
+            SchemaVersion::V2 => Some(2),
+        }
+    }
+}
+
+/// Row order for per-barcode count outputs.
+#[derive(Clone, Copy, PartialEq)]
+enum SortOrder {
+    /// Lexicographic by barcode (the default).
+    Barcode,
+    /// Descending by count, ties broken by barcode so the order is deterministic.
+    CountDesc,
+}
+
+/// Inequality of the per-barcode counts, from values sorted ascending: the
+/// Gini coefficient (0 when every barcode has the same count, approaching 1
+/// when a few barcodes hold nearly all reads, as with ambient RNA or PCR
+/// jackpots) and the share of reads held by the top 10% of barcodes (at least
+/// one). `None` without reads.
+fn inequality(ascending: &[usize]) -> Option<(f64, f64)> {
+    let n = ascending.len() as f64;
+    let total: f64 = ascending.iter().map(|&count| count as f64).sum();
+    if total == 0.0 {
+        return None;
+    }
+    let weighted: f64 = ascending.iter().enumerate().map(|(i, &count)| (i + 1) as f64 * count as f64).sum();
+    let gini = 2.0 * weighted / (n * total) - (n + 1.0) / n;
+    let top = ascending.len().div_ceil(10);
+    let top_total: f64 = ascending[ascending.len() - top..].iter().map(|&count| count as f64).sum();
+    Some((gini, top_total / total))
+}
+
+/// `--checksum`: a fingerprint of the counts that ignores output format and
+/// `--sort`. FNV-1a (64-bit) over `barcode<TAB>count<LF>` for every pair in
+/// barcode order, written as `fnv1a64:<16 hex digits>`. FNV is fixed by its
+/// spec, unlike std's hasher, so the value is stable across builds; it is a
+/// cache key, not a defence against tampering.
+fn counts_checksum(counts: &[(String, usize)]) -> String {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+    let mut pairs: Vec<&(String, usize)> = counts.iter().collect();
+    pairs.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    let mut hash = OFFSET_BASIS;
+    for (barcode, count) in pairs {
+        for byte in format!("{}\t{}\n", barcode, count).bytes() {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(PRIME);
+        }
+    }
+    format!("fnv1a64:{:016x}", hash)
+}
+
+/// Checks that `dir` is a directory we can create files in, by creating and
+/// removing a probe file; permission bits alone miss read-only mounts.
+fn check_writable_dir(dir: &str) -> std::io::Result<()> {
+    if !Path::new(dir).is_dir() {
+        return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "not a directory"));
+    }
+    let probe = Path::new(dir).join(format!(".read_counter_probe_{}", process::id()));
+    File::create(&probe)?;
+    std::fs::remove_file(&probe)
+}
+
+/// Expands an `--input-glob` pattern to the BAM, CRAM and SAM files it
+/// matches, sorted so that runs over the same directory read in the same
+/// order. Other matches (indexes, say) are left out; matching nothing is an
+/// error.
+fn expand_input_glob(pattern: &str) -> Result<Vec<String>, String> {
+    let paths = glob::glob(pattern).map_err(|e| format!("Invalid --input-glob '{}': {}", pattern, e))?;
+    let mut matched = Vec::new();
+    for path in paths {
+        let path = path.map_err(|e| format!("--input-glob '{}': {}", pattern, e))?;
+        let is_alignment = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| matches!(ext, "bam" | "cram" | "sam"));
+        if is_alignment && path.is_file() {
+            matched.push(path.to_string_lossy().into_owned());
+        } else {
+            debug!("--input-glob '{}': ignoring '{}'.", pattern, path.display());
+        }
+    }
+    if matched.is_empty() {
+        return Err(format!("--input-glob '{}' matched no BAM/CRAM/SAM files.", pattern));
+    }
+    matched.sort();
+    Ok(matched)
+}
+
+fn sort_counts(rows: &mut [(String, usize)], order: SortOrder) {
+    match order {
+        SortOrder::Barcode => rows.sort_unstable_by(|a, b| a.0.cmp(&b.0)),
+        SortOrder::CountDesc => rows.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0))),
+    }
+}
+
+/// Writes `--split-output`: one barcode per line and one count per line, in
+/// the same order, so line N of each file describes the same barcode. With
+/// `column_headers` (`--r-output`), each file starts with a `barcode` or
+/// `count` header line, as `readr::read_tsv` expects.
+fn write_split_counts(
+    sorted_barcodes: &[(String, usize)],
+    barcodes_path: &str,
+    counts_path: &str,
+    column_headers: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut barcodes = BufWriter::new(File::create(barcodes_path)?);
+    let mut counts = BufWriter::new(File::create(counts_path)?);
+    if column_headers {
+        writeln!(barcodes, "barcode")?;
+        writeln!(counts, "count")?;
+    }
+    for (barcode, count) in sorted_barcodes {
+        writeln!(barcodes, "{}", barcode)?;
+        writeln!(counts, "{}", count)?;
+    }
+    barcodes.flush()?;
+    counts.flush()?;
+    Ok(())
+}
+
+/// Layout of the main per-barcode results. Every format except `Npy` is
+/// gzip-compressed when the output path ends in `.gz`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum OutputFormat {
+    /// `reads_per_barcode`: right-aligned count, a space, then the barcode.
+    Text,
+    /// `reads_per_barcode.tsv`: `barcode<TAB>count` with a header line.
+    Tsv,
+    /// `reads_per_barcode.csv`: `barcode,count` with a header line.
+    Csv,
+    /// `reads_per_barcode.json`: one object mapping barcode to count, in row order.
+    Json,
+    /// `reads_per_barcode.bincode`: versioned binary rows for Rust consumers
+    /// (see `compact.rs`). Needs the `bincode` feature.
+    Bincode,
+    /// `reads_per_barcode.msgpack`: one MessagePack map of barcode to count,
+    /// in row order, for Python's `msgpack`. Needs the `msgpack` feature.
+    Msgpack,
+    /// `reads_per_barcode.npy`: counts as a 1-D little-endian uint64 NumPy
+    /// array, with `reads_per_barcode.barcodes.txt` holding one barcode per
+    /// line in the same order (`np.load` + `np.loadtxt(..., dtype=str)`).
+    Npy,
+}
+
+impl OutputFormat {
+    /// The `--format` name, as accepted by [`OutputFormat::parse`].
+    fn name(self) -> &'static str {
+        match self {
+            OutputFormat::Text => "text",
+            OutputFormat::Tsv => "tsv",
+            OutputFormat::Csv => "csv",
+            OutputFormat::Json => "json",
+            OutputFormat::Bincode => "bincode",
+            OutputFormat::Msgpack => "msgpack",
+            OutputFormat::Npy => "npy",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "text" => Some(OutputFormat::Text),
+            "tsv" => Some(OutputFormat::Tsv),
+            "csv" => Some(OutputFormat::Csv),
+            "json" => Some(OutputFormat::Json),
+            "bincode" => Some(OutputFormat::Bincode),
+            "msgpack" => Some(OutputFormat::Msgpack),
+            "npy" => Some(OutputFormat::Npy),
+            _ => None,
+        }
+    }
+
+    /// `--format auto`: the format named by `path`'s extension, ignoring a
+    /// trailing `.gz`.
+    fn from_extension(path: &str) -> Result<Self, String> {
+        let name = Path::new(path.strip_suffix(".gz").unwrap_or(path));
+        match name.extension().and_then(|ext| ext.to_str()) {
+            Some("txt") => Ok(OutputFormat::Text),
+            Some("tsv") => Ok(OutputFormat::Tsv),
+            Some("csv") => Ok(OutputFormat::Csv),
+            Some("json") => Ok(OutputFormat::Json),
+            Some("npy") => Ok(OutputFormat::Npy),
+            Some("bincode") => Ok(OutputFormat::Bincode),
+            Some("msgpack") => Ok(OutputFormat::Msgpack),
+            Some("parquet") => Err("Parquet output is not supported; use tsv, csv, json or npy.".to_string()),
+            Some(ext) => Err(format!(
+                "Cannot infer an output format from the '.{}' extension of '{}'; pass --format.",
+                ext, path
+            )),
+            None => Err(format!("Cannot infer an output format from '{}', which has no extension; pass --format.", path)),
+        }
+    }
+
+    /// Where the counts go when `--output` is not given.
+    fn default_path(self) -> &'static str {
+        match self {
+            OutputFormat::Text => "reads_per_barcode",
+            OutputFormat::Tsv => "reads_per_barcode.tsv",
+            OutputFormat::Csv => "reads_per_barcode.csv",
+            OutputFormat::Json => "reads_per_barcode.json",
+            OutputFormat::Npy => "reads_per_barcode.npy",
+            OutputFormat::Bincode => "reads_per_barcode.bincode",
+            OutputFormat::Msgpack => "reads_per_barcode.msgpack",
+        }
+    }
+}
+
+/// Opens an output file, gzip-compressed (as BGZF, which gzip reads) if
+/// `path` ends in `.gz`. Streams (see [`is_stream`]) get a small buffer.
+fn create_output(path: &str, stream_output: bool) -> Result<Box<dyn Write>, Box<dyn std::error::Error>> {
+    let capacity = if is_stream(path, stream_output) { STREAM_BUFFER_BYTES } else { 8 * 1024 };
+    Ok(if path.ends_with(".gz") {
+        Box::new(BufWriter::with_capacity(capacity, bgzf::Writer::from_path(path)?))
+    } else {
+        Box::new(BufWriter::with_capacity(capacity, File::create(path)?))
+    })
+}
+
+/// Writes counts as `barcode<delimiter>count` rows under a header line, or
+/// `count<delimiter>barcode` with `count_first`. CSV fields are quoted when
+/// they contain the delimiter or a quote.
+fn write_delimited_counts(
+    sorted_barcodes: &[(String, usize)],
+    path: &str,
+    delimiter: char,
+    count_first: bool,
+    stream_output: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut writer = create_output(path, stream_output)?;
+    write_delimited_header(&mut writer, delimiter, count_first)?;
+    for (barcode, count) in sorted_barcodes {
+        write_delimited_row(&mut writer, barcode, *count, delimiter, count_first)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn write_delimited_header(writer: &mut dyn Write, delimiter: char, count_first: bool) -> std::io::Result<()> {
+    if count_first {
+        writeln!(writer, "count{}barcode", delimiter)
+    } else {
+        writeln!(writer, "barcode{}count", delimiter)
+    }
+}
+
+fn write_delimited_row(
+    writer: &mut dyn Write,
+    barcode: &str,
+    count: usize,
+    delimiter: char,
+    count_first: bool,
+) -> std::io::Result<()> {
+    let barcode: Cow<str> = if delimiter == ',' && (barcode.contains(',') || barcode.contains('"')) {
+        Cow::Owned(format!("\"{}\"", barcode.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(barcode)
+    };
+    if count_first {
+        writeln!(writer, "{}{}{}", count, delimiter, barcode)
+    } else {
+        writeln!(writer, "{}{}{}", barcode, delimiter, count)
+    }
+}
+
+/// Writes counts as a JSON object keyed by barcode, keeping row order; from
+/// schema version 2 it is wrapped as
+/// `{"schema_version": 2, "partial": false, "counts": {...}}`, where
+/// `partial` is true if `--max-runtime` stopped the read early.
+fn write_json_counts(
+    sorted_barcodes: &[(String, usize)],
+    path: &str,
+    schema: SchemaVersion,
+    partial: bool,
+    stream_output: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut writer = create_output(path, stream_output)?;
+    let indent = match schema.number() {
+        Some(number) => {
+            writeln!(writer, "{{")?;
+            writeln!(writer, "  \"schema_version\": {},", number)?;
+            writeln!(writer, "  \"partial\": {},", partial)?;
+            write!(writer, "  \"counts\": ")?;
+            "    "
+        },
+        None => "  ",
+    };
+    writeln!(writer, "{{")?;
+    for (i, (barcode, count)) in sorted_barcodes.iter().enumerate() {
+        let sep = if i + 1 < sorted_barcodes.len() { "," } else { "" };
+        writeln!(writer, "{}{}: {}{}", indent, json_string(barcode), count, sep)?;
+    }
+    if schema.number().is_some() {
+        writeln!(writer, "  }}")?;
+    }
+    writeln!(writer, "}}")?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Marks the counts at `counts_path` as partial (`--max-runtime` ran out)
+/// with a `<counts_path>.partial` file next to them, so that a truncated run
+/// can't be mistaken for a complete one whatever the format. A complete run
+/// removes a marker left at the same path by an earlier partial one. Streams
+/// (see [`is_stream`]) get no marker.
+fn write_partial_marker(
+    counts_path: &str,
+    partial: bool,
+    records_read: usize,
+    stream_output: bool,
+) -> std::io::Result<Option<String>> {
+    if is_stream(counts_path, stream_output) {
+        return Ok(None);
+    }
+    let marker = format!("{}.partial", counts_path);
+    if partial {
+        std::fs::write(
+            &marker,
+            format!("--max-runtime reached after {} records; the counts in '{}' are incomplete.\n", records_read, counts_path),
+        )?;
+        return Ok(Some(marker));
+    }
+    match std::fs::remove_file(&marker) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(None),
+    }
+}
+
+/// Writes counts as an NPY v1.0 file plus an index-aligned barcode list.
+fn write_npy_counts(
+    sorted_barcodes: &[(String, usize)],
+    npy_path: &str,
+    index_path: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut header = format!(
+        "{{'descr': '<u8', 'fortran_order': False, 'shape': ({},), }}",
+        sorted_barcodes.len()
+    );
+    // Magic (6) + version (2) + header length (2) + header must be a multiple of 64, ending in '\n'.
+    let unpadded = 10 + header.len() + 1;
+    header.push_str(&" ".repeat((64 - unpadded % 64) % 64));
+    header.push('\n');
+
+    let mut writer = BufWriter::new(File::create(npy_path)?);
+    writer.write_all(b"\x93NUMPY\x01\x00")?;
+    writer.write_all(&(header.len() as u16).to_le_bytes())?;
+    writer.write_all(header.as_bytes())?;
+    for (_, count) in sorted_barcodes {
+        writer.write_all(&(*count as u64).to_le_bytes())?;
+    }
+    writer.flush()?;
+
+    let mut writer = BufWriter::new(File::create(index_path)?);
+    for (barcode, _) in sorted_barcodes {
+        writeln!(writer, "{}", barcode)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes `--stats` as a flat JSON object. Values must already be rendered
+/// JSON (see `json_string` for strings). From schema version 2 the object
+/// starts with a `schema_version` field.
+fn write_stats(path: &str, schema: SchemaVersion, fields: &[(&str, String)]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "{{")?;
+    if let Some(number) = schema.number() {
+        let sep = if fields.is_empty() { "" } else { "," };
+        writeln!(writer, "  \"schema_version\": {}{}", number, sep)?;
+    }
+    for (i, (key, value)) in fields.iter().enumerate() {
+        let sep = if i + 1 < fields.len() { "," } else { "" };
+        writeln!(writer, "  {}: {}{}", json_string(key), value, sep)?;
+    }
+    writeln!(writer, "}}")?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// A row of the filter accounting.
+struct FilterStage {
+    /// The filter, named by its flag where it has one; `barcodes` for the
+    /// switch from reads to barcodes under `--tag-split`.
+    filter: &'static str,
+    dropped: usize,
+    /// Reads left after this stage (barcodes, from `barcodes` on).
+    remaining: usize,
+}
+
+/// Chains the active filters' drop counts, in loop order, into what is left
+/// after each, starting from `records`. The `record_stages` drop reads;
+/// the `barcode_stages` drop barcodes, which differ from reads only under
+/// `--tag-split`, when `occurrences` gives the number of barcodes taken
+/// from the tags. Each stage is `(active, filter, dropped)`. The last
+/// `remaining` is what was counted.
+fn filter_accounting(
+    records: usize,
+    record_stages: &[(bool, &'static str, usize)],
+    occurrences: Option<usize>,
+    barcode_stages: &[(bool, &'static str, usize)],
+) -> Vec<FilterStage> {
+    let mut stages = Vec::new();
+    let mut remaining = records;
+    // A stage dropping more than reached it is a miscount in the read loop.
+    for &(_, filter, dropped) in record_stages.iter().filter(|stage| stage.0) {
+        debug_assert!(dropped <= remaining, "{} dropped {} of {}", filter, dropped, remaining);
+        remaining -= dropped;
+        stages.push(FilterStage { filter, dropped, remaining });
+    }
+    if let Some(occurrences) = occurrences {
+        remaining = occurrences;
+        stages.push(FilterStage { filter: "barcodes", dropped: 0, remaining });
+    }
+    for &(_, filter, dropped) in barcode_stages.iter().filter(|stage| stage.0) {
+        debug_assert!(dropped <= remaining, "{} dropped {} of {}", filter, dropped, remaining);
+        remaining -= dropped;
+        stages.push(FilterStage { filter, dropped, remaining });
+    }
+    stages
+}
+
+/// Prints the filter accounting as a table: records read, what each filter
+/// dropped and what was left, then what was counted.
+fn report_filter_accounting(records: usize, stages: &[FilterStage]) {
+    let counted = stages.last().map_or(records, |stage| stage.remaining);
+    info!("Filter accounting:");
+    info!("  {:<28} {:>12} {:>12}", "records read", "", records);
+    for stage in stages {
+        let dropped = if stage.filter == "barcodes" { String::new() } else { format!("-{}", stage.dropped) };
+        info!("  {:<28} {:>12} {:>12}", stage.filter, dropped, stage.remaining);
+    }
+    info!("  {:<28} {:>12} {:>12}", "counted", "", counted);
+}
+
+/// The filter accounting for `--stats`: `records`, then `stages` with
+/// `filter`, `dropped` and `remaining` each, then `counted`.
+fn filter_accounting_json(records: usize, stages: &[FilterStage]) -> String {
+    let counted = stages.last().map_or(records, |stage| stage.remaining);
+    let stages: Vec<String> = stages
+        .iter()
+        .map(|stage| {
+            format!(
+                "{{\"filter\": {}, \"dropped\": {}, \"remaining\": {}}}",
+                json_string(stage.filter),
+                stage.dropped,
+                stage.remaining
+            )
+        })
+        .collect();
+    format!("{{\"records\": {}, \"stages\": [{}], \"counted\": {}}}", records, stages.join(", "), counted)
+}
+
+/// JSON array of strings.
+fn json_array<I: IntoIterator<Item = S>, S: AsRef<str>>(items: I) -> String {
+    let items: Vec<String> = items.into_iter().map(|item| json_string(item.as_ref())).collect();
+    format!("[{}]", items.join(", "))
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Returns the value following a flag, exiting with an error if it is missing.
+fn flag_value<'a>(value: Option<&'a String>, flag: &str) -> &'a str {
+    match value {
+        Some(v) => v,
+        None => {
+            error!("{} flag requires a value.", flag);
+            process::exit(1);
+        }
+    }
+}
+
+/// Parses the value following a flag, exiting with an error if it is missing or malformed.
+fn parse_flag<T: std::str::FromStr>(value: Option<&String>, flag: &str, expected: &str) -> T {
+    let val_str = flag_value(value, flag);
+    match val_str.parse::<T>() {
+        Ok(v) => v,
+        Err(_) => {
+            error!("{} value '{}' is not a valid {}.", flag, val_str, expected);
+            process::exit(1);
+        }
+    }
+}
+
+/// Writes the UMI-mode table as a TSV with columns `barcode`, `reads`, `umis`
+/// (distinct UMI values), in the same order as the main output. With
+/// `umi_reads` (`--top-umi`), two more columns give the barcode's most read
+/// UMI and its read count (ties go to the smaller UMI; empty if none), which
+/// makes PCR jackpots stand out. Returns the total number of distinct
+/// barcode/UMI pairs.
+fn write_umi_counts(
+    sorted_barcodes: &[(String, usize)],
+    umi_sets: &AHashMap<String, AHashSet<String>>,
+    umi_reads: Option<&AHashMap<String, AHashMap<String, usize>>>,
+    path: &str,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    match umi_reads {
+        Some(_) => writeln!(writer, "barcode\treads\tumis\ttop_umi\ttop_umi_reads")?,
+        None => writeln!(writer, "barcode\treads\tumis")?,
+    }
+    let mut total = 0;
+    for (barcode, reads) in sorted_barcodes {
+        let umis = match umi_reads {
+            Some(umi_reads) => {
+                let per_umi = umi_reads.get(barcode);
+                let top = per_umi
+                    .and_then(|per_umi| per_umi.iter().max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(a.0))));
+                let (top_umi, top_reads) = top.map_or(("", 0), |(umi, n)| (umi.as_str(), *n));
+                let umis = per_umi.map_or(0, |per_umi| per_umi.len());
+                writeln!(writer, "{}\t{}\t{}\t{}\t{}", barcode, reads, umis, top_umi, top_reads)?;
+                umis
+            },
+            None => {
+                let umis = umi_sets.get(barcode).map_or(0, |set| set.len());
+                writeln!(writer, "{}\t{}\t{}", barcode, reads, umis)?;
+                umis
+            },
+        };
+        total += umis;
+    }
+    writer.flush()?;
+    Ok(total)
+}
+
+/// Reports the `--umi-length-diagnostic` summary: the overall UMI length
+/// histogram as info, then a warning listing the barcodes whose UMIs are not
+/// all the same length.
+fn report_umi_lengths(umi_lengths: &AHashMap<String, Vec<(usize, usize)>>) {
+    const MAX_LISTED: usize = 20;
+
+    let mut overall: AHashMap<usize, usize> = AHashMap::new();
+    let mut mixed: Vec<(&String, Vec<(usize, usize)>)> = Vec::new();
+    for (barcode, per_len) in umi_lengths {
+        for &(len, n) in per_len {
+            *overall.entry(len).or_insert(0) += n;
+        }
+        if per_len.len() > 1 {
+            let mut hist = per_len.clone();
+            hist.sort_unstable();
+            mixed.push((barcode, hist));
+        }
+    }
+    let mut overall: Vec<(usize, usize)> = overall.into_iter().collect();
+    overall.sort_unstable();
+
+    info!("UMI length histogram (length: reads):");
+    for (len, n) in &overall {
+        info!("  {:>4}: {}", len, n);
+    }
+    if mixed.is_empty() {
+        info!("All barcodes have UMIs of a single length.");
+        return;
+    }
+    mixed.sort_unstable_by(|a, b| a.0.cmp(b.0));
+    // The listing is the warning's detail, so it is one message.
+    let mut message = format!(
+        "{} barcodes have UMIs of mixed lengths; this usually means the UMI tag is parsed wrongly \
+         (barcode, length:reads):",
+        mixed.len()
+    );
+    for (barcode, hist) in mixed.iter().take(MAX_LISTED) {
+        let hist: Vec<String> = hist.iter().map(|(len, n)| format!("{}:{}", len, n)).collect();
+        let _ = write!(message, "\n  {}  {}", barcode, hist.join(" "));
+    }
+    if mixed.len() > MAX_LISTED {
+        let _ = write!(message, "\n  ... and {} more.", mixed.len() - MAX_LISTED);
+    }
+    warn!("{}", message);
+}
+
+/// Reports the `--count-by-tag-value-length` histogram as info, warning when
+/// there is more than one length. One barcode scheme gives one length;
+/// several usually mean the wrong tag, or values with and without a suffix
+/// or separator mixed together.
+fn report_tag_lengths(lengths: &AHashMap<usize, usize>, tag_list: &str) {
+    let mut lengths: Vec<(usize, usize)> = lengths.iter().map(|(&len, &n)| (len, n)).collect();
+    lengths.sort_unstable();
+    info!("Barcode tag {} value length histogram (length: reads):", tag_list);
+    for (len, n) in &lengths {
+        info!("  {:>4}: {}", len, n);
+    }
+    match lengths.len() {
+        0 => info!("No {} values were seen.", tag_list),
+        1 => info!("All {} values have a single length.", tag_list),
+        n => warn!(
+            "{} values come in {} different lengths; a single barcode scheme has one. Check the tag choice \
+             and the barcode format before trusting the counts.",
+            tag_list, n
+        ),
+    }
+}
+
+/// Writes the `--by-splice` table as a TSV with columns `barcode`, `spliced`,
+/// `unspliced`, sorted by barcode. Returns the (spliced, unspliced) totals.
+fn write_splice_counts(
+    splice_counts: AHashMap<String, (usize, usize)>,
+    path: &str,
+) -> Result<(usize, usize), Box<dyn std::error::Error>> {
+    let mut rows: Vec<(String, (usize, usize))> = splice_counts.into_iter().collect();
+    rows.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "barcode\tspliced\tunspliced")?;
+    let (mut total_spliced, mut total_unspliced) = (0, 0);
+    for (barcode, (spliced, unspliced)) in &rows {
+        writeln!(writer, "{}\t{}\t{}", barcode, spliced, unspliced)?;
+        total_spliced += spliced;
+        total_unspliced += unspliced;
+    }
+    writer.flush()?;
+    Ok((total_spliced, total_unspliced))
+}
+
+/// Writes the `--gc-stats` table as a TSV with columns `barcode`, `reads`
+/// (reads with at least one called base) and `mean_gc`, sorted by barcode.
+fn write_gc_stats(gc_means: AHashMap<String, (f64, usize)>, path: &str) -> Result<usize, Box<dyn std::error::Error>> {
+    let mut rows: Vec<(String, (f64, usize))> = gc_means.into_iter().collect();
+    rows.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "barcode\treads\tmean_gc")?;
+    for (barcode, (mean, reads)) in &rows {
+        writeln!(writer, "{}\t{}\t{:.4}", barcode, reads, mean)?;
+    }
+    writer.flush()?;
+    Ok(rows.len())
+}
+
+/// Writes the `--per-input` table as a TSV with columns `input`, `barcode`,
+/// `count`: what each input contributed to the merged counts, before
+/// `--cap-count` and normalization. Inputs are in reading order, and rows
+/// within an input follow `--sort`.
+fn write_per_input_counts(
+    per_input_counts: Vec<(String, AHashMap<String, usize>)>,
+    sort_order: SortOrder,
+    path: &str,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "input\tbarcode\tcount")?;
+    let mut rows = 0;
+    for (input, input_counts) in per_input_counts {
+        let mut sorted: Vec<(String, usize)> = input_counts.into_iter().collect();
+        sort_counts(&mut sorted, sort_order);
+        for (barcode, count) in &sorted {
+            writeln!(writer, "{}\t{}\t{}", input, barcode, count)?;
+        }
+        rows += sorted.len();
+    }
+    writer.flush()?;
+    Ok(rows)
+}
+
+/// Writes the `--build-barcode-index` table as a TSV with columns `barcode`,
+/// `input` and `virtual_offsets`: the BGZF virtual offsets of the barcode's
+/// counted records in that input, comma-separated in reading order. Seeking
+/// to one (htslib `bgzf_seek`, pysam `AlignmentFile.seek`) and reading a
+/// record gets that read back without a rescan. Costs 16 bytes of memory per
+/// counted read while counting.
+fn write_barcode_offsets(
+    barcode_offsets: AHashMap<String, Vec<(usize, i64)>>,
+    inputs: &[String],
+    path: &str,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let mut sorted: Vec<(String, Vec<(usize, i64)>)> = barcode_offsets.into_iter().collect();
+    sorted.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "barcode\tinput\tvirtual_offsets")?;
+    let mut rows = 0;
+    for (barcode, mut offsets) in sorted {
+        // Stable, so offsets stay in reading order within an input.
+        offsets.sort_by_key(|&(input, _)| input);
+        for group in offsets.chunk_by(|a, b| a.0 == b.0) {
+            let joined: Vec<String> = group.iter().map(|(_, offset)| offset.to_string()).collect();
+            writeln!(writer, "{}\t{}\t{}", barcode, inputs[group[0].0], joined.join(","))?;
+            rows += 1;
+        }
+    }
+    writer.flush()?;
+    Ok(rows)
+}
+
+/// Writes the `--strand-ratio` table as a TSV with columns `barcode`,
+/// `reads` (mapped) and `forward_fraction`, the share of those reads on the
+/// forward strand: about 0.5 for unstranded protocols, near 0 or 1 for
+/// stranded ones, and anything far from the run's typical value is suspect.
+fn write_strand_ratio(
+    strand_counts: AHashMap<String, (usize, usize)>,
+    path: &str,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    // Reads of weight 0 (--weight-tag) leave nothing to take a fraction of.
+    let mut rows: Vec<(String, (usize, usize))> =
+        strand_counts.into_iter().filter(|(_, (forward, reverse))| forward + reverse > 0).collect();
+    rows.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "barcode\treads\tforward_fraction")?;
+    for (barcode, (forward, reverse)) in &rows {
+        let reads = forward + reverse;
+        writeln!(writer, "{}\t{}\t{:.4}", barcode, reads, *forward as f64 / reads as f64)?;
+    }
+    writer.flush()?;
+    Ok(rows.len())
+}
+
+/// Side table a read goes to instead of the main counts.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Separated {
+    Unmapped,
+    Secondary,
+    Duplicate,
+}
+
+/// Where `--separate-unmapped`, `--secondary-separate` and `--dup-separate`
+/// (whichever are on, checked in that order) send `record`; `None` if it
+/// stays in the main counts.
+fn separation(record: &bam::Record, unmapped: bool, secondary: bool, duplicate: bool) -> Option<Separated> {
+    if unmapped && record.is_unmapped() {
+        Some(Separated::Unmapped)
+    } else if secondary && record.is_secondary() {
+        Some(Separated::Secondary)
+    } else if duplicate && record.is_duplicate() {
+        Some(Separated::Duplicate)
+    } else {
+        None
+    }
+}
+
+/// `--limit-per-barcode`: whether `barcode` already has `limit` reads.
+fn at_barcode_limit(counts: &AHashMap<String, usize>, barcode: &str, limit: Option<usize>) -> bool {
+    limit.is_some_and(|limit| counts.get(barcode).is_some_and(|&count| count >= limit))
+}
+
+/// Parses `--mapq-tiers`: comma-separated, strictly increasing MAPQ values
+/// from 1 to 255, each the lower bound of a tier after the first.
+fn parse_mapq_tiers(value: &str) -> Option<Vec<u8>> {
+    let boundaries: Vec<u8> = value.split(',').map(|bound| bound.trim().parse().ok()).collect::<Option<_>>()?;
+    let increasing = boundaries.windows(2).all(|pair| pair[0] < pair[1]);
+    (!boundaries.is_empty() && boundaries[0] >= 1 && increasing).then_some(boundaries)
+}
+
+/// Names of the `--mapq-tiers` tiers, e.g. `0`, `1-29` and `30+` for `1,30`.
+fn mapq_tier_names(boundaries: &[u8]) -> Vec<String> {
+    let mut names = Vec::with_capacity(boundaries.len() + 1);
+    let mut low = 0;
+    for &bound in boundaries {
+        let high = bound - 1;
+        names.push(if low == high { low.to_string() } else { format!("{}-{}", low, high) });
+        low = bound;
+    }
+    names.push(format!("{}+", low));
+    names
+}
+
+/// Writes the `--mapq-tiers` table as a TSV with columns `barcode` and one
+/// `mapq_<tier>` column of mapped reads per tier, sorted by barcode. MAPQ 255
+/// (unavailable) lands in the top tier. Returns the rows, the reads per tier
+/// and how many barcodes have more than half their reads in the lowest tier
+/// (with `1,30`, MAPQ 0: mostly multimappers).
+fn write_mapq_tiers(
+    mapq_counts: AHashMap<String, Vec<usize>>,
+    boundaries: &[u8],
+    path: &str,
+) -> Result<(usize, Vec<usize>, usize), Box<dyn std::error::Error>> {
+    let mut rows: Vec<(String, Vec<usize>)> = mapq_counts.into_iter().collect();
+    rows.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    let columns: Vec<String> = mapq_tier_names(boundaries).iter().map(|name| format!("mapq_{}", name)).collect();
+    writeln!(writer, "barcode\t{}", columns.join("\t"))?;
+    let mut totals = vec![0; boundaries.len() + 1];
+    let mut low_dominated = 0;
+    for (barcode, per_tier) in &rows {
+        let cells: Vec<String> = per_tier.iter().map(|n| n.to_string()).collect();
+        writeln!(writer, "{}\t{}", barcode, cells.join("\t"))?;
+        for (total, n) in totals.iter_mut().zip(per_tier) {
+            *total += n;
+        }
+        if per_tier[0] * 2 > per_tier.iter().sum::<usize>() {
+            low_dominated += 1;
+        }
+    }
+    writer.flush()?;
+    Ok((rows.len(), totals, low_dominated))
+}
+
+/// Writes the `--reference-totals` table as a TSV with columns `reference`,
+/// `reads` (after `--sample-fraction`) and `barcoded_reads` (those that also
+/// passed the read filters and had a usable barcode tag; the whitelist and
+/// barcode map are not applied), in header order. References without reads
+/// are left out; unmapped reads are always the last row, named `*`.
+fn write_reference_totals(
+    totals: &[(usize, usize)],
+    header: &bam::HeaderView,
+    path: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "reference\treads\tbarcoded_reads")?;
+    let (unmapped, per_tid) = totals.split_last().ok_or("no reference totals were collected")?;
+    for (tid, (reads, barcoded)) in per_tid.iter().enumerate() {
+        if *reads > 0 {
+            let name = String::from_utf8_lossy(header.tid2name(tid as u32));
+            writeln!(writer, "{}\t{}\t{}", name, reads, barcoded)?;
+        }
+    }
+    writeln!(writer, "*\t{}\t{}", unmapped.0, unmapped.1)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes the `--coord-range` table as a TSV with columns `barcode`,
+/// `reference`, `start`, `end`, `span`: for each barcode and each reference
+/// it has mapped reads on, the lowest read start and highest read end
+/// (0-based, half-open) and their distance. Rows are sorted by barcode, then
+/// in header order.
+fn write_coord_ranges(
+    coord_ranges: &AHashMap<String, Vec<(i32, i64, i64)>>,
+    header: &bam::HeaderView,
+    path: &str,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let mut rows: Vec<(&String, i32, i64, i64)> = coord_ranges
+        .iter()
+        .flat_map(|(barcode, per_ref)| per_ref.iter().map(move |&(tid, start, end)| (barcode, tid, start, end)))
+        .collect();
+    rows.sort_unstable_by(|a, b| a.0.cmp(b.0).then(a.1.cmp(&b.1)));
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "barcode\treference\tstart\tend\tspan")?;
+    for (barcode, tid, start, end) in &rows {
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}\t{}",
+            barcode,
+            String::from_utf8_lossy(header.tid2name(*tid as u32)),
+            start,
+            end,
+            end - start
+        )?;
+    }
+    writer.flush()?;
+    Ok(rows.len())
+}
+
+/// Writes the `--dominant-reference` table as a TSV with columns `barcode`,
+/// `reference`, `reads`, `fraction`: for each barcode, the reference holding
+/// most of its mapped reads, that read count, and its share of the barcode's
+/// mapped reads. Ties go to the reference listed first in the header.
+fn write_dominant_reference(
+    reference_counts: &AHashMap<String, Vec<(i32, usize)>>,
+    header: &bam::HeaderView,
+    path: &str,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let mut rows: Vec<(&String, &Vec<(i32, usize)>)> = reference_counts.iter().collect();
+    rows.sort_unstable_by(|a, b| a.0.cmp(b.0));
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "barcode\treference\treads\tfraction")?;
+    for (barcode, per_ref) in &rows {
+        let total: usize = per_ref.iter().map(|(_, n)| n).sum();
+        let Some(&(tid, reads)) = per_ref
+            .iter()
+            .max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(&a.0)))
+        else {
+            continue;
+        };
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{:.4}",
+            barcode,
+            String::from_utf8_lossy(header.tid2name(tid as u32)),
+            reads,
+            reads as f64 / total as f64
+        )?;
+    }
+    writer.flush()?;
+    Ok(rows.len())
+}
+
+/// Writes the `--reference-breadth` table as a TSV with columns `barcode`,
+/// `references`, `reads`: the number of distinct references each barcode has
+/// mapped reads on, and those mapped reads. Rows are sorted by barcode.
+fn write_reference_breadth(
+    reference_counts: &AHashMap<String, Vec<(i32, usize)>>,
+    path: &str,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let mut rows: Vec<(&String, &Vec<(i32, usize)>)> = reference_counts.iter().collect();
+    rows.sort_unstable_by(|a, b| a.0.cmp(b.0));
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "barcode\treferences\treads")?;
+    for (barcode, per_ref) in &rows {
+        let reads: usize = per_ref.iter().map(|(_, n)| n).sum();
+        writeln!(writer, "{}\t{}\t{}", barcode, per_ref.len(), reads)?;
+    }
+    writer.flush()?;
+    Ok(rows.len())
+}
+
+/// Writes `--split-by-reference` output: for each reference with mapped
+/// barcoded reads, `<dir>/<reference>.txt` in the main text layout (with
+/// characters unsafe in file names replaced by `_`), plus `<dir>/manifest.tsv`
+/// with columns `reference`, `file`, `barcodes`, `reads`, in header order.
+/// Returns the number of count files written.
+fn write_reference_split(
+    reference_counts: &AHashMap<String, Vec<(i32, usize)>>,
+    header: &bam::HeaderView,
+    dir: &str,
+    sort_order: SortOrder,
+    stream_output: bool,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let mut per_tid: Vec<Vec<(String, usize)>> = vec![Vec::new(); header.target_count() as usize];
+    for (barcode, per_ref) in reference_counts {
+        for &(tid, reads) in per_ref {
+            per_tid[tid as usize].push((barcode.clone(), reads));
+        }
+    }
+
+    std::fs::create_dir_all(dir)?;
+    let mut manifest = BufWriter::new(File::create(Path::new(dir).join("manifest.tsv"))?);
+    writeln!(manifest, "reference\tfile\tbarcodes\treads")?;
+    let mut files = 0;
+    for (tid, rows) in per_tid.iter_mut().enumerate() {
+        if rows.is_empty() {
+            continue;
+        }
+        let name = String::from_utf8_lossy(header.tid2name(tid as u32)).into_owned();
+        let file_name: String = name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || "._-".contains(c) { c } else { '_' })
+            .collect();
+        let file_name = format!("{}.txt", file_name);
+        sort_counts(rows, sort_order);
+        let path = Path::new(dir).join(&file_name);
+        write_text_counts(rows, &path.to_string_lossy(), stream_output)?;
+        let reads: usize = rows.iter().map(|(_, count)| count).sum();
+        writeln!(manifest, "{}\t{}\t{}\t{}", name, file_name, rows.len(), reads)?;
+        files += 1;
+    }
+    manifest.flush()?;
+    Ok(files)
+}
+
+/// Writes the `--bin-size` table as tab-separated long format, one row per
+/// non-empty (barcode, reference, bin):
+///
+/// ```text
+/// barcode  reference  bin_start  bin_end  count
+/// ```
+///
+/// `bin_start` is 0-based inclusive and `bin_end` exclusive (clamped to the
+/// reference length), so a row covers `[bin_start, bin_end)`. Reads are
+/// assigned by their leftmost aligned position. Rows are sorted by barcode,
+/// then by reference order in the header, then by bin.
+fn write_bin_counts(
+    bin_counts: AHashMap<(String, i32, i64), usize>,
+    header: &bam::HeaderView,
+    bin_size: i64,
+    path: &str,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let mut rows: Vec<((String, i32, i64), usize)> = bin_counts.into_iter().collect();
+    rows.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "barcode\treference\tbin_start\tbin_end\tcount")?;
+    for ((barcode, tid, bin), count) in &rows {
+        let tid = *tid as u32;
+        let bin_start = bin * bin_size;
+        let mut bin_end = bin_start + bin_size;
+        if let Some(len) = header.target_len(tid) {
+            bin_end = bin_end.min(len as i64);
+        }
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}\t{}",
+            barcode,
+            String::from_utf8_lossy(header.tid2name(tid)),
+            bin_start,
+            bin_end,
+            count
+        )?;
+    }
+    writer.flush()?;
+    Ok(rows.len())
+}
+
+fn print_usage(program_name: &str) {
+    eprintln!("A parallel BAM/CRAM barcode counter.");
+    eprintln!("\nUsage:");
+    eprintln!("  {} <input.bam_or_cram> [reference.fasta_if_cram] [OPTIONS]", program_name);
+    eprintln!("  {} --selftest", program_name);
+    eprintln!("\nArguments:");
+    eprintln!("  <input.bam_or_cram>    Path to the input file, or an http(s):// or ftp:// URL (s3:// and gs://");
+    eprintln!("                         with the 's3'/'gcs' features; credentials are taken from the usual");
+    eprintln!("                         AWS_*/GCS_OAUTH_TOKEN environment variables or ~/.aws/credentials).");
+    eprintln!("  [reference.fasta_if_cram]  Optional path to the reference FASTA (required for CRAM).");
+    eprintln!("\nOptions:");
+    eprintln!("  --input <FILE>         Another BAM/CRAM to count into the same totals (repeatable).");
+    eprintln!("  --input-glob <PATTERN> Also count every BAM/CRAM/SAM matching PATTERN (quote it so the shell");
+    eprintln!("                         does not expand it), in sorted order (repeatable).");
+    eprintln!("  --per-input            Also write each input's own counts to 'reads_per_barcode_per_input'");
+    eprintln!("                         (input, barcode, count); the main output stays merged.");
+    eprintln!("  --build-barcode-index  Also write the BGZF virtual offset of every counted read to");
+    eprintln!("                         'reads_per_barcode_offsets' (barcode, input, comma-separated offsets),");
+    eprintln!("                         to fetch a barcode's reads later by seeking instead of rescanning.");
+    eprintln!("                         BAM inputs only; not with --region. Needs 16 bytes per counted read.");
+    eprintln!("  --skip-bad-inputs      Warn about and skip inputs that cannot be opened instead of failing;");
+    eprintln!("                         skipped inputs are listed at the end of the run.");
+    eprintln!("  --threads <N>          Use N BAM/CRAM decompression threads and N threads for output formatting.");
+    eprintln!("  --bench                Count as usual (up to --limit records) but write nothing; print the");
+    eprintln!("                         throughput instead.");
+    eprintln!("  --bench-sweep <LIST>   Run --bench once per comma-separated thread count (e.g. 1,2,4,8) and");
+    eprintln!("                         print a table of the results.");
+    eprintln!("  --profile              Print wall-clock time per phase (setup, decode, count, sort, write);");
+    eprintln!("                         also added to --stats as 'profile'.");
+    eprintln!("  -n, --limit <N>        Process only the first N records (across all inputs).");
+    eprintln!("  --proper-pair-only     Count only reads with the PROPER_PAIR flag (0x2) set.");
+    eprintln!("  --min-length <N>       Skip reads whose sequence is shorter than N bases.");
+    eprintln!("  --max-length <N>       Skip reads whose sequence is longer than N bases.");
+    eprintln!("  --expect-barcode-length <N|MIN:MAX>");
+    eprintln!("                         Skip reads whose barcode (after --strip-barcode-suffix) is not N long,");
+    eprintln!("                         or outside MIN..=MAX; warns when more than 5% are skipped.");
+    eprintln!("  --barcode-regex <RE>   Count only reads whose barcode (after --strip-barcode-suffix) matches RE,");
+    eprintln!("                         e.g. '^[ACGT]{{16}}$'; anchor it to match the whole barcode.");
+    eprintln!("  --template-length-range <MIN:MAX>  Skip reads whose absolute TLEN is outside MIN..=MAX (either side may be empty).");
+    eprintln!("  --sample-fraction <F>  Randomly keep each read with probability F (0 < F <= 1).");
+    eprintln!("  --seed <N>             Seed for --sample-fraction (default {}). Same seed and input give identical output.", DEFAULT_SEED);
+    eprintln!("  --tag-split <DELIM>    Split the barcode tag on DELIM and count each non-empty sub-barcode");
+    eprintln!("                         separately (a read then contributes once per sub-barcode).");
+    eprintln!("  --tag-composite        With --tag-split, count the sub-barcodes as one composite key instead,");
+    eprintln!("                         re-joined with DELIM after dropping empty segments.");
+    eprintln!("  --by-splice            Also split mapped reads per barcode into spliced (CIGAR contains N)");
+    eprintln!("                         and unspliced, written to 'reads_per_barcode_splice'.");
+    eprintln!("  --window-diagnostics <N>");
+    eprintln!("                         Write the number of distinct barcodes in each window of N consecutive");
+    eprintln!("                         records to 'reads_per_barcode_windows'; sudden changes point at sorting");
+    eprintln!("                         or demultiplexing artifacts.");
+    eprintln!("  --reference-totals <FILE>");
+    eprintln!("                         Write reads and barcoded reads per reference to FILE as a TSV, with");
+    eprintln!("                         unmapped reads as a final '*' row.");
+    eprintln!("  --coord-range          Write the reference interval each barcode's mapped reads span, per");
+    eprintln!("                         reference, to 'reads_per_barcode_coord_range'.");
+    eprintln!("  --strand-ratio         Write each barcode's mapped reads and the fraction of them on the forward");
+    eprintln!("                         strand to 'reads_per_barcode_strand', to spot per-cell strand bias.");
+    eprintln!("  --gc-stats             Write each barcode's mean per-read GC fraction to 'reads_per_barcode_gc'.");
+    eprintln!("                         Reads every base of every counted read, so expect a slower run.");
+    eprintln!("  --dominant-reference   Write each barcode's most-read reference and its fraction of the");
+    eprintln!("                         barcode's mapped reads to 'reads_per_barcode_dominant_ref'.");
+    eprintln!("  --reference-breadth    Write the number of distinct references each barcode's mapped reads");
+    eprintln!("                         touch to 'reads_per_barcode_reference_breadth'; doublets and debris");
+    eprintln!("                         tend to scatter across more contigs than real cells.");
+    eprintln!("  --tag-fallback <LIST>  Comma-separated barcode tags tried in order per read, e.g. CB,CR;");
+    eprintln!("                         the first one present is counted (default: CB).");
+    eprintln!("  --tag <XX>             Tag to count (default CB). Repeatable: the first is the barcode tag all");
+    eprintln!("                         other options apply to; each further tag is counted in the same pass");
+    eprintln!("                         and written next to the main counts in the same format, with the tag");
+    eprintln!("                         before the extension ('reads_per_barcode.<XX>', 'counts.<XX>.tsv.gz').");
+    eprintln!("                         Barcode tags stored as bytes (H hex or B:C arrays) are read as the");
+    eprintln!("                         sequence if every byte is A/C/G/T/N, otherwise as uppercase hex.");
+    eprintln!("                         With '--tag BX' (linked reads) the summary also reports molecules;");
+    eprintln!("                         add '--umi-tag MI' for molecules per barcode.");
+    eprintln!("  --strip-barcode-suffix Drop a trailing '-<digits>' GEM group suffix from barcodes (AAAC-1 is");
+    eprintln!("                         counted as AAAC), before --tag-split and the whitelist.");
+    eprintln!("  --keep-barcode-suffix  Keep the '-<digits>' suffix under --compat-10x.");
+    eprintln!("  --compat-10x           10x Genomics preset: barcode tag CB, --strip-barcode-suffix, --umi-tag UB,");
+    eprintln!("                         and counts written as gzipped TSV to '{}'. Each part yields", COMPAT_10X_OUTPUT);
+    eprintln!("                         to its own flag: --tag/--tag-fallback, --keep-barcode-suffix, --umi-tag,");
+    eprintln!("                         --output/--format.");
+    eprintln!("  --tag-concat <XX,YY>   Build the barcode by concatenating the values of several tags, in order");
+    eprintln!("                         (for barcodes split across tags). Reads missing a tag are skipped.");
+    eprintln!("  --tag-concat-sep <SEP> With --tag-concat, put SEP between the parts (default: none).");
+    eprintln!("  --tag-concat-placeholder <STR>");
+    eprintln!("                         With --tag-concat, use STR for a missing part instead of skipping the read.");
+    eprintln!("  --tag-required         Treat reads without a string barcode tag as errors: tally and report them");
+    eprintln!("                         prominently (and abort on the first one with --strict).");
+    eprintln!("  --count-missing-as <LABEL>");
+    eprintln!("                         Count reads without a usable barcode tag under LABEL (e.g. NO_BARCODE)");
+    eprintln!("                         instead of skipping them, so the counts cover every read.");
+    eprintln!("  --fail-on-empty        Exit with an error, writing nothing, if no barcoded reads were counted.");
+    eprintln!("  --strict               Abort on the first data problem (unreadable record, or missing tag");
+    eprintln!("                         with --tag-required) instead of skipping it.");
+    eprintln!("  --preview <N>          Print name, flag, position and barcode of the first N barcoded reads that");
+    eprintln!("                         pass the read filters to stdout, then carry on counting.");
+    eprintln!("  --preview-only         With --preview, stop after the preview without writing any output.");
+    eprintln!("  --max-error-rate <F>   Abort if more than a fraction F of records cannot be read (default {}).", DEFAULT_MAX_ERROR_RATE);
+    eprintln!("  --umi-tag <XX>         UMI mode: also count distinct UMIs (from tag XX, e.g. UB) per barcode,");
+    eprintln!("                         written to 'reads_per_barcode_umi' (barcode, reads, umis).");
+    eprintln!("  --top-umi              With --umi-tag, add each barcode's most read UMI and its read count to");
+    eprintln!("                         'reads_per_barcode_umi'. Keeps a read count per UMI, so uses more memory.");
+    eprintln!("  --min-umi-reads <N>    With --umi-tag, count a barcode's UMI only if it was seen in at least N");
+    eprintln!("                         reads, dropping likely erroneous UMIs; the number dropped is reported.");
+    eprintln!("  --weight-tag <XX>      Add the integer value of tag XX per read (e.g. molecule multiplicity)");
+    eprintln!("                         instead of 1. Also applies to the binned/splice/reference tables.");
+    eprintln!("  --weight-by-as         Weight reads by their alignment score, i.e. '--weight-tag AS'. Negative");
+    eprintln!("                         scores count as missing weights.");
+    eprintln!("  --missing-weight <P>   With --weight-tag, reads without a usable weight are counted as 1");
+    eprintln!("                         ('one', default) or not counted ('skip').");
+    eprintln!("  --tag-summary <XX>     Print the {} most common values of tag XX over the first {} reads", TAG_SUMMARY_TOP, TAG_SUMMARY_READS);
+    eprintln!("                         (after --sample-fraction), e.g. RG or NH, for debugging.");
+    eprintln!("  --umi-length-diagnostic");
+    eprintln!("                         In UMI mode, report the UMI length histogram and list barcodes whose");
+    eprintln!("                         UMIs vary in length (usually a tag-parsing problem).");
+    eprintln!("  --count-by-tag-value-length");
+    eprintln!("                         Report the length histogram of the barcode tag's values (hidden by");
+    eprintln!("                         -q) and warn if there is more than one length. Combine with --limit");
+    eprintln!("                         or --sample-fraction for a quick check.");
+    eprintln!("  --quiet-reference      Don't warn when a reference is given for a non-CRAM input.");
+    eprintln!("  --require-reference    Fail instead of relying on HTSlib reference discovery when a CRAM");
+    eprintln!("                         input has no reference FASTA.");
+    eprintln!("  --strict-cram          For CRAM inputs, require an explicit reference FASTA with its '.fai'");
+    eprintln!("                         index, and ignore REF_PATH/REF_CACHE reference discovery.");
+    eprintln!("  --ref-cache <DIR>      Cache CRAM references htslib fetches by checksum in DIR (sets REF_CACHE),");
+    eprintln!("                         so later runs skip the download. Only used when no reference FASTA is");
+    eprintln!("                         given: an explicit reference always takes precedence.");
+    eprintln!("  --barcode-map <FILE>   Remap barcodes through a two-column 'observed canonical' table before");
+    eprintln!("                         counting (and before whitelist checks). Unlisted barcodes count as-is.");
+    eprintln!("  --barcode-map-drop-unlisted");
+    eprintln!("                         With --barcode-map, drop barcodes that are not in the table.");
+    eprintln!("  --cluster-map <FILE>   Sum reads per cluster instead of per barcode, from a two-column");
+    eprintln!("                         'barcode cluster' table (pseudobulk counts). Applied after the whitelist;");
+    eprintln!("                         unlisted barcodes go to 'unassigned'. Every output is keyed by cluster.");
+    eprintln!("  --separate-unmapped    Leave unmapped barcoded reads out of the main counts and report their");
+    eprintln!("                         total separately.");
+    eprintln!("  --unmapped-output <FILE>");
+    eprintln!("                         With --separate-unmapped, write the unmapped counts per barcode to FILE.");
+    eprintln!("  --secondary-separate   Count secondary alignments (flag 0x100) apart from the main counts and");
+    eprintln!("                         write them to 'reads_per_barcode_secondary'.");
+    eprintln!("  --mapq-tiers <LIST>    Also count mapped reads per barcode in MAPQ tiers bounded by LIST, e.g.");
+    eprintln!("                         1,30 for tiers 0, 1-29 and 30+, into 'reads_per_barcode_mapq' (one");
+    eprintln!("                         column per tier). Barcodes dominated by MAPQ 0 are mostly multimappers.");
+    eprintln!("  --dup-separate         Count duplicate-flagged reads (0x400) apart from the main counts and");
+    eprintln!("                         write them to 'reads_per_barcode_duplicate', so both the deduplicated");
+    eprintln!("                         and the duplicate count per barcode are available.");
+    eprintln!("  --multimap-rate        Count primary alignments only, and report the fraction of records that");
+    eprintln!("                         are secondary or supplementary (also 'multimap_rate' in --stats).");
+    eprintln!("  --merge-supplementary  Fold supplementary alignments of chimeric reads into the primary's count");
+    eprintln!("                         instead of counting them again. Applied before every other read filter;");
+    eprintln!("                         implied by --multimap-rate.");
+    eprintln!("  --whitelist <FILE>     Count only barcodes listed in FILE (one per line; '.gz' accepted).");
+    eprintln!("  --whitelist-ci         Match barcodes against the whitelist case-insensitively; both sides are");
+    eprintln!("                         uppercased, and folded matches are counted under the uppercased barcode.");
+    eprintln!("  --include-zero         With --whitelist, write every whitelist barcode, with a count of 0 if");
+    eprintln!("                         it was not seen, so samples share one barcode axis. Summaries and");
+    eprintln!("                         --fail-on-empty only count observed barcodes.");
+    eprintln!("                         (Alias: --write-empty-barcodes.)");
+    eprintln!("  --whitelist-correct <D>");
+    eprintln!("                         With --whitelist, count an off-whitelist barcode under the whitelisted");
+    eprintln!("                         barcode within Hamming distance D (1-{}) if exactly one is nearest;", MAX_CORRECTION_DISTANCE);
+    eprintln!("                         ambiguous barcodes are treated as off-whitelist.");
+    eprintln!("  --off-whitelist-output <FILE>");
+    eprintln!("                         With --whitelist, write counts of the non-whitelisted barcodes to FILE.");
+    eprintln!("  --gtf <FILE>           Count only reads overlapping an exon in the GTF annotation ('.gz'");
+    eprintln!("                         accepted) and report the fraction that did.");
+    eprintln!("  --mate-aware           With --gtf, also count a paired read whose mate overlaps an exon, so both");
+    eprintln!("                         mates of a fragment count. Uses the mate position and its 'MC' CIGAR tag");
+    eprintln!("                         (add with 'samtools fixmate -m'); without MC only the mate's first base");
+    eprintln!("                         is checked. Relies on the mate fields, so both mates must have been");
+    eprintln!("                         present when they were set (e.g. by 'samtools fixmate').");
+    eprintln!("  --chrom <NAME>         Count only reads on the named reference. Requires an index.");
+    eprintln!("  --region <REGION>      Count only reads in a samtools-style region (chr1, chr1:1000-2000).");
+    eprintln!("                         Repeatable; reads in overlapping regions are counted once. Requires an");
+    eprintln!("                         index.");
+    eprintln!("  --index <FILE|URL>     With --chrom or --region, the index to use instead of '<input>.bai' etc. Remote");
+    eprintln!("                         indexes are downloaded to the current directory by htslib.");
+    eprintln!("  -v, --verbose          Print extra per-file debug detail to stderr (repeatable).");
+    eprintln!("  -q, --quiet            Suppress informational output; -qq also suppresses warnings.");
+    eprintln!("  --log-json <FILE>      Also write every warning and error to FILE as JSON lines with level,");
+    eprintln!("                         message, input and record index, for monitoring; stderr is unchanged.");
+    eprintln!("  --sqlite <FILE>        Also write counts to an SQLite database (tables 'counts' and");
+    eprintln!("                         'metadata'). Requires building with '--features sqlite'.");
+    eprintln!("  --h5 <FILE>            Also write counts to an HDF5 file with AnnData-style 'obs_names' and");
+    eprintln!("                         an (n, 1) 'X'. Requires building with '--features hdf5'.");
+    eprintln!("  --script <FILE>        Count each read under the key (and optional weight) returned by the Rhai");
+    eprintln!("                         script FILE, which sees the read's fields and tags. Requires building");
+    eprintln!("                         with '--features script'. --separate-unmapped, --secondary-separate,");
+    eprintln!("                         --dup-separate and --limit-per-barcode apply to the returned key.");
+    eprintln!("  --max-runtime <SECS>   Stop reading after SECS of wall-clock time, write the partial counts");
+    eprintln!("                         (flagged in the summary, --stats, the JSON wrapper and a '<output>.partial'");
+    eprintln!("                         marker file) and exit successfully.");
+    eprintln!("  --estimate             With --max-runtime or --limit on a BAM input, extrapolate the counts to");
+    eprintln!("                         the whole file by compressed bytes read and write them to");
+    eprintln!("                         'reads_per_barcode_estimate'. A rough, quick estimate only.");
+    eprintln!("  --sort <ORDER>         Row order: 'barcode' (default) or 'count' (descending, ties by barcode).");
+    eprintln!("  --external-sort        For barcode spaces too large for memory: spill sorted runs of 4M barcodes");
+    eprintln!("                         to temporary files and merge them into the output. Trades disk space");
+    eprintln!("                         (up to the output size per run) and speed for bounded memory. Text,");
+    eprintln!("                         TSV and CSV in barcode order only, without side tables.");
+    eprintln!("  --temp-dir <DIR>       Where --external-sort writes its runs (default: the system temp dir).");
+    eprintln!("  --cumulative-fraction <F>");
+    eprintln!("                         Write only the highest-count barcodes that together cover fraction F");
+    eprintln!("                         of barcoded reads (0 < F <= 1), a simple cell-calling cutoff.");
+    eprintln!("  --cap-count <N>        Clamp each barcode's count at N before totals, normalization and output;");
+    eprintln!("                         the number of capped barcodes and reads over the cap are reported.");
+    eprintln!("  --limit-per-barcode <N>");
+    eprintln!("                         Stop counting a barcode once it has N reads, e.g. to collect balanced");
+    eprintln!("                         reads per cell; later reads of a full barcode are skipped, also for");
+    eprintln!("                         side tables. With --whitelist, reading stops once every listed barcode");
+    eprintln!("                         is full. --limit still bounds the records read: whichever stops first");
+    eprintln!("                         wins, so with --limit some barcodes may never fill. Unlike --cap-count,");
+    eprintln!("                         which clamps counts after counting, this changes what is counted.");
+    eprintln!("                         A weighted read that would pass N adds only the weight that fills it.");
+    eprintln!("  --knee-data <FILE>     Write the rank-vs-count curve of all barcodes to FILE for a knee plot,");
+    eprintln!("                         thinned to log-spaced ranks in long tails, with the knee (the last rank");
+    eprintln!("                         before the largest drop below the log-log line from first to last rank)");
+    eprintln!("                         as '# knee_rank' and '# knee_count' header comments; barcodes with at");
+    eprintln!("                         least knee_count reads are those before the drop.");
+    eprintln!("  --count-range <MIN:MAX>");
+    eprintln!("                         Write only barcodes whose count is in [MIN, MAX]; either bound may be");
+    eprintln!("                         left empty (e.g. '100:' or ':5').");
+    eprintln!("  --with-rank            With '--sort count', prepend a 1-based rank column (for knee plots).");
+    eprintln!("  --normalize-to <N>     Write counts scaled to N total reads (e.g. 1000000 for CPM), rounded");
+    eprintln!("                         to two decimals, instead of raw counts.");
+    eprintln!("  --with-raw             With --normalize-to, add the raw count as a second column.");
+    eprintln!("  --split-by-reference <DIR>");
+    eprintln!("                         Also write mapped reads per barcode for each reference to '<DIR>/<ref>.txt',");
+    eprintln!("                         listed with per-file totals in '<DIR>/manifest.tsv'.");
+    eprintln!("  --split-output <PREFIX>");
+    eprintln!("                         Also write '<PREFIX>.barcodes.txt' and '<PREFIX>.counts.txt', index-aligned.");
+    eprintln!("  --r-output <PREFIX>    Also write '<PREFIX>_barcodes.tsv' and '<PREFIX>_counts.tsv', index-aligned");
+    eprintln!("                         single-column files with a header line, for readr::read_tsv.");
+    eprintln!("  --output <FILE>        Where to write the counts (default 'reads_per_barcode', or");
+    eprintln!("                         'reads_per_barcode.<ext>' for the other formats). A '.gz' suffix compresses.");
+    eprintln!("  --stream-output        Write outputs as streams: small buffers, flushed as rows are written.");
+    eprintln!("                         Automatic when --output is a FIFO, e.g. 'mkfifo counts.pipe'. Outputs");
+    eprintln!("                         are always written in place, never via a temporary file and rename.");
+    eprintln!("  --field-order <ORDER>  Column order for tsv/csv output: 'barcode-count' (default) or");
+    eprintln!("                         'count-barcode'.");
+    eprintln!("  --format <FMT>         Output format: 'auto' (default: from the --output extension, else text),");
+    eprintln!("                         'text', 'tsv', 'csv', 'json' or 'npy' (uint64 count array plus an");
+    eprintln!("                         index-aligned '<name>.barcodes.txt').");
+    eprintln!("                         'bincode' writes versioned binary rows (needs '--features bincode').");
+    eprintln!("                         'msgpack' writes one barcode-to-count map for Python's msgpack");
+    eprintln!("                         (needs '--features msgpack').");
+    eprintln!("  --checksum             Print a fingerprint of the barcode/count pairs that does not depend on");
+    eprintln!("                         the output format or --sort (also 'checksum' in --stats).");
+    eprintln!("  --schema-version <N>   Layout of the JSON outputs (counts, --stats, --write-meta). 2 (default)");
+    eprintln!("                         adds a 'schema_version' field and nests JSON counts under 'counts';");
+    eprintln!("                         1 is the older unversioned layout.");
+    eprintln!("  --write-meta           Also write '<output>.meta.json' recording the command line, version,");
+    eprintln!("                         htslib version, inputs and their sizes, and the effective options.");
+    eprintln!("  --stats <FILE>         Write a JSON summary of the run (including sampling seed) to FILE.");
+    eprintln!("  --bin-size <N>         Also count mapped reads per barcode in N-bp genomic bins,");
+    eprintln!("                         written to 'reads_per_barcode_bin' as a TSV with columns");
+    eprintln!("                         barcode, reference, bin_start (0-based), bin_end (exclusive), count.");
+    eprintln!("  --dump-header          Print the first input's references (name and length) and its @RG and");
+    eprintln!("                         @PG header lines, then exit without reading any records.");
+    eprintln!("  --selftest             Count a small built-in BAM and check the output against the expected");
+    eprintln!("                         result; prints PASS or FAIL and exits 0 or 1.");
+    eprintln!("  --diff <A> <B>         Compare two count files (text, TSV or CSV) instead of counting: write");
+    eprintln!("                         barcode, count_a, count_b and delta (B - A) for every barcode in either,");
+    eprintln!("                         largest change first, to --output (default 'reads_per_barcode_diff').");
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentile_bounds() {
+        let values = [3, 5, 8, 13];
+        assert_eq!(percentile(&values, 0.0), 3);
+        assert_eq!(percentile(&values, 100.0), 13);
+        assert_eq!(percentile(&[7], 0.0), 7);
+        assert_eq!(percentile(&[7], 100.0), 7);
+    }
+
+    #[test]
+    fn percentile_rounds_rank_up() {
+        // Nearest rank: no interpolation between values, rank = ceil(p/100 * n).
+        let values: Vec<usize> = (1..=10).collect();
+        assert_eq!(percentile(&values, 50.0), 5);
+        assert_eq!(percentile(&values, 50.1), 6);
+        assert_eq!(percentile(&values, 55.0), 6);
+        assert_eq!(percentile(&values, 99.0), 10);
+        assert_eq!(percentile(&[10, 20, 30, 40], 90.0), 40);
+        assert_eq!(percentile(&[10, 20, 30, 40], 25.0), 10);
+        assert_eq!(percentile(&[10, 20, 30, 40], 26.0), 20);
+    }
+
+    #[test]
+    fn separation_applies_to_untagged_reads() {
+        // An unmapped read with no barcode tag, as counted under --count-missing-as.
+        let mut record = bam::Record::new();
+        record.set(b"r", None, b"ACGT", &[30; 4]);
+        record.set_flags(0x4);
+        assert!(record.aux(b"CB").is_err());
+        assert_eq!(separation(&record, true, false, false), Some(Separated::Unmapped));
+        assert_eq!(separation(&record, false, true, true), None);
+    }
+
+    #[test]
+    fn separation_checks_unmapped_then_secondary_then_duplicate() {
+        let mut record = bam::Record::new();
+        record.set_flags(0x100 | 0x400);
+        assert_eq!(separation(&record, true, true, true), Some(Separated::Secondary));
+        assert_eq!(separation(&record, true, false, true), Some(Separated::Duplicate));
+        assert_eq!(separation(&record, false, false, false), None);
+    }
+
+    #[test]
+    fn parse_count_range_open_ends() {
+        assert_eq!(parse_count_range("10:"), Some((10, usize::MAX)));
+        assert_eq!(parse_count_range(":50"), Some((0, 50)));
+        assert_eq!(parse_count_range(":"), Some((0, usize::MAX)));
+        assert_eq!(parse_count_range("5:5"), Some((5, 5)));
+    }
+
+    #[test]
+    fn parse_count_range_rejects_bad_values() {
+        assert_eq!(parse_count_range("50:10"), None);
+        for value in ["10", "a:10", "10:b", "-1:10", "1.5:10", "10:20:30", ""] {
+            assert_eq!(parse_count_range(value), None, "{}", value);
+        }
+    }
+
+    #[test]
+    fn knee_point_needs_three_barcodes() {
+        assert_eq!(knee_point(&[]), None);
+        assert_eq!(knee_point(&[100]), None);
+        assert_eq!(knee_point(&[100, 1]), None);
+    }
+
+    #[test]
+    fn knee_point_of_flat_counts() {
+        assert_eq!(knee_point(&[50; 20]), None);
+    }
+
+    #[test]
+    fn knee_point_before_the_drop() {
+        // Ten cells with 1000 reads, then ninety empty droplets with 5: the
+        // knee count is a cutoff that keeps exactly the cells.
+        let mut counts = vec![1000; 10];
+        counts.extend([5; 90]);
+        assert_eq!(knee_point(&counts), Some((10, 1000)));
+        assert_eq!(counts.iter().filter(|&&count| count >= 1000).count(), 10);
+    }
+
+    #[test]
+    fn knee_point_on_a_sloped_curve() {
+        // Cells tailing off from 2000 to 1050 reads, then a steep fall into
+        // empty droplets: the knee is the last cell.
+        let mut counts: Vec<usize> = (0..20).map(|i| 2000 - 50 * i).collect();
+        counts.extend((0..200).map(|i| 40 - i / 10));
+        let (rank, count) = knee_point(&counts).unwrap();
+        assert_eq!((rank, count), (20, 1050));
+        assert!(counts[rank] < count);
+    }
+
+    #[test]
+    fn counts_checksum_is_pinned_fnv1a() {
+        // Reference values from an independent FNV-1a 64 implementation; a
+        // change here invalidates every cached checksum.
+        assert_eq!(counts_checksum(&[]), "fnv1a64:cbf29ce484222325");
+        let counts = [("TTTG-1".to_string(), 2), ("AAAC-1".to_string(), 4)];
+        assert_eq!(counts_checksum(&counts), "fnv1a64:f5b64270f6f26530");
+    }
+
+    #[test]
+    fn counts_checksum_ignores_row_order() {
+        let sorted = [("AAAC-1".to_string(), 4), ("TTTG-1".to_string(), 2)];
+        let reversed = [("TTTG-1".to_string(), 2), ("AAAC-1".to_string(), 4)];
+        assert_eq!(counts_checksum(&sorted), counts_checksum(&reversed));
+        assert_ne!(counts_checksum(&sorted), counts_checksum(&[("AAAC-1".to_string(), 5), ("TTTG-1".to_string(), 2)]));
+    }
+
+    #[test]
+    fn parse_mapq_tiers_accepts_increasing_boundaries() {
+        assert_eq!(parse_mapq_tiers("10,30"), Some(vec![10, 30]));
+        assert_eq!(parse_mapq_tiers(" 1 , 255 "), Some(vec![1, 255]));
+        assert_eq!(parse_mapq_tiers("20"), Some(vec![20]));
+    }
+
+    #[test]
+    fn parse_mapq_tiers_rejects_bad_boundaries() {
+        for value in ["30,10", "10,10", "10,256", "0,10", "", "10,", "ten"] {
+            assert_eq!(parse_mapq_tiers(value), None, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn at_barcode_limit_is_reached_at_the_limit() {
+        let counts: AHashMap<String, usize> = [("AAAC-1".to_string(), 3), ("TTTG-1".to_string(), 5)].into_iter().collect();
+        assert!(!at_barcode_limit(&counts, "AAAC-1", Some(5)));
+        assert!(at_barcode_limit(&counts, "TTTG-1", Some(5)));
+        assert!(at_barcode_limit(&counts, "TTTG-1", Some(4)));
+        assert!(!at_barcode_limit(&counts, "GGGA-1", Some(1)));
+        assert!(!at_barcode_limit(&counts, "TTTG-1", None));
+    }
+
+    #[test]
+    fn filter_accounting_chains_stages_to_the_counted_total() {
+        // 100 records: 10 not sampled, 5 without a tag; 85 tagged reads split
+        // into 120 barcodes, 20 off the whitelist and 30 separated as unmapped.
+        let record_stages = [
+            (true, "--sample-fraction", 10),
+            (false, "--proper-pair-only", 0),
+            (true, "no barcode tag", 5),
+        ];
+        let barcode_stages = [
+            (true, "--whitelist", 20),
+            (true, "--separate-unmapped", 30),
+            (false, "--dup-separate", 0),
+        ];
+        let stages = filter_accounting(100, &record_stages, Some(120), &barcode_stages);
+        let summary: Vec<(&str, usize, usize)> =
+            stages.iter().map(|stage| (stage.filter, stage.dropped, stage.remaining)).collect();
+        assert_eq!(
+            summary,
+            [
+                ("--sample-fraction", 10, 90),
+                ("no barcode tag", 5, 85),
+                ("barcodes", 0, 120),
+                ("--whitelist", 20, 100),
+                ("--separate-unmapped", 30, 70),
+            ]
+        );
+        let counted = 70;
+        assert_eq!(stages.last().unwrap().remaining, counted);
+        assert!(filter_accounting_json(100, &stages).ends_with("\"counted\": 70}"));
+    }
+
+    #[test]
+    fn filter_accounting_without_tag_split_keeps_counting_reads() {
+        let stages = filter_accounting(10, &[(true, "no barcode tag", 4)], None, &[(true, "--whitelist", 6)]);
+        assert_eq!(stages.len(), 2);
+        assert_eq!(stages.last().unwrap().remaining, 0);
+        assert!(filter_accounting(7, &[], None, &[]).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "--whitelist dropped 5 of 4")]
+    #[cfg(debug_assertions)]
+    fn filter_accounting_catches_a_stage_dropping_too_much() {
+        filter_accounting(10, &[(true, "no barcode tag", 6)], None, &[(true, "--whitelist", 5)]);
+    }
+
+    #[test]
+    fn tag_output_path_puts_the_tag_before_the_extension() {
+        assert_eq!(tag_output_path("reads_per_barcode", "UB"), "reads_per_barcode.UB");
+        assert_eq!(tag_output_path("reads_per_barcode.tsv", "UB"), "reads_per_barcode.UB.tsv");
+        assert_eq!(tag_output_path("out/counts.csv.gz", "GX"), "out/counts.GX.csv.gz");
+        assert_eq!(tag_output_path("out.d/counts", "GX"), "out.d/counts.GX");
+        assert_eq!(tag_output_path("out/.hidden", "GX"), "out/.hidden.GX");
+        assert_eq!(tag_output_path("counts.gz", "GX"), "counts.GX.gz");
+    }
+
+    #[test]
+    fn write_npy_counts_pads_the_header_to_64_bytes() {
+        let dir = env::temp_dir().join(format!("read_counter_npy_test_{}", process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let npy_path = dir.join("counts.npy").to_string_lossy().into_owned();
+        let index_path = dir.join("counts.barcodes.txt").to_string_lossy().into_owned();
+        // Row counts with different widths in the shape, so the padding varies.
+        for rows in [0, 3, 12_345] {
+            let counts: Vec<(String, usize)> = (0..rows).map(|i| (format!("BC{}", i), i * 1000 + 7)).collect();
+            write_npy_counts(&counts, &npy_path, &index_path).unwrap();
+
+            let bytes = std::fs::read(&npy_path).unwrap();
+            assert_eq!(&bytes[..8], b"\x93NUMPY\x01\x00");
+            let header_len = u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+            assert_eq!((header_len + 10) % 64, 0, "{} rows", rows);
+            let header = std::str::from_utf8(&bytes[10..10 + header_len]).unwrap();
+            assert!(header.starts_with("{'descr': '<u8', 'fortran_order': False, "), "{}", header);
+            assert!(header.contains(&format!("'shape': ({},)", rows)), "{}", header);
+            assert!(header.ends_with('\n'));
+
+            let payload = &bytes[10 + header_len..];
+            assert_eq!(payload.len(), rows * 8);
+            for (chunk, (_, count)) in payload.chunks(8).zip(&counts) {
+                assert_eq!(u64::from_le_bytes(chunk.try_into().unwrap()), *count as u64);
+            }
+            let index = std::fs::read_to_string(&index_path).unwrap();
+            assert_eq!(index.lines().count(), rows);
+            assert!(index.lines().zip(&counts).all(|(line, (barcode, _))| line == barcode));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "{} != {}", actual, expected);
+    }
+
+    #[test]
+    fn inequality_of_equal_counts() {
+        let (gini, top) = inequality(&[5; 20]).unwrap();
+        assert_close(gini, 0.0);
+        // Two of twenty barcodes hold a tenth of the reads.
+        assert_close(top, 0.1);
+    }
+
+    #[test]
+    fn inequality_of_a_single_dominant_barcode() {
+        // All reads in one of n barcodes is the maximum sample Gini, (n - 1) / n.
+        let (gini, top) = inequality(&[0, 0, 0, 100]).unwrap();
+        assert_close(gini, 0.75);
+        assert_close(top, 1.0);
+    }
+
+    #[test]
+    fn inequality_top_decile_rounds_up() {
+        let ten: Vec<usize> = (1..=10).collect();
+        let (_, top) = inequality(&ten).unwrap();
+        assert_close(top, 10.0 / 55.0);
+        // Eleven barcodes make the top tenth two barcodes, not one.
+        let eleven: Vec<usize> = (1..=11).collect();
+        let (_, top) = inequality(&eleven).unwrap();
+        assert_close(top, 21.0 / 66.0);
+    }
+
+    #[test]
+    fn inequality_needs_reads() {
+        assert_eq!(inequality(&[0, 0]), None);
+        assert_eq!(inequality(&[]), None);
+    }
+
+    fn tagged(tags: &[(&[u8; 2], &str)]) -> bam::Record {
+        let mut record = bam::Record::new();
+        for (tag, value) in tags {
+            record.push_aux(*tag, Aux::String(value)).unwrap();
+        }
+        record
+    }
+
+    fn concat(placeholder: Option<&str>) -> TagConcat {
+        TagConcat { tags: vec![*b"CB", *b"UB"], separator: "_".to_string(), placeholder: placeholder.map(String::from) }
+    }
+
+    #[test]
+    fn tag_concat_joins_every_present_tag_in_order() {
+        let record = tagged(&[(b"UB", "TTTT"), (b"CB", "ACGT")]);
+        assert_eq!(concat(None).barcode(&record), (Some("ACGT_TTTT".to_string()), false));
+        assert_eq!(concat(Some("N")).barcode(&record), (Some("ACGT_TTTT".to_string()), false));
+    }
+
+    #[test]
+    fn tag_concat_partial_needs_a_placeholder() {
+        let record = tagged(&[(b"UB", "TTTT")]);
+        assert_eq!(concat(None).barcode(&record), (None, true));
+        assert_eq!(concat(Some("N")).barcode(&record), (Some("N_TTTT".to_string()), true));
+    }
+
+    #[test]
+    fn tag_concat_with_no_tags_present_is_not_partial() {
+        let record = tagged(&[(b"XX", "ACGT")]);
+        assert_eq!(concat(None).barcode(&record), (None, false));
+        // The placeholder only fills gaps; it never makes a barcode from nothing.
+        assert_eq!(concat(Some("N")).barcode(&record), (None, false));
+    }
+
+    #[test]
+    fn barcode_keys_without_a_delimiter() {
+        assert_eq!(barcode_keys("ACGT,TTTT", None, false), ["ACGT,TTTT"]);
+        assert_eq!(barcode_keys("ACGT,TTTT", None, true), ["ACGT,TTTT"]);
+        assert_eq!(barcode_keys("", None, false), [""]);
+    }
+
+    #[test]
+    fn barcode_keys_split_counts_each_segment() {
+        assert_eq!(barcode_keys("ACGT,TTTT,ACGT", Some(","), false), ["ACGT", "TTTT", "ACGT"]);
+        assert_eq!(barcode_keys(",ACGT,,TTTT,", Some(","), false), ["ACGT", "TTTT"]);
+        assert!(barcode_keys(",,", Some(","), false).is_empty());
+        assert_eq!(barcode_keys("AC::GT", Some("::"), false), ["AC", "GT"]);
+    }
+
+    #[test]
+    fn barcode_keys_composite_is_one_key_without_empty_segments() {
+        assert_eq!(barcode_keys("ACGT,TTTT", Some(","), true), ["ACGT,TTTT"]);
+        assert_eq!(barcode_keys(",ACGT,,TTTT,", Some(","), true), ["ACGT,TTTT"]);
+        assert!(barcode_keys(",,", Some(","), true).is_empty());
+        assert!(barcode_keys("", Some(","), true).is_empty());
+    }
+}
@@ -0,0 +1,34 @@
+//! `--format msgpack` output (enabled with the `msgpack` cargo feature): the
+//! counts as a single MessagePack map of barcode to count, in output order,
+//! which Python's `msgpack.unpackb` (or `msgpack.unpack` on the open file)
+//! turns straight into a dict.
+
+use std::io::Write;
+
+use serde::ser::{Serialize, SerializeMap, Serializer};
+
+use crate::create_output;
+
+/// Serializes rows as a map rather than serde's default sequence of pairs.
+struct CountMap<'a>(&'a [(String, usize)]);
+
+impl Serialize for CountMap<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (barcode, count) in self.0 {
+            map.serialize_entry(barcode, &(*count as u64))?;
+        }
+        map.end()
+    }
+}
+
+pub fn write_msgpack(
+    sorted_barcodes: &[(String, usize)],
+    path: &str,
+    stream_output: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut writer = create_output(path, stream_output)?;
+    rmp_serde::encode::write(&mut writer, &CountMap(sorted_barcodes))?;
+    writer.flush()?;
+    Ok(())
+}
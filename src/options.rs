@@ -0,0 +1,1586 @@
+//! Command-line options: parsed and checked once by [`Options::parse`], then
+//! taken apart by `run()`. `--write-meta` records every field with
+//! [`Options::json`], so a new option is in the provenance sidecar as soon as
+//! it is added here.
+
+use std::process;
+use std::time::Duration;
+
+use regex::Regex;
+
+use crate::{input, logging};
+use crate::{
+    expand_input_glob, flag_value, json_array, json_string, parse_count_range, parse_flag, parse_mapq_tiers,
+    parse_tag, print_usage, tag_names, MissingWeight, OutputFormat, SchemaVersion, SortOrder, TagConcat,
+    COMPAT_10X_OUTPUT, DEFAULT_MAX_ERROR_RATE, DEFAULT_SEED, MAX_CORRECTION_DISTANCE,
+};
+
+/// The effective options of a run: defaults filled in, and implied settings
+/// (`--compat-10x`, `--weight-by-as`, `--format auto`, ...) applied.
+pub struct Options {
+    /// Input BAM/CRAM/SAM files: the positional input, then `--input` and
+    /// `--input-glob` matches.
+    pub inputs: Vec<String>,
+    pub input_globs: Vec<String>,
+    pub ref_fasta_path_str: Option<String>,
+    pub max_records: Option<usize>,
+    pub bin_size: Option<i64>,
+    pub proper_pair_only: bool,
+    pub sample_fraction: Option<f64>,
+    pub seed: u64,
+    pub stats_path: Option<String>,
+    pub write_meta: bool,
+    pub output_format: OutputFormat,
+    pub output_path: Option<String>,
+    /// `--field-order count-barcode`: count before barcode in TSV/CSV.
+    pub count_first: bool,
+    pub tag_split: Option<String>,
+    pub tag_composite: bool,
+    pub max_runtime: Option<Duration>,
+    pub by_splice: bool,
+    pub gc_stats: bool,
+    pub strand_ratio: bool,
+    pub mapq_tiers: Option<Vec<u8>>,
+    pub coord_range: bool,
+    pub verbosity: i8,
+    pub chrom: Option<String>,
+    pub index_path: Option<String>,
+    pub ref_cache: Option<String>,
+    pub mate_aware: bool,
+    pub regions: Vec<String>,
+    pub dominant_reference: bool,
+    pub reference_breadth: bool,
+    pub split_by_reference: Option<String>,
+    pub whitelist_path: Option<String>,
+    pub whitelist_ci: bool,
+    pub include_zero: bool,
+    pub off_whitelist_path: Option<String>,
+    pub whitelist_correct: Option<usize>,
+    pub separate_unmapped: bool,
+    pub secondary_separate: bool,
+    pub dup_separate: bool,
+    pub multimap_rate: bool,
+    pub merge_supplementary: bool,
+    pub reference_totals_path: Option<String>,
+    pub window_size: Option<usize>,
+    pub unmapped_output: Option<String>,
+    pub tag_required: bool,
+    pub count_missing_as: Option<String>,
+    pub strip_suffix: bool,
+    pub keep_suffix: bool,
+    pub compat_10x: bool,
+    pub strict: bool,
+    pub preview: Option<usize>,
+    pub preview_only: bool,
+    pub max_error_rate: f64,
+    pub fail_on_empty: bool,
+    pub umi_tag: Option<[u8; 2]>,
+    pub top_umi: bool,
+    pub min_umi_reads: Option<usize>,
+    pub umi_length_diagnostic: bool,
+    pub tag_length_diagnostic: bool,
+    pub quiet_reference: bool,
+    pub require_reference: bool,
+    pub strict_cram: bool,
+    /// Barcode tags in fallback order; the first `--tag` if given.
+    pub barcode_tags: Vec<[u8; 2]>,
+    /// `--tag` after the first: counted into their own tables.
+    pub extra_tags: Vec<[u8; 2]>,
+    pub sort_order: SortOrder,
+    pub split_output: Option<String>,
+    pub r_output: Option<String>,
+    pub min_length: Option<usize>,
+    pub max_length: Option<usize>,
+    pub template_length: Option<(usize, usize)>,
+    pub barcode_length: Option<(usize, usize)>,
+    pub barcode_regex: Option<Regex>,
+    pub barcode_map_path: Option<String>,
+    pub barcode_map_drop_unlisted: bool,
+    pub cluster_map_path: Option<String>,
+    pub gtf_path: Option<String>,
+    /// `--weight-tag`, or `AS` with `--weight-by-as`.
+    pub weight_tag: Option<[u8; 2]>,
+    pub missing_weight: MissingWeight,
+    pub per_input: bool,
+    pub build_barcode_index: bool,
+    pub skip_bad_inputs: bool,
+    pub threads: Option<usize>,
+    pub estimate: bool,
+    pub tag_concat: Option<TagConcat>,
+    pub tag_summary: Option<[u8; 2]>,
+    pub bench: bool,
+    pub bench_sweep: Option<Vec<usize>>,
+    pub dump_header: bool,
+    pub profile_enabled: bool,
+    pub log_json_path: Option<String>,
+    pub diff_files: Option<(String, String)>,
+    pub checksum: bool,
+    pub external_sort: bool,
+    pub temp_dir: Option<String>,
+    pub schema: SchemaVersion,
+    pub stream_output: bool,
+    pub normalize_to: Option<f64>,
+    pub with_raw: bool,
+    pub with_rank: bool,
+    pub cumulative_fraction: Option<f64>,
+    pub count_range: Option<(usize, usize)>,
+    pub cap_count: Option<usize>,
+    pub limit_per_barcode: Option<usize>,
+    pub knee_data_path: Option<String>,
+    #[cfg(feature = "sqlite")]
+    pub sqlite_path: Option<String>,
+    #[cfg(feature = "hdf5")]
+    pub h5_path: Option<String>,
+    #[cfg(feature = "script")]
+    pub script_path: Option<String>,
+}
+
+impl Options {
+    /// Parses and checks the command line, exiting with an error on bad usage.
+    /// Also sets the log verbosity and opens `--log-json`, so later errors are
+    /// logged there too.
+    pub fn parse(args: &[String]) -> Result<Options, Box<dyn std::error::Error>> {
+        let mut input_path_str: Option<String> = None;
+        let mut ref_fasta_path_str: Option<String> = None;
+        let mut max_records: Option<usize> = None;
+        let mut bin_size: Option<i64> = None;
+        let mut proper_pair_only = false;
+        let mut sample_fraction: Option<f64> = None;
+        let mut seed: Option<u64> = None;
+        let mut stats_path: Option<String> = None;
+        let mut write_meta = false;
+        // `None` is `--format auto`: inferred from `--output`, else text.
+        let mut output_format: Option<OutputFormat> = None;
+        let mut output_path: Option<String> = None;
+        // `--field-order count-barcode` for TSV/CSV; barcode first otherwise.
+        let mut count_first: Option<bool> = None;
+        let mut tag_split: Option<String> = None;
+        let mut tag_composite = false;
+        let mut max_runtime: Option<Duration> = None;
+        let mut by_splice = false;
+        let mut gc_stats = false;
+        let mut strand_ratio = false;
+        let mut mapq_tiers: Option<Vec<u8>> = None;
+        let mut coord_range = false;
+        let mut verbosity: i8 = 0;
+        let mut chrom: Option<String> = None;
+        let mut index_path: Option<String> = None;
+        let mut ref_cache: Option<String> = None;
+        let mut mate_aware = false;
+        let mut regions: Vec<String> = Vec::new();
+        let mut dominant_reference = false;
+        let mut reference_breadth = false;
+        let mut split_by_reference: Option<String> = None;
+        let mut whitelist_path: Option<String> = None;
+        let mut whitelist_ci = false;
+        let mut include_zero = false;
+        let mut off_whitelist_path: Option<String> = None;
+        let mut whitelist_correct: Option<usize> = None;
+        let mut separate_unmapped = false;
+        let mut secondary_separate = false;
+        let mut dup_separate = false;
+        let mut multimap_rate = false;
+        let mut merge_supplementary = false;
+        let mut reference_totals_path: Option<String> = None;
+        let mut window_size: Option<usize> = None;
+        let mut unmapped_output: Option<String> = None;
+        let mut tag_required = false;
+        let mut count_missing_as: Option<String> = None;
+        let mut strip_suffix = false;
+        let mut keep_suffix = false;
+        let mut compat_10x = false;
+        let mut strict = false;
+        let mut preview: Option<usize> = None;
+        let mut preview_only = false;
+        let mut max_error_rate = DEFAULT_MAX_ERROR_RATE;
+        let mut fail_on_empty = false;
+        let mut umi_tag: Option<[u8; 2]> = None;
+        let mut top_umi = false;
+        let mut min_umi_reads: Option<usize> = None;
+        let mut umi_length_diagnostic = false;
+        let mut tag_length_diagnostic = false;
+        let mut quiet_reference = false;
+        let mut require_reference = false;
+        let mut strict_cram = false;
+        let mut barcode_tags: Vec<[u8; 2]> = vec![*b"CB"];
+        let mut tag_fallback_given = false;
+        let mut count_tags: Vec<[u8; 2]> = Vec::new();
+        let mut sort_order = SortOrder::Barcode;
+        let mut split_output: Option<String> = None;
+        let mut r_output: Option<String> = None;
+        let mut min_length: Option<usize> = None;
+        let mut barcode_map_path: Option<String> = None;
+        let mut gtf_path: Option<String> = None;
+        let mut weight_tag: Option<[u8; 2]> = None;
+        let mut missing_weight: Option<MissingWeight> = None;
+        let mut weight_by_as = false;
+        let mut extra_inputs: Vec<String> = Vec::new();
+        let mut input_globs: Vec<String> = Vec::new();
+        let mut per_input = false;
+        let mut build_barcode_index = false;
+        let mut threads: Option<usize> = None;
+        let mut estimate = false;
+        let mut concat_tags: Option<Vec<[u8; 2]>> = None;
+        let mut tag_summary: Option<[u8; 2]> = None;
+        let mut concat_separator = String::new();
+        let mut concat_placeholder: Option<String> = None;
+        let mut bench = false;
+        let mut dump_header = false;
+        let mut profile_enabled = false;
+        let mut log_json_path: Option<String> = None;
+        let mut bench_sweep: Option<Vec<usize>> = None;
+        let mut diff_files: Option<(String, String)> = None;
+        let mut checksum = false;
+        let mut external_sort = false;
+        let mut temp_dir: Option<String> = None;
+        let mut schema = SchemaVersion::CURRENT;
+        let mut skip_bad_inputs = false;
+        let mut barcode_map_drop_unlisted = false;
+        let mut cluster_map_path: Option<String> = None;
+        let mut normalize_to: Option<f64> = None;
+        let mut with_raw = false;
+        let mut with_rank = false;
+        let mut cumulative_fraction: Option<f64> = None;
+        let mut count_range: Option<(usize, usize)> = None;
+        let mut cap_count: Option<usize> = None;
+        let mut limit_per_barcode: Option<usize> = None;
+        let mut knee_data_path: Option<String> = None;
+        let mut max_length: Option<usize> = None;
+        let mut template_length: Option<(usize, usize)> = None;
+        let mut barcode_length: Option<(usize, usize)> = None;
+        let mut barcode_regex: Option<regex::Regex> = None;
+        let mut stream_output = false;
+        #[cfg(feature = "sqlite")]
+        let mut sqlite_path: Option<String> = None;
+        #[cfg(feature = "hdf5")]
+        let mut h5_path: Option<String> = None;
+        #[cfg(feature = "script")]
+        let mut script_path: Option<String> = None;
+
+
+        let mut arg_iter = args.iter().skip(1);
+        while let Some(arg) = arg_iter.next() {
+            match arg.as_str() {
+                "-n" | "--limit" => {
+                    if let Some(val_str) = arg_iter.next() {
+                        match val_str.parse::<usize>() {
+                            Ok(n) => max_records = Some(n),
+                            Err(_) => {
+                                error!("--limit value '{}' is not a valid positive integer.", val_str);
+                                process::exit(1);
+                            }
+                        }
+                    } else {
+                        error!("--limit flag requires a number.");
+                        process::exit(1);
+                    }
+                },
+                "--bin-size" => {
+                    if let Some(val_str) = arg_iter.next() {
+                        match val_str.parse::<i64>() {
+                            Ok(n) if n > 0 => bin_size = Some(n),
+                            _ => {
+                                error!("--bin-size value '{}' is not a valid positive integer.", val_str);
+                                process::exit(1);
+                            }
+                        }
+                    } else {
+                        error!("--bin-size flag requires a number.");
+                        process::exit(1);
+                    }
+                },
+                "--proper-pair-only" => proper_pair_only = true,
+                "--min-length" => min_length = Some(parse_flag(arg_iter.next(), "--min-length", "non-negative integer")),
+                "--max-length" => max_length = Some(parse_flag(arg_iter.next(), "--max-length", "non-negative integer")),
+                "--template-length-range" => {
+                    let val_str = flag_value(arg_iter.next(), "--template-length-range");
+                    match parse_count_range(val_str) {
+                        Some(range) => template_length = Some(range),
+                        None => {
+                            error!(
+                                "--template-length-range value '{}' must be MIN:MAX with MIN <= MAX (either side may be empty).",
+                                val_str
+                            );
+                            process::exit(1);
+                        }
+                    }
+                },
+                "--barcode-regex" => {
+                    let pattern = flag_value(arg_iter.next(), "--barcode-regex");
+                    match regex::Regex::new(pattern) {
+                        Ok(re) => barcode_regex = Some(re),
+                        Err(e) => {
+                            error!("--barcode-regex '{}' is not a valid regular expression: {}", pattern, e);
+                            process::exit(1);
+                        }
+                    }
+                },
+                "--expect-barcode-length" => {
+                    let val_str = flag_value(arg_iter.next(), "--expect-barcode-length");
+                    let range = match val_str.parse::<usize>() {
+                        Ok(length) => Some((length, length)),
+                        Err(_) => parse_count_range(val_str),
+                    };
+                    match range {
+                        Some(range) => barcode_length = Some(range),
+                        None => {
+                            error!(
+                                "--expect-barcode-length value '{}' must be a length N or a range MIN:MAX.",
+                                val_str
+                            );
+                            process::exit(1);
+                        }
+                    }
+                },
+                "--sample-fraction" => {
+                    let fraction: f64 = parse_flag(arg_iter.next(), "--sample-fraction", "number");
+                    if !(fraction > 0.0 && fraction <= 1.0) {
+                        error!("--sample-fraction must be in the range (0, 1].");
+                        process::exit(1);
+                    }
+                    sample_fraction = Some(fraction);
+                },
+                "--max-error-rate" => {
+                    max_error_rate = parse_flag(arg_iter.next(), "--max-error-rate", "number");
+                    if !(0.0..=1.0).contains(&max_error_rate) {
+                        error!("--max-error-rate must be in the range [0, 1].");
+                        process::exit(1);
+                    }
+                },
+                "--seed" => seed = Some(parse_flag(arg_iter.next(), "--seed", "non-negative integer")),
+                "--tag-split" => {
+                    let delim = flag_value(arg_iter.next(), "--tag-split");
+                    if delim.is_empty() {
+                        error!("--tag-split delimiter must not be empty.");
+                        process::exit(1);
+                    }
+                    tag_split = Some(delim.to_string());
+                },
+                "--tag-composite" => tag_composite = true,
+                "--tag-concat" => {
+                    let list = flag_value(arg_iter.next(), "--tag-concat");
+                    let tags: Vec<[u8; 2]> = list.split(',').map(|tag| parse_tag(tag.trim(), "--tag-concat")).collect();
+                    if tags.len() < 2 {
+                        error!("--tag-concat needs at least two tags, e.g. XX,YY.");
+                        process::exit(1);
+                    }
+                    concat_tags = Some(tags);
+                },
+                "--tag-concat-sep" => concat_separator = flag_value(arg_iter.next(), "--tag-concat-sep").to_string(),
+                "--tag-concat-placeholder" => {
+                    concat_placeholder = Some(flag_value(arg_iter.next(), "--tag-concat-placeholder").to_string())
+                },
+                "--by-splice" => by_splice = true,
+                "--gc-stats" => gc_stats = true,
+                "--strand-ratio" => strand_ratio = true,
+                "--mapq-tiers" => {
+                    let value = flag_value(arg_iter.next(), "--mapq-tiers");
+                    match parse_mapq_tiers(value) {
+                        Some(boundaries) => mapq_tiers = Some(boundaries),
+                        None => {
+                            error!(
+                                "--mapq-tiers value '{}' must be increasing MAPQ boundaries from 1 to 255, e.g. 1,30.",
+                                value
+                            );
+                            process::exit(1);
+                        },
+                    }
+                },
+                "--coord-range" => coord_range = true,
+                "--dominant-reference" => dominant_reference = true,
+                "--reference-breadth" => reference_breadth = true,
+                "--split-by-reference" => {
+                    split_by_reference = Some(flag_value(arg_iter.next(), "--split-by-reference").to_string())
+                },
+                "--tag-required" => tag_required = true,
+                "--strip-barcode-suffix" => strip_suffix = true,
+                "--keep-barcode-suffix" => keep_suffix = true,
+                "--compat-10x" => compat_10x = true,
+                "--count-missing-as" => {
+                    let label = flag_value(arg_iter.next(), "--count-missing-as");
+                    if label.is_empty() {
+                        error!("--count-missing-as label must not be empty.");
+                        process::exit(1);
+                    }
+                    count_missing_as = Some(label.to_string());
+                },
+                "--strict" => strict = true,
+                "--fail-on-empty" => fail_on_empty = true,
+                "--selftest" => {
+                    error!("--selftest takes no other arguments.");
+                    process::exit(1);
+                },
+                "--umi-tag" => umi_tag = Some(parse_tag(flag_value(arg_iter.next(), "--umi-tag"), "--umi-tag")),
+                "--weight-tag" => weight_tag = Some(parse_tag(flag_value(arg_iter.next(), "--weight-tag"), "--weight-tag")),
+                "--weight-by-as" => weight_by_as = true,
+                "--missing-weight" => {
+                    let val_str = flag_value(arg_iter.next(), "--missing-weight");
+                    missing_weight = match val_str {
+                        "one" => Some(MissingWeight::CountOne),
+                        "skip" => Some(MissingWeight::Skip),
+                        _ => {
+                            error!("Unknown --missing-weight '{}'. Expected 'one' or 'skip'.", val_str);
+                            process::exit(1);
+                        }
+                    };
+                },
+                "--tag-summary" => tag_summary = Some(parse_tag(flag_value(arg_iter.next(), "--tag-summary"), "--tag-summary")),
+                "--umi-length-diagnostic" => umi_length_diagnostic = true,
+                "--count-by-tag-value-length" => tag_length_diagnostic = true,
+                "--top-umi" => top_umi = true,
+                "--min-umi-reads" => {
+                    let n: usize = parse_flag(arg_iter.next(), "--min-umi-reads", "positive integer");
+                    if n == 0 {
+                        error!("--min-umi-reads must be at least 1.");
+                        process::exit(1);
+                    }
+                    min_umi_reads = Some(n);
+                },
+                "--quiet-reference" => quiet_reference = true,
+                "--sort" => {
+                    let val_str = flag_value(arg_iter.next(), "--sort");
+                    sort_order = match val_str {
+                        "barcode" => SortOrder::Barcode,
+                        "count" => SortOrder::CountDesc,
+                        _ => {
+                            error!("Unknown --sort '{}'. Expected 'barcode' or 'count'.", val_str);
+                            process::exit(1);
+                        }
+                    };
+                },
+                "--normalize-to" => {
+                    let target: f64 = parse_flag(arg_iter.next(), "--normalize-to", "number");
+                    if !(target > 0.0 && target.is_finite()) {
+                        error!("--normalize-to must be a positive number.");
+                        process::exit(1);
+                    }
+                    normalize_to = Some(target);
+                },
+                "--with-raw" => with_raw = true,
+                "--with-rank" => with_rank = true,
+                "--cumulative-fraction" => {
+                    let fraction: f64 = parse_flag(arg_iter.next(), "--cumulative-fraction", "number");
+                    if !(fraction > 0.0 && fraction <= 1.0) {
+                        error!("--cumulative-fraction must be in the range (0, 1].");
+                        process::exit(1);
+                    }
+                    cumulative_fraction = Some(fraction);
+                },
+                "--cap-count" => {
+                    let n: usize = parse_flag(arg_iter.next(), "--cap-count", "positive integer");
+                    if n == 0 {
+                        error!("--cap-count must be at least 1.");
+                        process::exit(1);
+                    }
+                    cap_count = Some(n);
+                },
+                "--limit-per-barcode" => {
+                    let n: usize = parse_flag(arg_iter.next(), "--limit-per-barcode", "positive integer");
+                    if n == 0 {
+                        error!("--limit-per-barcode must be at least 1.");
+                        process::exit(1);
+                    }
+                    limit_per_barcode = Some(n);
+                },
+                "--knee-data" => knee_data_path = Some(flag_value(arg_iter.next(), "--knee-data").to_string()),
+                "--count-range" => {
+                    let val_str = flag_value(arg_iter.next(), "--count-range");
+                    match parse_count_range(val_str) {
+                        Some(range) => count_range = Some(range),
+                        None => {
+                            error!(
+                                "--count-range value '{}' must be MIN:MAX with MIN <= MAX (either side may be empty).",
+                                val_str
+                            );
+                            process::exit(1);
+                        }
+                    }
+                },
+                "--split-output" => split_output = Some(flag_value(arg_iter.next(), "--split-output").to_string()),
+                "--r-output" => r_output = Some(flag_value(arg_iter.next(), "--r-output").to_string()),
+                "--tag-fallback" => {
+                    let list = flag_value(arg_iter.next(), "--tag-fallback");
+                    barcode_tags = list.split(',').map(|tag| parse_tag(tag.trim(), "--tag-fallback")).collect();
+                    tag_fallback_given = true;
+                },
+                "--tag" => count_tags.push(parse_tag(flag_value(arg_iter.next(), "--tag"), "--tag")),
+                "--require-reference" => require_reference = true,
+                "--strict-cram" => strict_cram = true,
+                "--separate-unmapped" => separate_unmapped = true,
+                "--secondary-separate" => secondary_separate = true,
+                "--dup-separate" => dup_separate = true,
+                "--multimap-rate" => multimap_rate = true,
+                "--merge-supplementary" => merge_supplementary = true,
+                "--window-diagnostics" => {
+                    let n: usize = parse_flag(arg_iter.next(), "--window-diagnostics", "positive integer");
+                    if n == 0 {
+                        error!("--window-diagnostics must be at least 1.");
+                        process::exit(1);
+                    }
+                    window_size = Some(n);
+                },
+                "--reference-totals" => {
+                    reference_totals_path = Some(flag_value(arg_iter.next(), "--reference-totals").to_string())
+                },
+                "--unmapped-output" => unmapped_output = Some(flag_value(arg_iter.next(), "--unmapped-output").to_string()),
+                "--off-whitelist-output" => {
+                    off_whitelist_path = Some(flag_value(arg_iter.next(), "--off-whitelist-output").to_string())
+                },
+                "--barcode-map" => barcode_map_path = Some(flag_value(arg_iter.next(), "--barcode-map").to_string()),
+                "--barcode-map-drop-unlisted" => barcode_map_drop_unlisted = true,
+                "--cluster-map" => cluster_map_path = Some(flag_value(arg_iter.next(), "--cluster-map").to_string()),
+                "--input" => extra_inputs.push(flag_value(arg_iter.next(), "--input").to_string()),
+                "--input-glob" => input_globs.push(flag_value(arg_iter.next(), "--input-glob").to_string()),
+                "--per-input" => per_input = true,
+                "--build-barcode-index" => build_barcode_index = true,
+                "--skip-bad-inputs" => skip_bad_inputs = true,
+                "--threads" => {
+                    let n: usize = parse_flag(arg_iter.next(), "--threads", "positive integer");
+                    if n == 0 {
+                        error!("--threads must be at least 1.");
+                        process::exit(1);
+                    }
+                    threads = Some(n);
+                },
+                "--bench" => bench = true,
+                "--dump-header" => dump_header = true,
+                "--preview" => preview = Some(parse_flag(arg_iter.next(), "--preview", "non-negative integer")),
+                "--preview-only" => preview_only = true,
+                "--profile" => profile_enabled = true,
+                "--estimate" => estimate = true,
+                "--bench-sweep" => {
+                    let list = flag_value(arg_iter.next(), "--bench-sweep");
+                    let counts: Vec<usize> = list
+                        .split(',')
+                        .map(|n| match n.trim().parse::<usize>() {
+                            Ok(n) if n > 0 => n,
+                            _ => {
+                                error!("--bench-sweep value '{}' is not a valid positive integer.", n);
+                                process::exit(1);
+                            },
+                        })
+                        .collect();
+                    bench_sweep = Some(counts);
+                },
+                "--gtf" => gtf_path = Some(flag_value(arg_iter.next(), "--gtf").to_string()),
+                "--whitelist-correct" => {
+                    let distance: usize = parse_flag(arg_iter.next(), "--whitelist-correct", "positive integer");
+                    if !(1..=MAX_CORRECTION_DISTANCE).contains(&distance) {
+                        error!("--whitelist-correct must be between 1 and {}.", MAX_CORRECTION_DISTANCE);
+                        process::exit(1);
+                    }
+                    whitelist_correct = Some(distance);
+                },
+                "--whitelist" => whitelist_path = Some(flag_value(arg_iter.next(), "--whitelist").to_string()),
+                "--whitelist-ci" => whitelist_ci = true,
+                "--include-zero" | "--write-empty-barcodes" => include_zero = true,
+                "--chrom" => chrom = Some(flag_value(arg_iter.next(), "--chrom").to_string()),
+                "--diff" => {
+                    let path_a = flag_value(arg_iter.next(), "--diff").to_string();
+                    let path_b = flag_value(arg_iter.next(), "--diff").to_string();
+                    diff_files = Some((path_a, path_b));
+                },
+                "--mate-aware" => mate_aware = true,
+                "--external-sort" => external_sort = true,
+                "--temp-dir" => temp_dir = Some(flag_value(arg_iter.next(), "--temp-dir").to_string()),
+                "--ref-cache" => ref_cache = Some(flag_value(arg_iter.next(), "--ref-cache").to_string()),
+                "--region" => regions.push(flag_value(arg_iter.next(), "--region").to_string()),
+                "--index" => index_path = Some(flag_value(arg_iter.next(), "--index").to_string()),
+                "-v" | "--verbose" => verbosity += 1,
+                "--log-json" => log_json_path = Some(flag_value(arg_iter.next(), "--log-json").to_string()),
+                "-vv" => verbosity += 2,
+                "-q" | "--quiet" => verbosity -= 1,
+                "-qq" => verbosity -= 2,
+                "--sqlite" => {
+                    let path = flag_value(arg_iter.next(), "--sqlite");
+                    #[cfg(feature = "sqlite")]
+                    {
+                        sqlite_path = Some(path.to_string());
+                    }
+                    #[cfg(not(feature = "sqlite"))]
+                    {
+                        error!("--sqlite '{}' requires read_counter to be built with the 'sqlite' feature.", path);
+                        process::exit(1);
+                    }
+                },
+                "--h5" => {
+                    let path = flag_value(arg_iter.next(), "--h5");
+                    #[cfg(feature = "hdf5")]
+                    {
+                        h5_path = Some(path.to_string());
+                    }
+                    #[cfg(not(feature = "hdf5"))]
+                    {
+                        error!("--h5 '{}' requires read_counter to be built with the 'hdf5' feature.", path);
+                        process::exit(1);
+                    }
+                },
+                "--script" => {
+                    let path = flag_value(arg_iter.next(), "--script");
+                    #[cfg(feature = "script")]
+                    {
+                        script_path = Some(path.to_string());
+                    }
+                    #[cfg(not(feature = "script"))]
+                    {
+                        error!("--script '{}' requires read_counter to be built with the 'script' feature.", path);
+                        process::exit(1);
+                    }
+                },
+                "--max-runtime" => {
+                    let secs: f64 = parse_flag(arg_iter.next(), "--max-runtime", "number of seconds");
+                    if !(secs > 0.0 && secs.is_finite()) {
+                        error!("--max-runtime must be a positive number of seconds.");
+                        process::exit(1);
+                    }
+                    max_runtime = Some(Duration::from_secs_f64(secs));
+                },
+                "--format" => {
+                    let val_str = flag_value(arg_iter.next(), "--format");
+                    output_format = match OutputFormat::parse(val_str) {
+                        Some(format) => Some(format),
+                        None if val_str == "auto" => None,
+                        None => {
+                            error!(
+                                "Unknown --format '{}'. Expected 'auto', 'text', 'tsv', 'csv', 'json', 'npy', 'bincode' or 'msgpack'.",
+                                val_str
+                            );
+                            process::exit(1);
+                        }
+                    };
+                },
+                "--field-order" => {
+                    let val_str = flag_value(arg_iter.next(), "--field-order");
+                    count_first = match val_str {
+                        "barcode-count" => Some(false),
+                        "count-barcode" => Some(true),
+                        _ => {
+                            error!(
+                                "Unknown --field-order '{}'. Expected 'barcode-count' or 'count-barcode'.",
+                                val_str
+                            );
+                            process::exit(1);
+                        }
+                    };
+                },
+                "--output" => output_path = Some(flag_value(arg_iter.next(), "--output").to_string()),
+                "--stream-output" => stream_output = true,
+                "--write-meta" => write_meta = true,
+                "--checksum" => checksum = true,
+                "--schema-version" => {
+                    let val_str = flag_value(arg_iter.next(), "--schema-version");
+                    schema = match val_str.parse().ok().and_then(SchemaVersion::from_number) {
+                        Some(version) => version,
+                        None => {
+                            error!(
+                                "Unknown --schema-version '{}'. Expected 1 or 2 (the default).",
+                                val_str
+                            );
+                            process::exit(1);
+                        }
+                    };
+                },
+                "--stats" => stats_path = Some(flag_value(arg_iter.next(), "--stats").to_string()),
+                _ if arg.starts_with('-') => {
+                    error!("Unknown flag '{}'", arg);
+                    print_usage(&args[0]);
+                    process::exit(1);
+                }
+                _ => { // Positional arguments
+                    if input_path_str.is_none() {
+                        input_path_str = Some(arg.clone());
+                    } else if ref_fasta_path_str.is_none() {
+                        ref_fasta_path_str = Some(arg.clone());
+                    } else {
+                        error!("Too many positional arguments provided.");
+                        print_usage(&args[0]);
+                        process::exit(1);
+                    }
+                }
+            }
+        }
+
+        logging::set_verbosity(verbosity.clamp(-2, 2));
+        if let Some(path) = &log_json_path
+            && let Err(e) = logging::open_json_log(path)
+        {
+            error!("Cannot create --log-json file '{}': {}.", path, e);
+            process::exit(1);
+        }
+
+        if strip_suffix && keep_suffix {
+            error!("--strip-barcode-suffix and --keep-barcode-suffix cannot be combined.");
+            process::exit(1);
+        }
+        // --compat-10x only fills in what the individual flags left unset. The
+        // barcode tag needs nothing: CB is already the default.
+        if compat_10x {
+            strip_suffix = !keep_suffix;
+            umi_tag = umi_tag.or(Some(*b"UB"));
+            if output_path.is_none() && output_format.is_none() {
+                output_path = Some(COMPAT_10X_OUTPUT.to_string());
+            }
+            debug!(
+                "--compat-10x: UMI tag {}, barcode suffix {}, counts to '{}'.",
+                tag_names(&umi_tag.into_iter().collect::<Vec<_>>()),
+                if strip_suffix { "stripped" } else { "kept" },
+                output_path.as_deref().unwrap_or("the --format default")
+            );
+        }
+
+        let output_format = match (output_format, &output_path) {
+            (Some(format), _) => format,
+            // --diff writes its own table, whatever the --output name.
+            (None, Some(path)) if diff_files.is_none() => OutputFormat::from_extension(path).unwrap_or_else(|e| {
+                error!("{}", e);
+                process::exit(1);
+            }),
+            (None, _) => OutputFormat::Text,
+        };
+        if matches!(output_format, OutputFormat::Npy | OutputFormat::Bincode)
+            && output_path.as_deref().is_some_and(|path| path.ends_with(".gz"))
+        {
+            error!("npy and bincode output cannot be gzip-compressed.");
+            process::exit(1);
+        }
+        if cfg!(not(feature = "bincode")) && output_format == OutputFormat::Bincode {
+            error!("--format bincode requires read_counter to be built with the 'bincode' feature.");
+            process::exit(1);
+        }
+        if count_first.is_some() && !matches!(output_format, OutputFormat::Tsv | OutputFormat::Csv) {
+            error!("--field-order only applies to the tsv and csv output formats.");
+            process::exit(1);
+        }
+        let count_first = count_first.unwrap_or(false);
+        if cfg!(not(feature = "msgpack")) && output_format == OutputFormat::Msgpack {
+            error!("--format msgpack requires read_counter to be built with the 'msgpack' feature.");
+            process::exit(1);
+        }
+
+        for pattern in &input_globs {
+            let matched = expand_input_glob(pattern).unwrap_or_else(|e| {
+                error!("{}", e);
+                process::exit(1);
+            });
+            info!("--input-glob '{}' matched {} BAM/CRAM/SAM files.", pattern, matched.len());
+            extra_inputs.extend(matched);
+        }
+        if input_path_str.is_none() && extra_inputs.is_empty() && diff_files.is_none() {
+            error!("Missing required input BAM/CRAM file.");
+            print_usage(&args[0]);
+            return Err("Missing input file".into());
+        }
+        let inputs: Vec<String> = input_path_str.into_iter().chain(extra_inputs).collect();
+        if seed.is_some() && sample_fraction.is_none() {
+            warn!("--seed has no effect without --sample-fraction.");
+        }
+        let seed = seed.unwrap_or(DEFAULT_SEED);
+        if let (Some(min), Some(max)) = (min_length, max_length)
+            && min > max
+        {
+            error!("--min-length ({}) is greater than --max-length ({}).", min, max);
+            process::exit(1);
+        }
+        if chrom.is_some() && !regions.is_empty() {
+            error!("--chrom and --region cannot be combined; give the reference as a --region instead.");
+            process::exit(1);
+        }
+        if build_barcode_index && !regions.is_empty() {
+            error!("--build-barcode-index cannot be combined with --region.");
+            process::exit(1);
+        }
+        if index_path.is_some() && ((chrom.is_none() && regions.is_empty()) || inputs.len() > 1) {
+            error!("--index requires --chrom or --region, and a single input.");
+            process::exit(1);
+        }
+        if with_raw && normalize_to.is_none() {
+            error!("--with-raw requires --normalize-to.");
+            process::exit(1);
+        }
+        if normalize_to.is_some() && output_format != OutputFormat::Text {
+            error!("--normalize-to is only supported with the text output format.");
+            process::exit(1);
+        }
+        // The first --tag is the barcode tag that every other option applies to;
+        // the rest are only counted, each into its own map.
+        if let Some(&first) = count_tags.first() {
+            if tag_fallback_given {
+                error!("--tag cannot be combined with --tag-fallback.");
+                process::exit(1);
+            }
+            if let Some(dup) = count_tags.iter().enumerate().find(|(i, tag)| count_tags[..*i].contains(tag)) {
+                error!("--tag {} given more than once.", String::from_utf8_lossy(dup.1));
+                process::exit(1);
+            }
+            barcode_tags = vec![first];
+        }
+        let extra_tags: Vec<[u8; 2]> = count_tags.iter().skip(1).copied().collect();
+        if with_rank && output_format != OutputFormat::Text {
+            error!("--with-rank is only supported with the text output format.");
+            process::exit(1);
+        }
+        if with_rank && sort_order != SortOrder::CountDesc {
+            warn!("--with-rank numbers rows in barcode order; use '--sort count' for count ranks.");
+        }
+        if barcode_map_drop_unlisted && barcode_map_path.is_none() {
+            error!("--barcode-map-drop-unlisted requires --barcode-map.");
+            process::exit(1);
+        }
+        if preview_only && preview.is_none() {
+            error!("--preview-only requires --preview.");
+            process::exit(1);
+        }
+        if multimap_rate && secondary_separate {
+            error!("--multimap-rate (which skips secondary alignments) cannot be combined with --secondary-separate.");
+            process::exit(1);
+        }
+        if unmapped_output.is_some() && !separate_unmapped {
+            error!("--unmapped-output requires --separate-unmapped.");
+            process::exit(1);
+        }
+        if whitelist_correct.is_some() && whitelist_path.is_none() {
+            error!("--whitelist-correct requires --whitelist.");
+            process::exit(1);
+        }
+        if off_whitelist_path.is_some() && whitelist_path.is_none() {
+            error!("--off-whitelist-output requires --whitelist.");
+            process::exit(1);
+        }
+        if mate_aware && gtf_path.is_none() {
+            error!("--mate-aware requires --gtf.");
+            process::exit(1);
+        }
+        if whitelist_ci && whitelist_path.is_none() {
+            error!("--whitelist-ci requires --whitelist.");
+            process::exit(1);
+        }
+        if include_zero && whitelist_path.is_none() {
+            warn!("--include-zero has no effect without --whitelist; only observed barcodes are written.");
+            include_zero = false;
+        }
+        if include_zero && cluster_map_path.is_some() {
+            error!("--include-zero cannot be combined with --cluster-map, whose output is keyed by cluster.");
+            process::exit(1);
+        }
+        if weight_by_as {
+            if weight_tag.is_some_and(|tag| tag != *b"AS") {
+                error!("--weight-by-as cannot be combined with a different --weight-tag.");
+                process::exit(1);
+            }
+            weight_tag = Some(*b"AS");
+        }
+        if missing_weight.is_some() && weight_tag.is_none() {
+            error!("--missing-weight requires --weight-tag or --weight-by-as.");
+            process::exit(1);
+        }
+        let missing_weight = missing_weight.unwrap_or(MissingWeight::CountOne);
+        if umi_length_diagnostic && umi_tag.is_none() {
+            error!("--umi-length-diagnostic requires --umi-tag.");
+            process::exit(1);
+        }
+        if top_umi && umi_tag.is_none() {
+            error!("--top-umi requires --umi-tag.");
+            process::exit(1);
+        }
+        if min_umi_reads.is_some() && umi_tag.is_none() {
+            error!("--min-umi-reads requires --umi-tag.");
+            process::exit(1);
+        }
+        if concat_tags.is_some() && (tag_fallback_given || !count_tags.is_empty()) {
+            error!("--tag-concat cannot be combined with --tag or --tag-fallback.");
+            process::exit(1);
+        }
+        if concat_tags.is_none() && (!concat_separator.is_empty() || concat_placeholder.is_some()) {
+            error!("--tag-concat-sep and --tag-concat-placeholder require --tag-concat.");
+            process::exit(1);
+        }
+        let tag_concat = concat_tags.map(|tags| TagConcat {
+            tags,
+            separator: concat_separator,
+            placeholder: concat_placeholder,
+        });
+        if count_missing_as.is_some() && tag_required {
+            error!("--count-missing-as cannot be combined with --tag-required.");
+            process::exit(1);
+        }
+        if tag_composite && tag_split.is_none() {
+            error!("--tag-composite requires --tag-split.");
+            process::exit(1);
+        }
+        #[cfg(feature = "script")]
+        if script_path.is_some() {
+            let conflicts: Vec<&str> = [
+                (tag_split.is_some(), "--tag-split"),
+                (barcode_map_path.is_some(), "--barcode-map"),
+                (whitelist_path.is_some(), "--whitelist"),
+                (cluster_map_path.is_some(), "--cluster-map"),
+            ]
+            .into_iter()
+            .filter_map(|(given, flag)| given.then_some(flag))
+            .collect();
+            if !conflicts.is_empty() {
+                error!(
+                    "--script decides the counting key itself and cannot be combined with {}.",
+                    conflicts.join(", ")
+                );
+                process::exit(1);
+            }
+        }
+
+        let options = Options {
+            inputs,
+            input_globs,
+            ref_fasta_path_str,
+            max_records,
+            bin_size,
+            proper_pair_only,
+            sample_fraction,
+            seed,
+            stats_path,
+            write_meta,
+            output_format,
+            output_path,
+            count_first,
+            tag_split,
+            tag_composite,
+            max_runtime,
+            by_splice,
+            gc_stats,
+            strand_ratio,
+            mapq_tiers,
+            coord_range,
+            verbosity,
+            chrom,
+            index_path,
+            ref_cache,
+            mate_aware,
+            regions,
+            dominant_reference,
+            reference_breadth,
+            split_by_reference,
+            whitelist_path,
+            whitelist_ci,
+            include_zero,
+            off_whitelist_path,
+            whitelist_correct,
+            separate_unmapped,
+            secondary_separate,
+            dup_separate,
+            multimap_rate,
+            merge_supplementary,
+            reference_totals_path,
+            window_size,
+            unmapped_output,
+            tag_required,
+            count_missing_as,
+            strip_suffix,
+            keep_suffix,
+            compat_10x,
+            strict,
+            preview,
+            preview_only,
+            max_error_rate,
+            fail_on_empty,
+            umi_tag,
+            top_umi,
+            min_umi_reads,
+            umi_length_diagnostic,
+            tag_length_diagnostic,
+            quiet_reference,
+            require_reference,
+            strict_cram,
+            barcode_tags,
+            extra_tags,
+            sort_order,
+            split_output,
+            r_output,
+            min_length,
+            max_length,
+            template_length,
+            barcode_length,
+            barcode_regex,
+            barcode_map_path,
+            barcode_map_drop_unlisted,
+            cluster_map_path,
+            gtf_path,
+            weight_tag,
+            missing_weight,
+            per_input,
+            build_barcode_index,
+            skip_bad_inputs,
+            threads,
+            estimate,
+            tag_concat,
+            tag_summary,
+            bench,
+            bench_sweep,
+            dump_header,
+            profile_enabled,
+            log_json_path,
+            diff_files,
+            checksum,
+            external_sort,
+            temp_dir,
+            schema,
+            stream_output,
+            normalize_to,
+            with_raw,
+            with_rank,
+            cumulative_fraction,
+            count_range,
+            cap_count,
+            limit_per_barcode,
+            knee_data_path,
+            #[cfg(feature = "sqlite")]
+            sqlite_path,
+            #[cfg(feature = "hdf5")]
+            h5_path,
+            #[cfg(feature = "script")]
+            script_path,
+        };
+        if options.estimate
+            && let Some(problem) = options.estimate_problem()
+        {
+            error!("--estimate {}.", problem);
+            process::exit(1);
+        }
+        if options.external_sort {
+            if !matches!(options.output_format, OutputFormat::Text | OutputFormat::Tsv | OutputFormat::Csv) {
+                error!("--external-sort only supports the text, TSV and CSV output formats.");
+                process::exit(1);
+            }
+            let conflicts = options.external_sort_conflicts();
+            if !conflicts.is_empty() {
+                error!(
+                    "--external-sort writes only the main counts table and cannot be combined with {}.",
+                    conflicts.join(", ")
+                );
+                process::exit(1);
+            }
+        }
+        Ok(options)
+    }
+
+    /// Why `--estimate` can't extrapolate this run, if it can't.
+    fn estimate_problem(&self) -> Option<&'static str> {
+        if self.max_runtime.is_none() && self.max_records.is_none() {
+            Some("needs a budget: --max-runtime or --limit")
+        } else if self.inputs.len() > 1 {
+            Some("supports a single input")
+        } else if self.chrom.is_some() || !self.regions.is_empty() {
+            Some("cannot be combined with --chrom or --region")
+        } else if self.output_format != OutputFormat::Text {
+            Some("only supports the text output format")
+        } else if !self.inputs[0].ends_with(".bam") {
+            Some("needs a BAM input (the extrapolation uses BGZF offsets)")
+        } else if input::remote_url(&self.inputs[0]).is_some() {
+            Some("needs a local input (the extrapolation uses the file size)")
+        } else {
+            None
+        }
+    }
+
+    /// The given flags that `--external-sort` can't honour: its merge streams
+    /// rows straight to the output, so anything that needs every count at
+    /// once is out.
+    fn external_sort_conflicts(&self) -> Vec<&'static str> {
+        #[allow(unused_mut)] // only pushed to with the sqlite or hdf5 feature
+        let mut conflicts: Vec<&str> = [
+            (self.sort_order == SortOrder::CountDesc, "--sort count"),
+            (self.estimate, "--estimate"),
+            (self.normalize_to.is_some(), "--normalize-to"),
+            (self.with_rank, "--with-rank"),
+            (self.cumulative_fraction.is_some(), "--cumulative-fraction"),
+            (self.count_range.is_some(), "--count-range"),
+            (self.cap_count.is_some(), "--cap-count"),
+            (self.limit_per_barcode.is_some(), "--limit-per-barcode"),
+            (self.knee_data_path.is_some(), "--knee-data"),
+            (self.stats_path.is_some(), "--stats"),
+            (self.write_meta, "--write-meta"),
+            (self.checksum, "--checksum"),
+            (self.split_output.is_some(), "--split-output"),
+            (self.r_output.is_some(), "--r-output"),
+            (self.per_input, "--per-input"),
+            (self.include_zero, "--include-zero"),
+            (self.dup_separate, "--dup-separate"),
+            (self.build_barcode_index, "--build-barcode-index"),
+            (!self.extra_tags.is_empty(), "a second --tag"),
+            (self.umi_tag.is_some(), "--umi-tag"),
+            (self.bin_size.is_some(), "--bin-size"),
+            (self.by_splice, "--by-splice"),
+            (self.gc_stats, "--gc-stats"),
+            (self.strand_ratio, "--strand-ratio"),
+            (self.mapq_tiers.is_some(), "--mapq-tiers"),
+            (self.coord_range, "--coord-range"),
+            (self.dominant_reference, "--dominant-reference"),
+            (self.reference_breadth, "--reference-breadth"),
+            (self.split_by_reference.is_some(), "--split-by-reference"),
+            (self.window_size.is_some(), "--window-diagnostics"),
+        ]
+        .into_iter()
+        .filter_map(|(given, flag)| given.then_some(flag))
+        .collect();
+        #[cfg(feature = "sqlite")]
+        if self.sqlite_path.is_some() {
+            conflicts.push("--sqlite");
+        }
+        #[cfg(feature = "hdf5")]
+        if self.h5_path.is_some() {
+            conflicts.push("--h5");
+        }
+        conflicts
+    }
+
+    /// Every option as a JSON object, keyed by option name.
+    pub fn json(&self) -> String {
+        // Destructured in full so that a new field can't be left out.
+        let Options {
+            inputs,
+            input_globs,
+            ref_fasta_path_str,
+            max_records,
+            bin_size,
+            proper_pair_only,
+            sample_fraction,
+            seed,
+            stats_path,
+            write_meta,
+            output_format,
+            output_path,
+            count_first,
+            tag_split,
+            tag_composite,
+            max_runtime,
+            by_splice,
+            gc_stats,
+            strand_ratio,
+            mapq_tiers,
+            coord_range,
+            verbosity,
+            chrom,
+            index_path,
+            ref_cache,
+            mate_aware,
+            regions,
+            dominant_reference,
+            reference_breadth,
+            split_by_reference,
+            whitelist_path,
+            whitelist_ci,
+            include_zero,
+            off_whitelist_path,
+            whitelist_correct,
+            separate_unmapped,
+            secondary_separate,
+            dup_separate,
+            multimap_rate,
+            merge_supplementary,
+            reference_totals_path,
+            window_size,
+            unmapped_output,
+            tag_required,
+            count_missing_as,
+            strip_suffix,
+            keep_suffix,
+            compat_10x,
+            strict,
+            preview,
+            preview_only,
+            max_error_rate,
+            fail_on_empty,
+            umi_tag,
+            top_umi,
+            min_umi_reads,
+            umi_length_diagnostic,
+            tag_length_diagnostic,
+            quiet_reference,
+            require_reference,
+            strict_cram,
+            barcode_tags,
+            extra_tags,
+            sort_order,
+            split_output,
+            r_output,
+            min_length,
+            max_length,
+            template_length,
+            barcode_length,
+            barcode_regex,
+            barcode_map_path,
+            barcode_map_drop_unlisted,
+            cluster_map_path,
+            gtf_path,
+            weight_tag,
+            missing_weight,
+            per_input,
+            build_barcode_index,
+            skip_bad_inputs,
+            threads,
+            estimate,
+            tag_concat,
+            tag_summary,
+            bench,
+            bench_sweep,
+            dump_header,
+            profile_enabled,
+            log_json_path,
+            diff_files,
+            checksum,
+            external_sort,
+            temp_dir,
+            schema,
+            stream_output,
+            normalize_to,
+            with_raw,
+            with_rank,
+            cumulative_fraction,
+            count_range,
+            cap_count,
+            limit_per_barcode,
+            knee_data_path,
+            #[cfg(feature = "sqlite")]
+            sqlite_path,
+            #[cfg(feature = "hdf5")]
+            h5_path,
+            #[cfg(feature = "script")]
+            script_path,
+        } = self;
+        #[allow(unused_mut)] // only pushed to with the sqlite, hdf5 or script feature
+        let mut fields = vec![
+            ("inputs", inputs.to_json()),
+            ("input_globs", input_globs.to_json()),
+            ("reference", ref_fasta_path_str.to_json()),
+            ("limit", max_records.to_json()),
+            ("bin_size", bin_size.to_json()),
+            ("proper_pair_only", proper_pair_only.to_json()),
+            ("sample_fraction", sample_fraction.to_json()),
+            ("seed", seed.to_json()),
+            ("stats", stats_path.to_json()),
+            ("write_meta", write_meta.to_json()),
+            ("format", output_format.to_json()),
+            ("output", output_path.to_json()),
+            ("count_first", count_first.to_json()),
+            ("tag_split", tag_split.to_json()),
+            ("tag_composite", tag_composite.to_json()),
+            ("max_runtime", max_runtime.to_json()),
+            ("by_splice", by_splice.to_json()),
+            ("gc_stats", gc_stats.to_json()),
+            ("strand_ratio", strand_ratio.to_json()),
+            ("mapq_tiers", mapq_tiers.to_json()),
+            ("coord_range", coord_range.to_json()),
+            ("verbosity", verbosity.to_json()),
+            ("chrom", chrom.to_json()),
+            ("index", index_path.to_json()),
+            ("ref_cache", ref_cache.to_json()),
+            ("mate_aware", mate_aware.to_json()),
+            ("regions", regions.to_json()),
+            ("dominant_reference", dominant_reference.to_json()),
+            ("reference_breadth", reference_breadth.to_json()),
+            ("split_by_reference", split_by_reference.to_json()),
+            ("whitelist", whitelist_path.to_json()),
+            ("whitelist_ci", whitelist_ci.to_json()),
+            ("include_zero", include_zero.to_json()),
+            ("off_whitelist_output", off_whitelist_path.to_json()),
+            ("whitelist_correct", whitelist_correct.to_json()),
+            ("separate_unmapped", separate_unmapped.to_json()),
+            ("secondary_separate", secondary_separate.to_json()),
+            ("dup_separate", dup_separate.to_json()),
+            ("multimap_rate", multimap_rate.to_json()),
+            ("merge_supplementary", merge_supplementary.to_json()),
+            ("reference_totals", reference_totals_path.to_json()),
+            ("window_diagnostics", window_size.to_json()),
+            ("unmapped_output", unmapped_output.to_json()),
+            ("tag_required", tag_required.to_json()),
+            ("count_missing_as", count_missing_as.to_json()),
+            ("strip_barcode_suffix", strip_suffix.to_json()),
+            ("keep_barcode_suffix", keep_suffix.to_json()),
+            ("compat_10x", compat_10x.to_json()),
+            ("strict", strict.to_json()),
+            ("preview", preview.to_json()),
+            ("preview_only", preview_only.to_json()),
+            ("max_error_rate", max_error_rate.to_json()),
+            ("fail_on_empty", fail_on_empty.to_json()),
+            ("umi_tag", umi_tag.to_json()),
+            ("top_umi", top_umi.to_json()),
+            ("min_umi_reads", min_umi_reads.to_json()),
+            ("umi_length_diagnostic", umi_length_diagnostic.to_json()),
+            ("count_by_tag_value_length", tag_length_diagnostic.to_json()),
+            ("quiet_reference", quiet_reference.to_json()),
+            ("require_reference", require_reference.to_json()),
+            ("strict_cram", strict_cram.to_json()),
+            ("barcode_tags", barcode_tags.to_json()),
+            ("extra_tags", extra_tags.to_json()),
+            ("sort", sort_order.to_json()),
+            ("split_output", split_output.to_json()),
+            ("r_output", r_output.to_json()),
+            ("min_length", min_length.to_json()),
+            ("max_length", max_length.to_json()),
+            ("template_length_range", template_length.to_json()),
+            ("expect_barcode_length", barcode_length.to_json()),
+            ("barcode_regex", barcode_regex.to_json()),
+            ("barcode_map", barcode_map_path.to_json()),
+            ("barcode_map_drop_unlisted", barcode_map_drop_unlisted.to_json()),
+            ("cluster_map", cluster_map_path.to_json()),
+            ("gtf", gtf_path.to_json()),
+            ("weight_tag", weight_tag.to_json()),
+            ("missing_weight", missing_weight.to_json()),
+            ("per_input", per_input.to_json()),
+            ("build_barcode_index", build_barcode_index.to_json()),
+            ("skip_bad_inputs", skip_bad_inputs.to_json()),
+            ("threads", threads.to_json()),
+            ("estimate", estimate.to_json()),
+            ("tag_concat", tag_concat.to_json()),
+            ("tag_summary", tag_summary.to_json()),
+            ("bench", bench.to_json()),
+            ("bench_sweep", bench_sweep.to_json()),
+            ("dump_header", dump_header.to_json()),
+            ("profile", profile_enabled.to_json()),
+            ("log_json", log_json_path.to_json()),
+            ("diff", diff_files.to_json()),
+            ("checksum", checksum.to_json()),
+            ("external_sort", external_sort.to_json()),
+            ("temp_dir", temp_dir.to_json()),
+            ("schema_version", schema.to_json()),
+            ("stream_output", stream_output.to_json()),
+            ("normalize_to", normalize_to.to_json()),
+            ("with_raw", with_raw.to_json()),
+            ("with_rank", with_rank.to_json()),
+            ("cumulative_fraction", cumulative_fraction.to_json()),
+            ("count_range", count_range.to_json()),
+            ("cap_count", cap_count.to_json()),
+            ("limit_per_barcode", limit_per_barcode.to_json()),
+            ("knee_data", knee_data_path.to_json()),
+        ];
+        #[cfg(feature = "sqlite")]
+        fields.push(("sqlite", sqlite_path.to_json()));
+        #[cfg(feature = "hdf5")]
+        fields.push(("h5", h5_path.to_json()));
+        #[cfg(feature = "script")]
+        fields.push(("script", script_path.to_json()));
+        let fields: Vec<String> =
+            fields.iter().map(|(key, value)| format!("{}: {}", json_string(key), value)).collect();
+        format!("{{{}}}", fields.join(", "))
+    }
+}
+
+/// An option value as JSON.
+trait ToJson {
+    fn to_json(&self) -> String;
+}
+
+impl ToJson for bool {
+    fn to_json(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl ToJson for usize {
+    fn to_json(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl ToJson for u64 {
+    fn to_json(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl ToJson for i64 {
+    fn to_json(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl ToJson for i8 {
+    fn to_json(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl ToJson for u8 {
+    fn to_json(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl ToJson for f64 {
+    fn to_json(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl ToJson for String {
+    fn to_json(&self) -> String {
+        json_string(self)
+    }
+}
+
+/// A SAM tag, e.g. `"CB"`.
+impl ToJson for [u8; 2] {
+    fn to_json(&self) -> String {
+        json_string(&tag_names(&[*self]))
+    }
+}
+
+/// A `MIN:MAX` range as given on the command line, MAX empty when unbounded.
+impl ToJson for (usize, usize) {
+    fn to_json(&self) -> String {
+        let max = if self.1 == usize::MAX { String::new() } else { self.1.to_string() };
+        json_string(&format!("{}:{}", self.0, max))
+    }
+}
+
+impl ToJson for (String, String) {
+    fn to_json(&self) -> String {
+        json_array([&self.0, &self.1])
+    }
+}
+
+/// Seconds.
+impl ToJson for Duration {
+    fn to_json(&self) -> String {
+        self.as_secs_f64().to_string()
+    }
+}
+
+impl ToJson for Regex {
+    fn to_json(&self) -> String {
+        json_string(self.as_str())
+    }
+}
+
+impl<T: ToJson> ToJson for Option<T> {
+    fn to_json(&self) -> String {
+        self.as_ref().map_or("null".to_string(), T::to_json)
+    }
+}
+
+impl<T: ToJson> ToJson for Vec<T> {
+    fn to_json(&self) -> String {
+        format!("[{}]", self.iter().map(T::to_json).collect::<Vec<_>>().join(", "))
+    }
+}
+
+impl ToJson for OutputFormat {
+    fn to_json(&self) -> String {
+        json_string(self.name())
+    }
+}
+
+impl ToJson for SortOrder {
+    fn to_json(&self) -> String {
+        json_string(match self {
+            SortOrder::Barcode => "barcode",
+            SortOrder::CountDesc => "count",
+        })
+    }
+}
+
+impl ToJson for MissingWeight {
+    fn to_json(&self) -> String {
+        json_string(match self {
+            MissingWeight::CountOne => "one",
+            MissingWeight::Skip => "skip",
+        })
+    }
+}
+
+impl ToJson for SchemaVersion {
+    fn to_json(&self) -> String {
+        self.number().unwrap_or(1).to_string()
+    }
+}
+
+impl ToJson for TagConcat {
+    fn to_json(&self) -> String {
+        format!(
+            "{{\"tags\": {}, \"separator\": {}, \"placeholder\": {}}}",
+            self.tags.to_json(),
+            self.separator.to_json(),
+            self.placeholder.to_json()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Options {
+        let args: Vec<String> =
+            std::iter::once("read_counter").chain(args.split_whitespace()).map(String::from).collect();
+        Options::parse(&args).unwrap()
+    }
+
+    #[test]
+    fn compat_10x_fills_in_unset_options() {
+        let options = parse("in.bam --compat-10x");
+        assert_eq!(options.umi_tag, Some(*b"UB"));
+        assert!(options.strip_suffix);
+        assert_eq!(options.output_path.as_deref(), Some(COMPAT_10X_OUTPUT));
+        assert_eq!(options.output_format, OutputFormat::Tsv);
+    }
+
+    #[test]
+    fn compat_10x_keeps_explicit_options() {
+        let options = parse("in.bam --compat-10x --umi-tag UR --keep-barcode-suffix --output counts.csv");
+        assert_eq!(options.umi_tag, Some(*b"UR"));
+        assert!(!options.strip_suffix);
+        assert_eq!(options.output_path.as_deref(), Some("counts.csv"));
+        assert_eq!(options.output_format, OutputFormat::Csv);
+
+        // An explicit --format keeps the format's default path.
+        let options = parse("in.bam --compat-10x --format json");
+        assert_eq!(options.output_path, None);
+        assert_eq!(options.output_format, OutputFormat::Json);
+    }
+
+    #[test]
+    fn format_auto_follows_the_output_extension() {
+        assert_eq!(parse("in.bam").output_format, OutputFormat::Text);
+        assert_eq!(parse("in.bam --format auto").output_format, OutputFormat::Text);
+        assert_eq!(parse("in.bam --format auto --output x.tsv").output_format, OutputFormat::Tsv);
+        assert_eq!(parse("in.bam --output x.csv.gz").output_format, OutputFormat::Csv);
+        assert_eq!(parse("in.bam --output x.txt").output_format, OutputFormat::Text);
+        assert_eq!(parse("in.bam --format json --output x.tsv").output_format, OutputFormat::Json);
+        // --diff writes its own table whatever the --output name.
+        assert_eq!(parse("--diff a b --output deltas").output_format, OutputFormat::Text);
+    }
+
+    #[test]
+    fn estimate_preconditions() {
+        // Checked without --estimate itself, on which parse() would exit.
+        assert_eq!(parse("in.bam --limit 100").estimate_problem(), None);
+        assert_eq!(parse("in.bam --max-runtime 5").estimate_problem(), None);
+        let cases = [
+            ("in.bam", "needs a budget: --max-runtime or --limit"),
+            ("in.bam --limit 100 --input b.bam", "supports a single input"),
+            ("in.bam --limit 100 --chrom chr1", "cannot be combined with --chrom or --region"),
+            ("in.bam --limit 100 --region chr1:1-10", "cannot be combined with --chrom or --region"),
+            ("in.bam --limit 100 --format tsv", "only supports the text output format"),
+            ("in.cram --limit 100", "needs a BAM input (the extrapolation uses BGZF offsets)"),
+            (
+                "https://example.org/in.bam --limit 100",
+                "needs a local input (the extrapolation uses the file size)",
+            ),
+        ];
+        for (args, problem) in cases {
+            assert_eq!(parse(args).estimate_problem(), Some(problem), "{}", args);
+        }
+    }
+
+    #[test]
+    fn external_sort_conflicts_list_every_given_flag() {
+        assert!(parse("in.bam --external-sort").external_sort_conflicts().is_empty());
+        let options = parse("in.bam --external-sort --format csv --field-order count-barcode");
+        assert!(options.external_sort_conflicts().is_empty());
+        // Checked by hand, as parse() exits on a conflict.
+        let options = parse("in.bam --sort count --stats s.json --tag CB --tag UB --umi-tag UR --knee-data k.tsv");
+        let expected = ["--sort count", "--knee-data", "--stats", "a second --tag", "--umi-tag"];
+        assert_eq!(options.external_sort_conflicts(), expected);
+        let options = parse("in.bam --include-zero --whitelist wl.txt --dup-separate --with-rank --sort count");
+        let expected = ["--sort count", "--with-rank", "--include-zero", "--dup-separate"];
+        assert_eq!(options.external_sort_conflicts(), expected);
+    }
+}
@@ -0,0 +1,100 @@
+//! `--profile`: coarse wall-clock timings for the phases of a run.
+//!
+//! Phases are timed back to back with one clock read per phase boundary.
+//! The only per-record cost is splitting the read loop into `decode` (time
+//! spent inside the record iterator: BGZF/CRAM decompression and record
+//! parsing) and `count` (everything else in the loop: filters, aux lookups,
+//! hashing), which takes two clock reads per record and only when enabled.
+
+use std::time::{Duration, Instant};
+
+use rust_htslib::bam;
+use rust_htslib::errors::Error as HtslibError;
+
+pub struct Profile {
+    enabled: bool,
+    phases: Vec<(&'static str, Duration)>,
+    mark: Instant,
+}
+
+impl Profile {
+    /// Starts timing the first phase now.
+    pub fn new(enabled: bool) -> Self {
+        Profile { enabled, phases: Vec::new(), mark: Instant::now() }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Ends the current phase, recording it as `name`.
+    pub fn phase(&mut self, name: &'static str) {
+        let elapsed = self.lap();
+        self.record(name, elapsed);
+    }
+
+    /// Ends the read loop, splitting its time into `decode` (the `decode`
+    /// accumulated by [`Timed`]) and `count` (the rest).
+    pub fn read_loop(&mut self, decode: Duration) {
+        let elapsed = self.lap();
+        self.record("decode", decode);
+        self.record("count", elapsed.saturating_sub(decode));
+    }
+
+    fn lap(&mut self) -> Duration {
+        let now = Instant::now();
+        let elapsed = now - self.mark;
+        self.mark = now;
+        elapsed
+    }
+
+    fn record(&mut self, name: &'static str, elapsed: Duration) {
+        if self.enabled {
+            self.phases.push((name, elapsed));
+        }
+    }
+
+    /// Prints one line per phase with its share of the profiled time.
+    pub fn report(&self) {
+        let total: f64 = self.phases.iter().map(|(_, d)| d.as_secs_f64()).sum();
+        println!("Profile ({:.3}s total):", total);
+        for (name, elapsed) in &self.phases {
+            let secs = elapsed.as_secs_f64();
+            let share = if total > 0.0 { secs * 100.0 / total } else { 0.0 };
+            println!("  {:<8} {:>9.3}s {:>6.1}%", name, secs, share);
+        }
+    }
+
+    /// The phases as a JSON object of seconds, or `null` when not profiling.
+    pub fn json(&self) -> String {
+        if !self.enabled {
+            return "null".to_string();
+        }
+        let fields: Vec<String> = self
+            .phases
+            .iter()
+            .map(|(name, elapsed)| format!("\"{}\": {:.6}", name, elapsed.as_secs_f64()))
+            .collect();
+        format!("{{{}}}", fields.join(", "))
+    }
+}
+
+/// Wraps a record iterator, adding the time spent in `next` to `spent`.
+pub struct Timed<'a, I> {
+    pub inner: I,
+    pub spent: &'a mut Duration,
+}
+
+impl<I> Iterator for Timed<'_, I>
+where
+    I: Iterator<Item = Result<bam::Record, HtslibError>>,
+{
+    type Item = Result<bam::Record, HtslibError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let start = Instant::now();
+        let item = self.inner.next();
+        *self.spent += start.elapsed();
+        item
+    }
+}
@@ -0,0 +1,161 @@
+//! `--script` (enabled with the `script` cargo feature): a [Rhai] script that
+//! decides, per read, the key the read is counted under and its weight.
+//!
+//! The script runs once for every read that survives the read filters and
+//! has a barcode (or is let through by `--count-missing-as`). Its value is:
+//!
+//! - a string: count the read under that key with the current `weight`;
+//! - `[key, weight]`: count it under `key` with an integer weight >= 0;
+//! - `()` (e.g. a bare `return;`): don't count the read.
+//!
+//! The key is counted as is, like the `--count-missing-as` label: it is not
+//! split, remapped or checked against a whitelist. It still goes through
+//! `--separate-unmapped`, `--secondary-separate`, `--dup-separate` and
+//! `--limit-per-barcode` after the script returns, so a separated read is
+//! counted under its key in the side table, not in the main counts; use the
+//! `unmapped`, `secondary` and `duplicate` variables to decide otherwise.
+//! Variables available to the script, all read-only:
+//!
+//! ```text
+//! qname          string  read name
+//! flag           int     SAM flag
+//! reference      string  reference name, "*" if none
+//! pos            int     1-based leftmost position, 0 if none
+//! end            int     1-based last reference base covered by the alignment
+//! mapq           int     mapping quality
+//! tlen           int     template length (signed, as in SAM)
+//! seq_len        int     read length
+//! unmapped, reverse, paired, read1, secondary, supplementary, duplicate
+//!                bool    flag bits
+//! barcode        string  barcode as read from the tag; () under --count-missing-as
+//! umi            string  --umi-tag value, () if missing or not asked for
+//! weight         int     --weight-tag value, otherwise 1
+//! tags           map     every aux tag by name: ints and floats as numbers,
+//!                        A/Z/H values as strings, B arrays as arrays
+//! ```
+//!
+//! For example, counting reads per barcode and gene (`GX`), leaving out reads
+//! without a gene and counting confidently mapped ones twice:
+//!
+//! ```rhai
+//! if !("GX" in tags) { return; }
+//! [barcode + "_" + tags.GX, if mapq >= 30 { 2 } else { 1 }]
+//! ```
+//!
+//! [Rhai]: https://rhai.rs
+
+use std::path::PathBuf;
+
+use read_counter::filter::reference_span;
+use rhai::{Array, Dynamic, Engine, Map, Scope, AST};
+use rust_htslib::bam::{self, record::Aux};
+
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    scope: Scope<'static>,
+}
+
+impl Script {
+    pub fn load(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let engine = Engine::new();
+        let ast = engine
+            .compile_file(PathBuf::from(path))
+            .map_err(|e| format!("Error loading script '{}': {}", path, e))?;
+        Ok(Script { engine, ast, scope: Scope::new() })
+    }
+
+    /// Runs the script on `record`: the key and weight to count it under, or
+    /// `None` to leave it out.
+    pub fn key(
+        &mut self,
+        record: &bam::Record,
+        header: &bam::HeaderView,
+        barcode: Option<&str>,
+        umi: Option<&str>,
+        weight: usize,
+    ) -> Result<Option<(String, usize)>, String> {
+        let reference = match record.tid() {
+            tid if tid >= 0 => String::from_utf8_lossy(header.tid2name(tid as u32)).into_owned(),
+            _ => "*".to_string(),
+        };
+        let optional = |value: Option<&str>| value.map_or(Dynamic::UNIT, |v| Dynamic::from(v.to_string()));
+        let scope = &mut self.scope;
+        scope.clear();
+        scope.push_constant("qname", String::from_utf8_lossy(record.qname()).into_owned());
+        scope.push_constant("flag", i64::from(record.flags()));
+        scope.push_constant("reference", reference);
+        scope.push_constant("pos", if record.pos() >= 0 { record.pos() + 1 } else { 0 });
+        scope.push_constant("end", reference_span(record).1);
+        scope.push_constant("mapq", i64::from(record.mapq()));
+        scope.push_constant("tlen", record.insert_size());
+        scope.push_constant("seq_len", record.seq_len() as i64);
+        scope.push_constant("unmapped", record.is_unmapped());
+        scope.push_constant("reverse", record.is_reverse());
+        scope.push_constant("paired", record.is_paired());
+        scope.push_constant("read1", record.is_first_in_template());
+        scope.push_constant("secondary", record.is_secondary());
+        scope.push_constant("supplementary", record.is_supplementary());
+        scope.push_constant("duplicate", record.is_duplicate());
+        scope.push_constant("barcode", optional(barcode));
+        scope.push_constant("umi", optional(umi));
+        scope.push_constant("weight", weight as i64);
+        scope.push_constant("tags", aux_map(record));
+
+        let value = self
+            .engine
+            .eval_ast_with_scope::<Dynamic>(scope, &self.ast)
+            .map_err(|e| e.to_string())?;
+        if value.is_unit() {
+            return Ok(None);
+        }
+        if value.is_string() {
+            return Ok(Some((value.to_string(), weight)));
+        }
+        if let Some(pair) = value.clone().try_cast::<Array>()
+            && let [key, weight] = pair.as_slice()
+            && key.is_string()
+            && let Ok(weight) = weight.as_int()
+            && weight >= 0
+        {
+            return Ok(Some((key.to_string(), weight as usize)));
+        }
+        Err(format!(
+            "expected a string key, [key, weight] with weight >= 0, or () to skip, got {} ({})",
+            value,
+            value.type_name()
+        ))
+    }
+}
+
+/// The record's aux tags as a script map.
+fn aux_map(record: &bam::Record) -> Map {
+    let mut map = Map::new();
+    for (tag, value) in record.aux_iter().flatten() {
+        let value = match value {
+            Aux::Char(c) => Dynamic::from((c as char).to_string()),
+            Aux::I8(v) => Dynamic::from(i64::from(v)),
+            Aux::U8(v) => Dynamic::from(i64::from(v)),
+            Aux::I16(v) => Dynamic::from(i64::from(v)),
+            Aux::U16(v) => Dynamic::from(i64::from(v)),
+            Aux::I32(v) => Dynamic::from(i64::from(v)),
+            Aux::U32(v) => Dynamic::from(i64::from(v)),
+            Aux::Float(v) => Dynamic::from(f64::from(v)),
+            Aux::Double(v) => Dynamic::from(v),
+            Aux::String(v) | Aux::HexByteArray(v) => Dynamic::from(v.to_string()),
+            Aux::ArrayI8(a) => ints(a.iter().map(i64::from)),
+            Aux::ArrayU8(a) => ints(a.iter().map(i64::from)),
+            Aux::ArrayI16(a) => ints(a.iter().map(i64::from)),
+            Aux::ArrayU16(a) => ints(a.iter().map(i64::from)),
+            Aux::ArrayI32(a) => ints(a.iter().map(i64::from)),
+            Aux::ArrayU32(a) => ints(a.iter().map(i64::from)),
+            Aux::ArrayFloat(a) => Dynamic::from_array(a.iter().map(|v| Dynamic::from(f64::from(v))).collect()),
+        };
+        map.insert(String::from_utf8_lossy(tag).as_ref().into(), value);
+    }
+    map
+}
+
+fn ints(values: impl Iterator<Item = i64>) -> Dynamic {
+    Dynamic::from_array(values.map(Dynamic::from).collect())
+}
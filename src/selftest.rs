@@ -0,0 +1,122 @@
+//! `--selftest`: an end-to-end check that a build can write, read and count a
+//! BAM, for CI in downstream deployments and for users checking a binary.
+//!
+//! A small BAM with known barcodes is built in memory and written to a
+//! temporary file through htslib. This executable is then re-run on it once
+//! per entry in [`CASES`], so each case goes through the real command line:
+//! option parsing, the read loop, whitelist and splitting, sorting and the
+//! output writers. Each output must match its expected text byte for byte.
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::{self, Command};
+
+use rust_htslib::bam::{self, header::HeaderRecord};
+
+/// Reads covering the cases the counter must get right: repeated barcodes, a
+/// missing tag, a non-string tag, an unmapped read that still counts, and an
+/// unmapped read without a tag.
+const RECORDS: &[&str] = &[
+    "r1\t0\tchr1\t100\t60\t10M\t*\t0\t0\tACGTACGTAC\tIIIIIIIIII\tCB:Z:AAAC-1",
+    "r2\t16\tchr1\t200\t60\t10M\t*\t0\t0\tACGTACGTAC\tIIIIIIIIII\tCB:Z:AAAC-1",
+    "r3\t0\tchr1\t300\t60\t4M100N6M\t*\t0\t0\tACGTACGTAC\tIIIIIIIIII\tCB:Z:TTTG-1",
+    "r4\t0\tchr1\t400\t60\t10M\t*\t0\t0\tACGTACGTAC\tIIIIIIIIII",
+    "r5\t0\tchr1\t500\t60\t10M\t*\t0\t0\tACGTACGTAC\tIIIIIIIIII\tCB:i:7",
+    "r6\t4\t*\t0\t0\t*\t*\t0\t0\tACGTACGTAC\tIIIIIIIIII\tCB:Z:GGGA-1",
+    "r7\t0\tchr1\t700\t60\t10M\t*\t0\t0\tACGTACGTAC\tIIIIIIIIII\tCB:Z:AAAC-1",
+    "r8\t4\t*\t0\t0\t*\t*\t0\t0\tACGTACGTAC\tIIIIIIIIII",
+];
+
+/// Barcodes listed in the whitelist file written for the `--whitelist` case.
+const WHITELIST: &str = "AAAC-1\nTTTG-1\n";
+
+/// Extra arguments for each run on [`RECORDS`] (`{dir}` is the temporary
+/// directory), and the counts output it must produce.
+const CASES: &[(&[&str], &str)] = &[
+    (&["--format", "text"], "      3 AAAC-1\n      1 GGGA-1\n      1 TTTG-1\n"),
+    (
+        &["--whitelist", "{dir}/whitelist.txt", "--format", "tsv"],
+        "barcode\tcount\nAAAC-1\t3\nTTTG-1\t1\n",
+    ),
+    (
+        &["--format", "text", "--tag-split", "-", "--sort", "count"],
+        "      5 1\n      3 AAAC\n      1 GGGA\n      1 TTTG\n",
+    ),
+    // The label is kept out of the main counts for unmapped reads too.
+    (
+        &["--format", "text", "--count-missing-as", "NO_BARCODE", "--separate-unmapped"],
+        "      3 AAAC-1\n      2 NO_BARCODE\n      1 TTTG-1\n",
+    ),
+];
+
+/// Runs the self-test, printing PASS or FAIL, and exits 0 or 1 accordingly.
+pub fn run() -> ! {
+    let dir = env::temp_dir().join(format!("read_counter_selftest_{}", process::id()));
+    let result = check(&dir);
+    let _ = fs::remove_dir_all(&dir);
+    match result {
+        Ok(()) => {
+            println!("PASS: read_counter {} self-test", env!("CARGO_PKG_VERSION"));
+            process::exit(0);
+        },
+        Err(e) => {
+            println!("FAIL: read_counter {} self-test: {}", env!("CARGO_PKG_VERSION"), e);
+            process::exit(1);
+        },
+    }
+}
+
+fn check(dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    fs::create_dir_all(dir)?;
+    let bam_path = dir.join("selftest.bam");
+    let counts_path = dir.join("reads_per_barcode");
+
+    let mut header = bam::Header::new();
+    header.push_record(HeaderRecord::new(b"HD").push_tag(b"VN", "1.6"));
+    header.push_record(HeaderRecord::new(b"SQ").push_tag(b"SN", "chr1").push_tag(b"LN", 1000));
+    let header_view = bam::HeaderView::from_header(&header);
+    {
+        let mut writer = bam::Writer::from_path(&bam_path, &header, bam::Format::Bam)?;
+        for line in RECORDS {
+            writer.write(&bam::Record::from_sam(&header_view, line.as_bytes())?)?;
+        }
+    }
+
+    fs::write(dir.join("whitelist.txt"), WHITELIST)?;
+
+    let exe = env::current_exe()?;
+    let dir_str = dir.to_str().ok_or("temporary path is not valid UTF-8")?;
+    let bam_str = bam_path.to_str().ok_or("temporary path is not valid UTF-8")?;
+    let counts_str = counts_path.to_str().ok_or("temporary path is not valid UTF-8")?;
+    for (args, expected) in CASES {
+        let args: Vec<String> = args.iter().map(|arg| arg.replace("{dir}", dir_str)).collect();
+        let _ = fs::remove_file(&counts_path);
+        let output = Command::new(&exe)
+            .arg(bam_str)
+            .args(&args)
+            .args(["--output", counts_str, "-q"])
+            .current_dir(dir)
+            .output()?;
+        if !output.status.success() {
+            return Err(format!(
+                "run with [{}] failed ({})\n{}",
+                args.join(" "),
+                output.status,
+                String::from_utf8_lossy(&output.stderr)
+            )
+            .into());
+        }
+        let actual = fs::read_to_string(&counts_path)?;
+        if actual != *expected {
+            return Err(format!(
+                "output mismatch with [{}]\n--- expected\n{}--- actual\n{}",
+                args.join(" "),
+                expected,
+                actual
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
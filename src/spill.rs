@@ -0,0 +1,253 @@
+//! `--external-sort`: counting in bounded memory when there are too many
+//! distinct barcodes to hold them all.
+//!
+//! Whenever the count map reaches [`RUN_BARCODES`] barcodes it is sorted and
+//! written to a run file under `--temp-dir` (one `barcode<TAB>count` line per
+//! barcode), then cleared. At the end the runs and whatever is still in memory
+//! are merged k-way, summing the counts of a barcode that turns up in several
+//! runs, and handed on in barcode order one at a time, so neither the full map
+//! nor a sorted copy of it is ever held.
+//!
+//! The price is disk and time: every barcode is written out and read back once
+//! per run it appears in, so the temp directory needs about the size of the
+//! final text output when barcodes rarely repeat across runs (reads sorted by
+//! barcode) and up to once per run when they do (reads in coordinate order),
+//! and counting is noticeably slower than in memory. Only worth it for inputs
+//! that would otherwise run out of memory.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use ahash::AHashMap;
+
+/// Distinct barcodes held in memory before they are spilled to a run.
+pub const RUN_BARCODES: usize = 4_000_000;
+
+/// Runs merged at once, keeping open files well under the usual limit of
+/// 1024. With more runs than this they are first merged in groups.
+const MERGE_FAN_IN: usize = 64;
+
+/// Rows of one run, in barcode order.
+type Rows = Box<dyn Iterator<Item = io::Result<(String, usize)>>>;
+
+/// The run files written so far; removed again when dropped.
+pub struct Spill {
+    dir: PathBuf,
+    /// Runs not yet merged away.
+    runs: Vec<PathBuf>,
+    /// Runs written so far, including merged ones.
+    written: usize,
+}
+
+impl Spill {
+    pub fn new(dir: &Path) -> Self {
+        Spill { dir: dir.to_path_buf(), runs: Vec::new(), written: 0 }
+    }
+
+    /// Runs spilled from the count map.
+    pub fn runs(&self) -> usize {
+        self.runs.len()
+    }
+
+    /// Starts a new run file, recorded before it is created so that a
+    /// half-written run is removed too.
+    fn create_run(&mut self) -> io::Result<BufWriter<File>> {
+        let path = self.dir.join(format!("read_counter_{}_{}.run", process::id(), self.written));
+        self.written += 1;
+        self.runs.push(path.clone());
+        Ok(BufWriter::new(File::create(&path)?))
+    }
+
+    /// Writes `counts` to a new run, sorted by barcode, and empties it.
+    pub fn write_run(&mut self, counts: &mut AHashMap<String, usize>) -> io::Result<()> {
+        let mut rows: Vec<(String, usize)> = counts.drain().collect();
+        rows.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        let mut writer = self.create_run()?;
+        for (barcode, count) in &rows {
+            writeln!(writer, "{}\t{}", barcode, count)?;
+        }
+        writer.flush()
+    }
+
+    /// Merges the runs with the barcodes left in `rest`, calling `emit` once
+    /// per barcode, in barcode order, with its total count.
+    pub fn merge<F>(mut self, rest: AHashMap<String, usize>, emit: F) -> io::Result<()>
+    where
+        F: FnMut(&str, usize) -> io::Result<()>,
+    {
+        while self.runs.len() > MERGE_FAN_IN {
+            let group = self.runs[..MERGE_FAN_IN].to_vec();
+            let mut writer = self.create_run()?;
+            merge_rows(open_runs(&group)?, |barcode, count| writeln!(writer, "{}\t{}", barcode, count))?;
+            writer.flush()?;
+            for path in self.runs.drain(..MERGE_FAN_IN) {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+        let mut rest: Vec<(String, usize)> = rest.into_iter().collect();
+        rest.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        let mut sources = open_runs(&self.runs)?;
+        sources.push(Box::new(rest.into_iter().map(Ok)));
+        merge_rows(sources, emit)
+    }
+}
+
+fn open_runs(paths: &[PathBuf]) -> io::Result<Vec<Rows>> {
+    let mut sources: Vec<Rows> = Vec::new();
+    for path in paths {
+        let reader = BufReader::new(File::open(path)?);
+        sources.push(Box::new(reader.lines().map(|line| parse_run_line(&line?))));
+    }
+    Ok(sources)
+}
+
+/// K-way merge of sorted `sources`, summing the counts of equal barcodes.
+fn merge_rows<F>(mut sources: Vec<Rows>, mut emit: F) -> io::Result<()>
+where
+    F: FnMut(&str, usize) -> io::Result<()>,
+{
+    // Min-heap on (barcode, source); each source has at most one entry.
+    let mut heap = BinaryHeap::new();
+    for (index, source) in sources.iter_mut().enumerate() {
+        if let Some(row) = source.next() {
+            let (barcode, count) = row?;
+            heap.push(Reverse((barcode, index, count)));
+        }
+    }
+    let mut current: Option<(String, usize)> = None;
+    while let Some(Reverse((barcode, index, count))) = heap.pop() {
+        if let Some(row) = sources[index].next() {
+            let (next_barcode, next_count) = row?;
+            heap.push(Reverse((next_barcode, index, next_count)));
+        }
+        match &mut current {
+            Some((held, total)) if *held == barcode => *total += count,
+            _ => {
+                if let Some((held, total)) = current.replace((barcode, count)) {
+                    emit(&held, total)?;
+                }
+            },
+        }
+    }
+    if let Some((held, total)) = current {
+        emit(&held, total)?;
+    }
+    Ok(())
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        for path in &self.runs {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+fn parse_run_line(line: &str) -> io::Result<(String, usize)> {
+    line.split_once('\t')
+        .and_then(|(barcode, count)| Some((barcode.to_string(), count.parse().ok()?)))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("malformed run line '{}'", line)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows(pairs: &[(&str, usize)]) -> Rows {
+        let pairs: Vec<(String, usize)> = pairs.iter().map(|&(barcode, count)| (barcode.to_string(), count)).collect();
+        Box::new(pairs.into_iter().map(Ok))
+    }
+
+    fn merged(sources: Vec<Rows>) -> Vec<(String, usize)> {
+        let mut out = Vec::new();
+        merge_rows(sources, |barcode, count| {
+            out.push((barcode.to_string(), count));
+            Ok(())
+        })
+        .unwrap();
+        out
+    }
+
+    /// A fresh, empty directory for one test's runs.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("read_counter_spill_{}_{}", process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn files_in(dir: &Path) -> usize {
+        std::fs::read_dir(dir).unwrap().count()
+    }
+
+    #[test]
+    fn merge_rows_sums_barcodes_across_sources() {
+        let sources = vec![
+            rows(&[("AAAC", 1), ("CCCT", 2), ("TTTG", 3)]),
+            rows(&[("AAAC", 10), ("GGGA", 20)]),
+            rows(&[]),
+            rows(&[("CCCT", 100), ("TTTG", 200)]),
+        ];
+        let expected = [("AAAC", 11), ("CCCT", 102), ("GGGA", 20), ("TTTG", 203)];
+        let expected: Vec<(String, usize)> = expected.iter().map(|&(b, c)| (b.to_string(), c)).collect();
+        assert_eq!(merged(sources), expected);
+    }
+
+    #[test]
+    fn merge_rows_passes_on_source_errors() {
+        let failing: Rows = Box::new(std::iter::once(Err(io::Error::other("bad run"))));
+        let result = merge_rows(vec![rows(&[("AAAC", 1)]), failing], |_, _| Ok(()));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn merge_regroups_more_runs_than_the_fan_in() {
+        let dir = scratch_dir("fan_in");
+        let mut spill = Spill::new(&dir);
+        let runs = MERGE_FAN_IN * 2 + 3;
+        for run in 0..runs {
+            // SHARED is in every run; each run also has a barcode of its own.
+            let mut counts: AHashMap<String, usize> =
+                [("SHARED".to_string(), 1), (format!("RUN{:04}", run), run)].into_iter().collect();
+            spill.write_run(&mut counts).unwrap();
+            assert!(counts.is_empty());
+        }
+        assert_eq!(spill.runs(), runs);
+        let rest: AHashMap<String, usize> =
+            [("SHARED".to_string(), 1000), ("AAAA".to_string(), 7)].into_iter().collect();
+
+        let mut out = Vec::new();
+        spill
+            .merge(rest, |barcode, count| {
+                out.push((barcode.to_string(), count));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(out.len(), runs + 2);
+        assert!(out.windows(2).all(|pair| pair[0].0 < pair[1].0), "not in barcode order");
+        assert_eq!(out[0], ("AAAA".to_string(), 7));
+        assert_eq!(out[1], ("RUN0000".to_string(), 0));
+        assert_eq!(out[runs], (format!("RUN{:04}", runs - 1), runs - 1));
+        assert_eq!(out[runs + 1], ("SHARED".to_string(), runs + 1000));
+        assert_eq!(files_in(&dir), 0, "run files left behind");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dropping_a_spill_removes_its_runs() {
+        let dir = scratch_dir("drop");
+        let mut spill = Spill::new(&dir);
+        for _ in 0..3 {
+            let mut counts: AHashMap<String, usize> = [("AAAC".to_string(), 1)].into_iter().collect();
+            spill.write_run(&mut counts).unwrap();
+        }
+        assert_eq!(files_in(&dir), 3);
+        drop(spill);
+        assert_eq!(files_in(&dir), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}